@@ -0,0 +1,47 @@
+// Copyright © 2024 David Caldwell <david@porkrind.org>
+
+// cargo bench --features bench
+//
+// These run the real binary, the same as the integration tests do, so process startup is in every number. It's
+// small next to the trees here and it's the same from run to run, which is what matters for catching regressions.
+
+use std::{path::Path,
+          process::{Command, Stdio}};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+use support::{make_test_tree, make_wide_tree};
+
+const DIRS: usize = 100;
+const FILES: usize = 100;
+
+fn rm_rfp(flags: &[&str], path: &Path) {
+    let status = Command::new(env!("CARGO_BIN_EXE_rm-rfp")).args(flags).arg("--progress=none").arg(path)
+                                                           .stdout(Stdio::null()).stderr(Stdio::null())
+                                                           .status().expect("run rm-rfp");
+    assert!(status.success());
+}
+
+fn engine(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engine");
+    group.sample_size(10);
+
+    group.throughput(Throughput::Elements((DIRS * FILES + DIRS + 1) as u64));
+    let tree = make_wide_tree(DIRS, FILES).expect("make_wide_tree");
+    group.bench_function("count-only", |b| b.iter(|| rm_rfp(&["--count-only"], tree.path())));
+    group.bench_function("delete", |b| b.iter_batched(|| make_wide_tree(DIRS, FILES).expect("make_wide_tree"),
+                                                      |tree| rm_rfp(&[], tree.path()),
+                                                      BatchSize::PerIteration));
+
+    // --dry-run sleeps for every item (so that it looks like it's deleting), so a small tree is plenty.
+    group.throughput(Throughput::Elements(10 * 10 + 10));
+    let tree = make_test_tree(10).expect("make_test_tree");
+    group.bench_function("dry-run", |b| b.iter(|| rm_rfp(&["--dry-run"], tree.path())));
+
+    group.finish();
+}
+
+criterion_group!(benches, engine);
+criterion_main!(benches);
@@ -1,388 +1,4310 @@
 // Copyright © 2024 David Caldwell <david@porkrind.org>
 
-use std::{fs::{read_dir, remove_dir, remove_file, Metadata},
+use std::{borrow::Cow,
+          collections::BTreeMap,
+          fs::{read_dir, remove_dir, remove_file, FileType, Metadata},
           io::{IsTerminal, Write},
           panic,
           path::{Path, PathBuf},
           sync::{atomic::{AtomicBool, AtomicU64, Ordering},
-                 mpsc::{sync_channel, SyncSender}},
+                 Arc, OnceLock,
+                 mpsc::{sync_channel, Receiver, RecvTimeoutError, SendError, SyncSender, TrySendError}},
           thread::{self, sleep},
-          time::Duration};
+          time::{Duration, Instant, SystemTime}};
 
 use anyhow::{anyhow, Error, Result};
 use docopt::Docopt;
-use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{BinaryBytes, DecimalBytes, HumanCount, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use serde::Deserialize;
 
+// 0 is success and 1 is an error (or errors).
+const EXIT_TIME_LIMIT: i32 = 3;
+const EXIT_LIMIT: i32 = 4;
+const EXIT_NOTHING: i32 = 5;
+const EXIT_FREE_SHORT: i32 = 6;
+
 static TOTAL: AtomicStats = AtomicStats { bytes: AtomicU64::new(0),
                                           files: AtomicU64::new(0),
                                           dirs : AtomicU64::new(0),
+                                          symlinks: AtomicU64::new(0),
                                           done : AtomicBool::new(false) };
 
+// Set by the SIGUSR1 handler (with --summary-on-signal) and cleared once the summary's been printed.
+static SUMMARY_WANTED: AtomicBool = AtomicBool::new(false);
+
+// From --humanize. Set once at the start, and everything that shows a size goes through `Size`.
+static HUMANIZE: OnceLock<Humanize> = OnceLock::new();
+
 fn usage() -> String {
-    format!(r#"
+    r#"
 Usage:
   rm-rfp --help
   rm-rfp [options] <path>...
+  rm-rfp --restore [options] [<run>]
+  rm-rfp --manifest=<file> [options]
+  rm-rfp --by-inode=<dir:inode> [options]
 
 Options:
   -h, --help          Show this screen.
   -n, --dry-run       Don't delete anything, but go through the motions as if it were.
+  --count-only        Don't delete anything, just print how much would be deleted.
+  --tree              With --dry-run or --count-only, print a tree of everything that would be deleted.
+  --all-or-nothing    Find everything before deleting anything, and don't delete anything if finding had errors.
+  --manifest=<file>   Delete exactly what's listed in <file>, one per line (optionally "<path><tab><size>").
+  --prune             With --manifest, also delete directories that are left empty.
+  --by-inode=<dir:inode>  Delete whatever in <dir> has inode number <inode>, whatever its name is (unix only).
+  --dry-run-delay=<us>  How long --dry-run pretends deleting a file,directory takes, in microseconds [default: 1000,80].
   -i, --interactive   Prompt before deleting each file.
+  --assume-yes        With --interactive, an empty answer (or no more input) means yes instead of no.
+  --prompt-timeout=<duration>  With --interactive, take the default answer if there isn't one this soon (unix only).
+  -d, --dir           Don't recurse: delete directory <path>s only if they are empty, like `rmdir`.
+  --dereference       When a <path> is a symlink to a directory, delete what's in the directory too, not just the link.
+  --no-dereference    When a <path> is a symlink to a directory, just delete the link (without warning about it).
+  -C, --directory=<dir>  Change to <dir> first, so relative <path>s are relative to it.
+  -f, --force         Ignore <path>s that don't exist (and --glob patterns that don't match anything).
+  --glob              Expand wildcards and {a,b} braces in <path>s (for when there's no shell to do it).
+  --dedupe-args       Drop <path>s that are inside another <path> (or the same as one), with a warning.
   --no-preserve-root  Don't fail if '/' is given as an argument.
-"#)
+  --allow-delete-cwd  Don't fail if a <path> is the current directory or contains it.
+  --allow-delete-self  Don't fail if a <path> is this rm-rfp executable or contains it.
+  --ignore-readonly   Try to delete <path>s on read-only filesystems instead of failing up front (unix only).
+  --cross-mounts      Go into filesystems mounted inside the <path>s (and delete what's in them) instead of reporting them.
+  --precious=<dirs>   ':' separated directories that need confirmation to delete [default: ~:/usr:/etc:/var:..].
+  --yes-really        Delete <path>s that are --precious without asking.
+  --protect-from=<file>  Never delete anything listed in <file> (absolute paths, one per line), or what contains it.
+  --color=<when>      Colorize the progress output: auto, always, or never [default: auto].
+  --progress=<how>    How to show progress: bar, plain (a status line every few seconds), or none (default: bar, or plain with --ci).
+  --ci                Show progress in a way that suits CI logs (--progress=plain). On by default when $CI is set.
+  --no-clear          With --progress=bar, leave the bars on the screen when finished instead of just the totals.
+  --progress-to=<stream>  Where progress, prompts, and the totals go: stderr or stdout [default: stderr].
+  --progress-by=<unit>  What the progress bar measures: count (of files) or bytes [default: count].
+  --progress-template=<template>  An indicatif template for the progress bar line, to replace the usual one.
+  --spinner-template=<template>  An indicatif template for the line showing what's being deleted.
+  --apparent-size=<yes|no>  Count files' sizes (yes), or the disk space they actually take up (no, unix only) [default: yes].
+  --humanize=<units>  How to show sizes: si (kB = 1000 B), iec (KiB = 1024 B), or bytes [default: iec].
+  --provisional-eta   Show an ETA while still counting, for what's been found so far (so it can only go up).
+  --show-inodes       Also show how many inodes (files + directories) have been removed, and how fast.
+  --per-arg-summary   Print how much each <path> accounted for when finished.
+  --by-extension      Print how much each file extension accounted for when finished, biggest first.
+  --summary-format=<template>  Also print a summary line of your own to stdout at the end, eg: "{files},{bytes}".
+  --timing-log=<file>  Write when finding and deleting started and finished to <file>, for seeing where the time went.
+  --trace-dirs=<file>  Write what happened to everything in each directory to <file>, for seeing why one wasn't empty.
+  --stop-on-error     Quit at the first error instead of reporting it and carrying on.
+  --max-errors=<n>    Give up once there have been <n> errors.
+  --list-errors       List all the errors again at the end, where they're easy to find.
+  --verify            Afterwards, go over the <path>s again and report anything that should be gone but isn't.
+  --errors-to=<file>  Write all the errors to <file> at the end (one per line) instead of listing them.
+  --report-errors-json=<stream>  Report errors as JSON lines on <stream> (stderr or stdout), with the errno.
+  --on-error=<cmd>    Run <cmd> with the shell for everything that couldn't be deleted (unix only).
+  --trash-on-fail     Move anything that can't be deleted to the trash instead, if that works.
+  -P, --overwrite     Overwrite regular files (with 0xff, then 0x00, then 0xff) before deleting them, like BSD `rm -P`.
+  --bury              Move things to the graveyard instead of deleting them, so --restore can put them back.
+  --graveyard=<dir>   Where --bury puts things (implies --bury). Defaults to $XDG_DATA_HOME/rm-rfp/graveyard.
+  --restore           Put back everything from the last --bury (or from <run>, a directory in the graveyard).
+  --sort=<key>        Order to delete directory entries in: name, size, mtime, random, or none [default: name].
+  --seed=<n>          With --sort=random, shuffle the same way as the last time this <n> was used.
+  --sort-threshold=<n>  Don't sort directories with this many entries or more. 0 means never sort [default: 5000].
+  --deterministic     Same output every time (for tests): sorted by name, no progress, errors sorted, one thread.
+  --only-broken-symlinks  Only delete symbolic links that point to something that doesn't exist.
+  --queue-size=<n>    How many found items can be waiting to be deleted [default: 4096].
+  --files-only        Only delete files, leaving all the directories in place.
+  --trim-to-depth=<n>  Only delete what's more than <n> levels down in each <path>, keeping the top <n> levels.
+  --empty-dirs-only   Only delete directories that are empty (or only contain empty directories).
+  --syslog            Log the start and end of the run to syslog (unix only).
+  --syslog-facility=<facility>  Syslog facility to log to [default: user].
+  --syslog-level=<level>  Syslog level for the start and end records [default: notice].
+  --syslog-each       Also log each item that is deleted (at the debug level).
+  --respect-xattr=<name>  Don't delete anything with this extended attribute (or anything containing it).
+  --skip-hidden       Don't delete anything whose name starts with '.' (or anything containing it).
+  --regex=<pattern>   Only delete things whose path matches this regular expression.
+  --regex-scope=<scope>  What --regex matches: name, relative (starting at the <path>), or absolute [default: relative].
+  --include=<globs>   ':' separated wildcards: only delete things that match one (or are in a directory that does).
+  --exclude=<globs>   ':' separated wildcards: don't delete anything that matches one (or is in a directory that does).
+  --owned-by=<user>   Only delete things owned by <user> (a name or a uid) (unix only).
+  --skip-open         Don't delete files that some process has open (or the directories they're in) (Linux only).
+  --newer-than-file=<ref>  Only delete files modified after <ref> was.
+  --older-than-file=<ref>  Only delete files modified before <ref> was.
+  --preserve-parent-mtime  Keep the modification times of directories that things get deleted from (unix only).
+  --sync              Make sure the deletes are on disk before exiting by fsyncing the directories involved (unix only).
+  --relative-to=<dir>  Show paths relative to <dir> in the progress display.
+  --relative          Show paths relative to the directory the <path>s have in common.
+  --time-limit=<duration>  Stop after this long (eg: 90s, 5m, 1h), exiting with status 3.
+  --limit=<n>         Stop after deleting <n> files (directories don't count), exiting with status 4.
+  --free=<size>       Only delete enough files to free <size> (eg: 5GB), leaving the rest and all the directories.
+  --free-order=<key>  Which files --free deletes first: biggest or oldest [default: biggest].
+  --nothing-status    Exit with status 5 if there turned out to be nothing to delete.
+  --raw-paths         Print paths in error messages exactly as they are instead of quoted (unix only).
+  --eta-window=<secs>  Roughly how many seconds of recent progress the ETA is based on [default: 10].
+  --stats-interval=<ms>  Update the progress display at most this often [default: 100].
+  --refresh-rate=<hz>  With --progress=bar, redraw the bars this many times a second, from 1 to 60 (default: 20).
+  --summary-on-signal  Print a summary line to stderr whenever a SIGUSR1 comes in, and keep going (unix only).
+  --status-socket=<path>  Listen on a unix socket at <path> and send whatever connects the totals as JSON every second (unix only).
+  --throttle=<rate>   Don't delete faster than this: files per second (eg: 500/s) or bytes (eg: 20MB/s).
+  --lock              Fail if another `rm-rfp --lock` is already deleting any of the <path>s (unix only).
+  --backend=<how>     How to delete: std (one file at a time) or iouring (in batches, Linux only) [default: std].
+"#.to_string()
 }
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_dry_run:     bool,
+    flag_count_only:  bool,
+    flag_tree:        bool,
+    flag_all_or_nothing: bool,
+    flag_manifest:    Option<PathBuf>,
+    flag_by_inode:    Option<String>,
+    flag_prune:       bool,
+    flag_dry_run_delay: String,
     flag_interactive: bool,
+    flag_assume_yes:  bool,
+    flag_prompt_timeout: Option<String>,
+    flag_dir:         bool,
+    flag_dereference: bool,
+    flag_no_dereference: bool,
+    flag_directory:   Option<PathBuf>,
+    flag_force:       bool,
+    flag_glob:        bool,
+    flag_dedupe_args: bool,
     flag_no_preserve_root: bool,
+    flag_allow_delete_cwd: bool,
+    flag_allow_delete_self: bool,
+    flag_ignore_readonly: bool,
+    flag_cross_mounts: bool,
+    flag_precious:    String,
+    flag_protect_from: Option<PathBuf>,
+    flag_yes_really:  bool,
+    flag_color:       Color,
+    flag_progress:    Option<Progress>,
+    flag_ci:          bool,
+    flag_no_clear:    bool,
+    flag_progress_to: Stream,
+    flag_progress_by: ProgressBy,
+    flag_progress_template: Option<String>,
+    flag_spinner_template: Option<String>,
+    flag_provisional_eta: bool,
+    flag_show_inodes: bool,
+    flag_per_arg_summary: bool,
+    flag_by_extension: bool,
+    flag_verify:      bool,
+    flag_nothing_status: bool,
+    flag_overwrite:   bool,
+    flag_summary_format: Option<String>,
+    flag_timing_log:  Option<PathBuf>,
+    flag_trace_dirs:  Option<PathBuf>,
+    flag_stop_on_error: bool,
+    flag_max_errors:  Option<u64>,
+    flag_list_errors: bool,
+    flag_errors_to:   Option<PathBuf>,
+    flag_report_errors_json: Option<Stream>,
+    flag_on_error:    Option<String>,
+    flag_trash_on_fail: bool,
+    flag_bury:        bool,
+    flag_graveyard:   Option<PathBuf>,
+    flag_restore:     bool,
+    flag_sort:        Sort,
+    flag_sort_threshold: usize,
+    flag_seed:        Option<u64>,
+    flag_deterministic: bool,
+    flag_only_broken_symlinks: bool,
+    flag_queue_size:  usize,
+    flag_files_only:  bool,
+    flag_trim_to_depth: Option<usize>,
+    flag_empty_dirs_only: bool,
+    flag_syslog:      bool,
+    flag_syslog_facility: String,
+    flag_syslog_level: String,
+    flag_syslog_each: bool,
+    flag_respect_xattr: Option<String>,
+    flag_skip_hidden: bool,
+    flag_regex:       Option<String>,
+    flag_regex_scope: RegexScope,
+    flag_include:     Option<String>,
+    flag_exclude:     Option<String>,
+    flag_owned_by:    Option<String>,
+    flag_skip_open:   bool,
+    flag_newer_than_file: Option<PathBuf>,
+    flag_older_than_file: Option<PathBuf>,
+    flag_preserve_parent_mtime: bool,
+    flag_sync:        bool,
+    flag_relative_to: Option<PathBuf>,
+    flag_relative:    bool,
+    flag_time_limit:  Option<String>,
+    flag_limit:       Option<u64>,
+    flag_free:        Option<String>,
+    flag_free_order:  FreeOrder,
+    flag_apparent_size: YesNo,
+    flag_raw_paths:   bool,
+    flag_eta_window:  f64,
+    flag_stats_interval: u64,
+    flag_refresh_rate: Option<u32>,
+    flag_summary_on_signal: bool,
+    flag_status_socket: Option<PathBuf>,
+    flag_throttle:    Option<String>,
+    flag_lock:        bool,
+    flag_backend:     Backend,
+    flag_humanize:    Humanize,
     arg_path:         Vec<PathBuf>,
+    arg_run:          Option<PathBuf>,
+}
+
+impl Args {
+    /// Options that don't make sense together (or here), caught before anything is looked at, let alone deleted.
+    fn check(&self) -> Result<()> {
+        let manifest = self.flag_manifest.is_some();
+        let free = self.flag_free.is_some();
+        if self.flag_by_inode.is_some() && manifest {
+            Err(anyhow!("--by-inode and --manifest can't both be given"))?
+        }
+        if self.flag_trim_to_depth.is_some() && manifest {
+            Err(anyhow!("--trim-to-depth goes by how deep things are in the <path>s, which --manifest doesn't have"))?
+        }
+        if self.flag_dedupe_args && manifest {
+            Err(anyhow!("--dedupe-args is for <path>s that overlap, and --manifest entries are one thing each"))?
+        }
+        if self.flag_prune && !manifest {
+            Err(anyhow!("--prune only works with --manifest (otherwise directories left empty are already deleted)"))?
+        }
+
+        if self.flag_files_only && self.flag_empty_dirs_only {
+            Err(anyhow!("--files-only and --empty-dirs-only together would never delete anything"))?
+        }
+
+        if free && self.flag_empty_dirs_only {
+            Err(anyhow!("--free only deletes files, so it doesn't go with --empty-dirs-only"))?
+        }
+        if free && self.flag_interactive {
+            Err(anyhow!("--free doesn't know which files it'll delete until it's found them all, so --interactive can't ask as it goes"))?
+        }
+        if free && self.flag_verify {
+            Err(anyhow!("--free leaves files behind on purpose, so --verify would call every one of them a failure"))?
+        }
+
+        if self.flag_count_only && self.flag_interactive {
+            Err(anyhow!("--count-only doesn't delete anything so there's nothing for --interactive to ask about"))?
+        }
+
+        if self.flag_tree && !(self.flag_dry_run || self.flag_count_only) {
+            Err(anyhow!("--tree only works with --dry-run or --count-only"))?
+        }
+
+        if self.flag_seed.is_some() && self.flag_sort != Sort::Random {
+            Err(anyhow!("--seed only does anything with --sort=random"))?
+        }
+
+        if self.flag_dereference && self.flag_no_dereference {
+            Err(anyhow!("--dereference and --no-dereference can't both be given"))?
+        }
+
+        if self.flag_verify && (self.flag_dry_run || self.flag_count_only) {
+            Err(anyhow!("--verify checks that things got deleted, which --dry-run and --count-only don't do"))?
+        }
+
+        if self.flag_verify && self.flag_interactive {
+            // Everything that was answered "no" would show up as a survivor.
+            Err(anyhow!("--verify can't tell what you skipped with --interactive from what failed to delete"))?
+        }
+
+        if self.flag_backend == Backend::Iouring && (self.flag_bury || self.flag_graveyard.is_some() || self.flag_overwrite ||
+                                                     self.flag_preserve_parent_mtime) {
+            // These all need to do something to each file right before it goes, which a batch can't.
+            Err(anyhow!("--backend=iouring doesn't work with --bury, --overwrite, or --preserve-parent-mtime"))?
+        }
+
+        if self.flag_overwrite && (self.flag_bury || self.flag_graveyard.is_some()) {
+            // Burying is so it can be put back, overwriting is so it can't.
+            Err(anyhow!("--overwrite and --bury don't go together"))?
+        }
+
+        if self.flag_prompt_timeout.is_some() && !self.flag_interactive {
+            Err(anyhow!("--prompt-timeout only does anything with --interactive"))?
+        }
+
+        #[cfg(not(unix))]
+        for (given, what) in [(self.flag_respect_xattr.is_some(),         "--respect-xattr"),
+                              (self.flag_preserve_parent_mtime,           "--preserve-parent-mtime"),
+                              (self.flag_sync,                            "--sync"),
+                              (self.flag_apparent_size == YesNo::No,      "--apparent-size=no"),
+                              (self.flag_prompt_timeout.is_some(),        "--prompt-timeout")] {
+            if given { Err(anyhow!("{what} is only supported on unix"))? }
+        }
+
+        if let Some(ref template) = self.flag_summary_format { // Catch typos before there's anything to lose.
+            render_summary(template, |name| summary_field(name, Stats::default(), Stats::default(), 0, Duration::ZERO))?;
+        }
+        if !(self.flag_eta_window >= 0.0 && self.flag_eta_window.is_finite()) {
+            Err(anyhow!("--eta-window should be a number of seconds"))?
+        }
+        if self.flag_refresh_rate.is_some_and(|hz| !(1..=60).contains(&hz)) {
+            Err(anyhow!("--refresh-rate should be from 1 to 60 (times a second)"))?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum Sort {
+    Name,
+    Size,
+    Mtime,
+    Random,
+    None,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum FreeOrder {
+    Biggest,
+    Oldest,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum YesNo {
+    Yes,
+    No,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum Humanize {
+    Si,
+    Iec,
+    Bytes,
+}
+
+/// A size in bytes, displayed the way --humanize says.
+struct Size(u64);
+
+impl std::fmt::Display for Size {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match HUMANIZE.get().copied().unwrap_or(Humanize::Iec) {
+            Humanize::Si    => write!(f, "{}", DecimalBytes(self.0)),
+            Humanize::Iec   => write!(f, "{}", BinaryBytes(self.0)),
+            Humanize::Bytes => write!(f, "{} B", self.0),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Std,
+    Iouring,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum Progress {
+    Bar,
+    Plain,
+    None,
+}
+
+/// Where the UI (progress, prompts, totals) goes. Data (--tree, --count-only, the summaries) is always on stdout and
+/// errors are always on stderr.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stderr,
+    Stdout,
+}
+
+impl Stream {
+    fn print(self, s: &str) {
+        match self {
+            Stream::Stderr => { eprint!("{s}"); _ = std::io::stderr().flush() },
+            Stream::Stdout => { print!("{s}"); _ = std::io::stdout().flush() },
+        }
+    }
+
+    fn is_terminal(self) -> bool {
+        match self {
+            Stream::Stderr => std::io::stderr().is_terminal(),
+            Stream::Stdout => std::io::stdout().is_terminal(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum RegexScope {
+    Name,
+    Relative,
+    Absolute,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum ProgressBy {
+    Count,
+    Bytes,
+}
+
+impl ProgressBy {
+    fn of(self, stats: Stats) -> u64 {
+        match self {
+            ProgressBy::Count => stats.unlinked(),
+            ProgressBy::Bytes => stats.bytes,
+        }
+    }
 }
 
 fn main() -> Result<()> {
-    let args: Args = Docopt::new(usage())
-        .and_then(|d| d.deserialize())
+    let (argv, ask_once) = from_rm_args(&usage(), std::env::args().collect());
+    let argv = with_defaults(&usage(), read_defaults(&usage())?, argv)?;
+    let mut args: Args = Docopt::new(usage())
+        .and_then(|d| d.argv(argv).deserialize())
         .unwrap_or_else(|e| e.exit());
+    HUMANIZE.set(args.flag_humanize).expect("main() only runs once");
+
+    // Before anything looks at a path. Since this really changes directories (rather than prefixing the <path>s)
+    // everything after, including the "." and ".." checks, sees exactly what it would have if we were run from there.
+    if let Some(ref dir) = args.flag_directory {
+        std::env::set_current_dir(dir).map_err(|e| anyhow!("{dir:?}: Couldn't change to directory: {e}"))?;
+    }
+
+    if args.flag_restore {
+        let graveyard = args.flag_graveyard.map_or_else(default_graveyard, Ok)?;
+        return restore(&graveyard, args.arg_run.as_deref());
+    }
+
+    args.check()?;
+
+    // The entries take the place of the <path>s, so they get all the same checks.
+    let manifest = args.flag_manifest.as_deref().map(read_manifest).transpose()?;
+    if let Some(ref manifest) = manifest {
+        args.arg_path = manifest.iter().map(|(path, _)| path.clone()).collect();
+    }
+    if let Some(ref spec) = args.flag_by_inode {
+        args.arg_path = named_by_inode(spec)?;
+    }
+
+    if args.flag_glob && manifest.is_none() && args.flag_by_inode.is_none() { // Those names are exactly what's there.
+        args.arg_path = expand_globs(&args.arg_path, args.flag_force)?;
+    }
+    if args.flag_force {
+        args.arg_path.retain(|path| !path.symlink_metadata().is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound));
+    }
+    if args.arg_path.is_empty() { // Only possible with --force, which is fine with there being nothing to do.
+        if args.flag_nothing_status {
+            eprintln!("Nothing to delete");
+            std::process::exit(EXIT_NOTHING);
+        }
+        return Ok(());
+    }
+
+    let free = args.flag_free.as_deref().map(|size| parse_size(size).ok_or_else(|| anyhow!("Bad --free {size:?}: should be a size (eg: 500MB or 5GiB)")))
+                                         .transpose()?;
+
+    let protect_from = args.flag_protect_from.as_deref().map(Protected::load).transpose()?.map(Arc::new);
+    let options = FindOptions::new(&args, protect_from.clone())?;
+
+    let time_limit = args.flag_time_limit.as_deref().map(parse_duration).transpose()?;
+    let prompt_timeout = args.flag_prompt_timeout.as_deref().map(parse_duration).transpose()?;
+    let throttle = args.flag_throttle.as_deref().map(Throttle::parse).transpose()?;
+    let (file_delay, dir_delay) = parse_dry_run_delay(&args.flag_dry_run_delay)?;
+
+    let mut syslog = Syslog::new(args.flag_syslog, &args.flag_syslog_facility, &args.flag_syslog_level, args.flag_syslog_each)?;
+    let timing_log = Arc::new(TimingLog::new(args.flag_timing_log.as_deref())?);
+    let dir_trace = args.flag_trace_dirs.as_deref().map(DirTrace::new).transpose()?;
 
     // Do this up front so the user doesn't get halfway through a delete run before seeing failures.
-    let validator = Validator::new(!args.flag_no_preserve_root, !args.flag_no_preserve_root)?;
-    for path in args.arg_path.iter() {
+    let validator = Validator::new(!args.flag_no_preserve_root, !args.flag_no_preserve_root, !args.flag_allow_delete_cwd,
+                                   !args.flag_allow_delete_self,
+                                   !(args.flag_ignore_readonly || args.flag_dry_run || args.flag_count_only),
+                                   &args.flag_precious, protect_from)?;
+    // With --dereference the directories the links point to get emptied, so they need the same checks.
+    let targets = match args.flag_dereference {
+        true  => args.arg_path.iter().filter(|path| is_symlink_to_dir(path))
+                                     .filter_map(|path| path.canonicalize().ok()).collect(),
+        false => vec![],
+    };
+    for path in args.arg_path.iter().chain(&targets) {
+        // Missing manifest entries get reported along with everything else, rather than stopping the whole thing.
+        if manifest.is_some() && path.symlink_metadata().is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound) { continue }
         validator.validate(path)?;
+        if validator.protected(path) { // The finder skips it, but it's worth saying why up front.
+            eprintln!("{path:?}: Is in the --protect-from list, so it won't be deleted");
+        }
+    }
+    for path in args.arg_path.iter().chain(&targets) {
+        let Some(precious) = validator.precious(path) else { continue };
+        if args.flag_yes_really { continue }
+        if !args.flag_interactive {
+            Err(anyhow!("{path:?}: Refusing to delete (same as {precious:?}). You can override with `--yes-really`"))?
+        }
+        let answer = read_line(&format!("{path:?} is the same as {precious:?}. Really delete it? [y/N] "),
+                               args.flag_progress_to, prompt_timeout)?;
+        if !answer.is_some_and(|answer| answer.trim().eq_ignore_ascii_case("y")) {
+            Err(anyhow!("{path:?}: Not deleting"))?
+        }
+    }
+
+    // Otherwise what's inside gets found (and counted) twice, and whichever goes second fails with "not found".
+    if args.flag_dedupe_args {
+        let inside = overlapping(&args.arg_path);
+        for &(path, outer) in &inside {
+            eprintln!("{:?}: Is inside {:?} (which was also given), so it's left to that", args.arg_path[path], args.arg_path[outer]);
+        }
+        args.arg_path = std::mem::take(&mut args.arg_path).into_iter().enumerate()
+            .filter(|(path, _)| !inside.iter().any(|(p, _)| p == path))
+            .map(|(_, path)| path).collect();
+    }
+
+    // `rm -I`: one question before anything is deleted instead of one for everything. Like rm, a few <path>s with -d
+    // aren't worth asking about.
+    let n = args.arg_path.len();
+    if ask_once && !(args.flag_dir && n <= 3) {
+        let prompt = format!("rm: remove {n} argument{}{}? [y/N] ", if n == 1 { "" } else { "s" },
+                             if args.flag_dir { "" } else { " recursively" });
+        let answer = read_line(&prompt, args.flag_progress_to, prompt_timeout)?;
+        if !answer.is_some_and(|answer| answer.trim().eq_ignore_ascii_case("y")) { return Ok(()) }
     }
 
-    let (to_delete_tx, to_delete_rx) = sync_channel(1_000_000);
+    // Held until we exit (however we exit), at which point the OS releases the locks for us.
+    let _locks = if args.flag_lock { lock(&args.arg_path)? } else { vec![] };
+
+    let graveyard = match args.flag_graveyard {
+        _ if args.flag_dry_run || args.flag_count_only => None,
+        Some(ref graveyard) => Some(Graveyard::new(graveyard, &args.arg_path)?),
+        None if args.flag_bury => Some(Graveyard::new(&default_graveyard()?, &args.arg_path)?),
+        None => None,
+    };
+
+    #[cfg(unix)] let uid = unsafe { libc::getuid() };
+    #[cfg(not(unix))] let uid = "?";
+    syslog.log(format!("started by uid {uid}{}: {:?}", if args.flag_count_only { " (count only)" } else if args.flag_dry_run { " (dry run)" } else { "" },
+                       std::env::args_os().collect::<Vec<_>>()));
+
+    let color = match args.flag_color {
+        Color::Auto   => console::colors_enabled(), // console already looks at the terminal and CLICOLOR & friends.
+        Color::Always => true,
+        Color::Never  => false,
+    };
+    console::set_colors_enabled(color);
+    console::set_colors_enabled_stderr(color);
+
+    let display = PathDisplay::new(args.flag_relative_to.as_deref(), args.flag_relative, &args.arg_path)?;
+    let theme = Theme::new(color, args.flag_progress_template.as_deref(), args.flag_spinner_template.as_deref())?;
+
+    let deadline = time_limit.map(|limit| Instant::now() + limit);
+    // Every item in the queue holds a path, so this bounds how much memory the finder can use getting ahead.
+    let (to_delete_tx, to_delete_rx) = sync_channel(args.flag_queue_size);
+
+    // Most CI systems set $CI, and their logs only ever get appended to, so the bars would just be noise there.
+    let ci = args.flag_ci || std::env::var_os("CI").is_some_and(|ci| !["", "0", "false"].contains(&&*ci.to_string_lossy()));
+    let reporter: Arc<dyn Reporter> = match args.flag_progress.unwrap_or(if ci { Progress::Plain } else { Progress::Bar }) {
+        _ if args.flag_deterministic => Arc::new(NoReporter(args.flag_progress_to)),
+        // The tree is the progress, and it'd fight with the bars.
+        _ if args.flag_tree => Arc::new(NoReporter(args.flag_progress_to)),
+        Progress::Bar   => Arc::new(BarReporter::new(theme, args.flag_progress_to, args.flag_no_clear,
+                                                                      args.flag_refresh_rate.map(|hz| hz as u8))),
+        Progress::Plain => Arc::new(PlainReporter::new(args.flag_progress_to)),
+        Progress::None  => Arc::new(NoReporter(args.flag_progress_to)),
+    };
+    let _cleanup = ProgressCleanup(reporter.clone());
+    if !args.flag_count_only {
+        if let Some(note) = snapshot_note(&args.arg_path) { reporter.note(&note) }
+    }
+    let on_error = args.flag_on_error.as_deref().map(|cmd| OnError::new(cmd, &reporter)).transpose()?;
 
-    let progress = ProgressBar::new_spinner().with_style(ProgressStyle::with_template("Counting files{spinner:3}  {len}").unwrap()
-                                                                                                                         .tick_strings(&[".", "..", "...", ""]));
-    let path_spinner = ProgressBar::new_spinner().with_style(ProgressStyle::with_template("{spinner} {prefix:<5.245} {msg:!.214}").unwrap());
-    path_spinner.enable_steady_tick(Duration::from_secs_f32(1.0 / 10.0));
-    let totals = ProgressBar::new_spinner().with_style(ProgressStyle::with_template("{msg}, elapsed: {elapsed_precise}")?);
-    let multi = MultiProgress::new();
-    multi.add(progress.clone());
-    multi.add(path_spinner.clone());
-    multi.add(totals.clone());
+    // Saying "freed" during a dry run would be a lie.
+    let (freed, removed) = if args.flag_dry_run || args.flag_count_only { ("would free", "to remove") }
+                           else if graveyard.is_some() { ("moved", "buried") }
+                           else { ("freed", "removed") };
+    let start = Instant::now();
+    let totals = Totals { freed, removed, show_inodes: args.flag_show_inodes, throttle, start };
+    let mut eta = Eta::new(Duration::from_secs_f64(args.flag_eta_window));
+    // Before the finder starts: a SIGUSR1 while it's counting (or waiting on an --interactive answer) would kill us.
+    let summary = args.flag_summary_on_signal.then(|| SummaryOnSignal::new(&reporter, totals, args.flag_progress_by, eta))
+                                             .transpose()?;
 
-    let finder = thread::spawn({
-        let progress = progress.clone();
+    let find = {
+        let reporter = reporter.clone();
         let paths = args.arg_path.clone();
-        let multi = multi.clone();
-        move || -> Result<()> {
-            let interactive = Interactive::new(args.flag_interactive, move |f| multi.suspend(|| f()));
-            let mut finder = Find::new(&to_delete_tx, interactive);
-            for path in paths {
-                finder.find(path).map_err(|(path, err)| anyhow!("{path:?};{err}"))?;
+        let sizes = manifest.as_ref().map(|manifest| manifest.iter().map(|(_, size)| *size).collect::<Vec<_>>());
+        let prompter = Prompter::new(&reporter);
+        let options = options.clone();
+        let progress_by = args.flag_progress_by;
+        let prompt_to = args.flag_progress_to;
+        let all_or_nothing = args.flag_all_or_nothing;
+        let free = free.map(|target| (target, args.flag_free_order));
+        let timing_log = timing_log.clone();
+        // With `hold_all` nothing gets sent, it all comes back instead.
+        move |hold_all: bool| -> (Vec<ToDelete>, Result<()>) {
+            timing_log.event(Instant::now(), "counting-started");
+            let interactive = Interactive::new(args.flag_interactive, args.flag_assume_yes, prompt_timeout, prompt_to,
+                                               move |f| prompter.suspend(f));
+            let mut finder = Find::new(&to_delete_tx, interactive, options, reporter.clone());
+            if all_or_nothing || free.is_some() || hold_all { finder.hold() }
+            let found = paths.into_iter().enumerate().try_for_each(|(i, path)| {
+                match sizes {
+                    Some(ref sizes) => finder.find_listed(path, sizes[i]),
+                    None => finder.find_arg(path),
+                }.map_err(found_error)?;
                 finder.interactive.reset_state();
-            }
+                Ok(())
+            });
+            if let Some((target, order)) = free { finder.choose_to_free(target, order) }
             TOTAL.done.store(true, Ordering::Relaxed);
-            progress.set_length(TOTAL.files.load(Ordering::Relaxed));
-            progress.set_style(ProgressStyle::with_template("{elapsed_precise} {wide_bar:.on_cyan/on_17} {eta_precise}").unwrap()
-                                                                                                                        .progress_chars("   "));
-            Ok(())
+            let total = TOTAL.stats();
+            timing_log.event(Instant::now(), &format!("counting-finished bytes={} files={} dirs={} symlinks={}",
+                                                      total.bytes, total.files, total.dirs, total.symlinks));
+            reporter.found_all(progress_by.of(total));
+            match hold_all {
+                true  => finder.take_held(found, all_or_nothing),
+                false => (vec![], finder.release(found, all_or_nothing)),
+            }
         }
-    });
+    };
+    // --deterministic finds everything before deleting anything, right here, so finding and deleting are on the one
+    // thread and the order things happen in can't depend on how two would have taken turns.
+    let mut found = Ok(());
+    let (finder, mut queue) = match args.flag_deterministic {
+        true => {
+            let (held, res) = find(true);
+            found = res;
+            (None, Queue::Found(held.into()))
+        },
+        false => (Some(thread::spawn(move || find(false).1)), Queue::Finder(to_delete_rx)),
+    };
 
-    let mut done = Stats::default();
+    let mut deleter = Deleter::new(&args, reporter.clone(), display, (file_delay, dir_delay), graveyard, syslog, dir_trace);
+    let mut errors = Errors::new(&args, reporter.clone(), on_error);
+    let mut per_arg = vec![Stats::default(); args.arg_path.len()];
+    let mut arg = 0;
+    let mut timed_out = false;
+    let mut limited = false;
+    let mut last_delete = None::<Instant>; // For --timing-log.
+    let mut uring = match args.flag_backend {
+        _ if args.flag_dry_run || args.flag_count_only => None,
+        Backend::Std     => None,
+        Backend::Iouring => Uring::start()?,
+    };
+    let mut tree = args.flag_tree.then(TreePrinter::default);
+    let stats_interval = Duration::from_millis(args.flag_stats_interval);
+    let mut last_update = None::<Instant>;
+    let status_socket = args.flag_status_socket.as_deref().map(StatusSocket::new).transpose()?;
     loop {
-        match to_delete_rx.recv() {
-            Ok(ToDelete::File { size, path }) => {
-                if args.flag_dry_run {
-                    sleep(Duration::from_micros(1000));
-                } else {
-                    remove_file(&path)?;
-                }
-                path_spinner.set_message((*path.to_string_lossy()).to_owned());
-                path_spinner.set_prefix("rm");
-                done.bytes += size;
-                done.files += 1;
+        // Whatever io_uring already deleted has to be counted before stopping, whatever the reason.
+        let idle = uring.as_ref().is_none_or(|uring| !uring.deleted_some());
+        if idle && errors.stopping() {
+            break
+        }
+        // Checked up front so whatever we were in the middle of gets finished. recv_timeout() alone isn't enough
+        // since it'll happily keep returning things that are already queued.
+        if idle && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            timed_out = true;
+            break
+        }
+        let (msg, unlinked) = match uring.as_mut().and_then(Uring::next) {
+            Some((item, unlinked)) => (Ok(item), unlinked),
+            None => (queue.recv(deadline), None),
+        };
+        // The files that are waiting right behind this one go all at once, and then come back around this loop one
+        // at a time, already deleted.
+        let max = args.flag_limit.map_or(usize::MAX, |limit| limit.saturating_sub(deleter.done.unlinked()) as usize);
+        let msg = match (&mut uring, msg, &unlinked) {
+            (Some(ref mut uring), Ok(file@ToDelete::File { .. }), None) if max > 0 => {
+                uring.batch(file, &mut queue, max)?;
+                continue
             },
-            Ok(ToDelete::Dir(path)) => {
-                if args.flag_dry_run {
-                    sleep(Duration::from_micros(80));
-                } else {
-                    remove_dir(&path)?;
-                }
-                path_spinner.set_message((*path.to_string_lossy()).to_owned());
-                path_spinner.set_prefix("rmdir");
-                done.dirs += 1;
+            (_, msg, _) => msg,
+        };
+        // The finder does the arguments in order, so once a path stops being under the current one we've moved on.
+        if let Ok(ToDelete::File { ref path, .. } | ToDelete::Dir(ref path)) = msg {
+            while arg + 1 < args.arg_path.len() && !path.starts_with(&args.arg_path[arg]) { arg += 1 }
+        }
+        if let (Some(tree), Ok(ToDelete::File { ref path, .. })) = (tree.as_mut(), &msg) {
+            tree.item(&args.arg_path[arg], path, false).iter().for_each(|line| println!("{line}"));
+        }
+        if let (Some(tree), Ok(ToDelete::Dir(ref path))) = (tree.as_mut(), &msg) {
+            tree.item(&args.arg_path[arg], path, true).iter().for_each(|line| println!("{line}"));
+        }
+        // indicatif throttles the drawing, but on a tree full of tiny files all the formatting that goes into the
+        // display still shows up in profiles, so skip that too unless something's actually going to be shown.
+        let now = Instant::now();
+        let show = last_update.is_none_or(|last| now.duration_since(last) >= stats_interval);
+        let before = deleter.done;
+        let deleting = matches!(msg, Ok(ToDelete::File { .. } | ToDelete::Dir(_)));
+        let failed = match msg {
+            // Only stopping when there's another file means directories emptied by the last few still go.
+            Ok(ToDelete::File { .. }) if args.flag_limit.is_some_and(|limit| deleter.done.unlinked() >= limit) => {
+                limited = true;
+                break
             },
-            Ok(ToDelete::Err { path, err }) => {
-                _ = multi.println(format!("{path:?}: {err}"));
+            Ok(ToDelete::File { size, path, symlink }) => deleter.file(path, size, symlink, unlinked, show),
+            Ok(ToDelete::Dir(path)) => deleter.dir(path, show),
+            Ok(ToDelete::Err { path, err }) => deleter.couldnt_find(path, err),
+            Ok(ToDelete::DirStart { .. } | ToDelete::DirDone { .. }) => continue, // Nothing here shows these.
+            Err(RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                break
             },
-            Err(_) => {  /* read-on-close-channel, ie: done. We'll get the real status from the join so just ignore this. */
+            Err(RecvTimeoutError::Disconnected) => {  /* read-on-close-channel, ie: done. We'll get the real status from the join so just ignore this. */
                 break
             },
+        };
+        if deleting {
+            if last_delete.is_none() { timing_log.event(now, "first-delete") }
+            last_delete = Some(Instant::now());
         }
-        match (TOTAL.done.load(Ordering::Relaxed),
-               TOTAL.bytes.load(Ordering::Relaxed),
-               TOTAL.files.load(Ordering::Relaxed),
-               TOTAL.dirs.load(Ordering::Relaxed)) {
-            (true, bytes, files, dirs) => {
-                totals.set_message(format!("Total: freed: {}/{}, directories removed: {}/{}, files removed: {}/{}",
-                                           HumanBytes(done.bytes), HumanBytes(bytes),
-                                           done.dirs, dirs,
-                                           done.files, files));
-            },
-            _ => {
-                totals.set_message(format!("Total: freed: {}, directories removed: {}, files removed: {}",
-                                           HumanBytes(done.bytes), done.dirs, done.files));
-            },
+        if let Some((path, err)) = failed {
+            if errors.add(path, err) {
+                continue // The top of the loop stops.
+            }
+        }
+        let done = deleter.done;
+        let (position, length) = (args.flag_progress_by.of(done), args.flag_progress_by.of(TOTAL.stats()));
+        eta.update(now, position);
+        if let Some(ref summary) = summary { summary.update(done, eta) }
+        if show {
+            last_update = Some(now);
+            let remaining = eta.show(length.saturating_sub(position));
+            let eta = match TOTAL.done.load(Ordering::Relaxed) {
+                true => remaining,
+                // Still counting, so `length` is only what's been found so far and there's more where that came from.
+                false if args.flag_provisional_eta => format!("ETA at least {remaining}"),
+                false => String::new(),
+            };
+            reporter.update(position, length, &totals.message(done), &eta);
+            if let Some(ref status_socket) = status_socket { status_socket.update(done, errors.count) }
+        }
+        per_arg[arg] += done - before;
+        if let (Some(throttle), false) = (throttle, args.flag_count_only) {
+            let wait = throttle.wait(done, start.elapsed());
+            let wait = deadline.map_or(wait, |deadline| wait.min(deadline.saturating_duration_since(Instant::now())));
+            if !wait.is_zero() { sleep(wait) }
+        }
+    }
+    if let Some(last_delete) = last_delete {
+        timing_log.event(last_delete, "last-delete");
+    }
+    if args.flag_no_clear { // The last update may have been skipped, and this one is going to stay on the screen.
+        let done = deleter.done;
+        let (position, length) = (args.flag_progress_by.of(done), args.flag_progress_by.of(TOTAL.stats()));
+        reporter.update(position, length, &totals.message(done), &eta.show(length.saturating_sub(position)));
+    }
+
+    if !(errors.stopping() || timed_out || limited) {
+        deleter.prune(&args.arg_path)?;
+    }
+    errors.report_sorted();
+    errors.count += deleter.finish(&args.arg_path);
+    let done = deleter.done;
+
+    if let Some(status_socket) = status_socket {
+        status_socket.finish(done, errors.count);
+    }
+
+    if args.flag_count_only {
+        // This is the whole point, so it goes to stdout where it can't be hidden.
+        reporter.clear();
+        println!("{} in {} files{} and {} directories", Size(done.bytes), HumanCount(done.files), symlinks(done),
+                 HumanCount(done.dirs));
+    } else if args.flag_dry_run {
+        reporter.finish(&format!("Would free {} across {} files{} and {} directories",
+                                 Size(done.bytes), HumanCount(done.files), symlinks(done), HumanCount(done.dirs)));
+    } else if deleter.trashed > 0 {
+        reporter.finish(&format!("{} ({} of them moved to the trash instead)", totals.message(done), deleter.trashed));
+    } else {
+        reporter.finish(&totals.message(done));
+    }
+
+    // Now that the progress is out of the way for good.
+    if let Some(ref list) = errors.list {
+        write_error_list(list, args.flag_errors_to.as_deref())?;
+    }
+
+    if args.flag_per_arg_summary {
+        for (path, stats) in args.arg_path.iter().zip(per_arg.iter()) {
+            println!("{path:?}: {freed}: {}, directories {removed}: {}, files {removed}: {}{}",
+                     Size(stats.bytes), stats.dirs, stats.files,
+                     if stats.symlinks > 0 { format!(", symlinks {removed}: {}", stats.symlinks) } else { String::new() });
         }
-        progress.set_position(done.files);
-        progress.set_length(TOTAL.files.load(Ordering::Relaxed));
     }
 
-    totals.finish();
-    path_spinner.finish_and_clear();
-    progress.finish_and_clear();
+    if let Some(by_extension) = deleter.by_extension.take() {
+        let mut by_extension: Vec<_> = by_extension.into_iter().collect();
+        by_extension.sort_by(|(a_ext, a), (b_ext, b)| b.bytes.cmp(&a.bytes).then_with(|| a_ext.cmp(b_ext)));
+        for (ext, stats) in by_extension {
+            let ext = match ext { Some(ext) => format!(".{}", ext.to_string_lossy()), None => "(none)".to_owned() };
+            println!("{ext}: {freed}: {}, files {removed}: {}", Size(stats.bytes), stats.files);
+        }
+    }
 
-    match finder.join() {
-        Ok(res) => res?,
-        Err(e) => panic::resume_unwind(e),
+    drop(queue); // If we stopped early this unblocks the finder so we can join it.
+    let found = match finder.map(thread::JoinHandle::join) {
+        // Whatever made us stop listening gets reported below, and it shouldn't count as a second error either.
+        Some(Ok(Err(e))) if e.is::<ChannelClosed>() => Ok(()),
+        Some(Ok(res)) => res,
+        Some(Err(e)) => panic::resume_unwind(e),
+        None => found,
+    };
+    if let Some(on_error) = errors.on_error.take() {
+        on_error.finish();
+    }
+    let total = TOTAL.stats(); // Before --verify's pass adds to it.
+    let mut survivors = 0;
+    // Stopping early leaves things behind on purpose, so there's no point checking.
+    if args.flag_verify && !errors.stopping() && !timed_out && !limited {
+        for (path, err) in survivors_of(&args.arg_path, options, args.flag_queue_size)? {
+            match err {
+                Some(err) => reporter.error(format!("{path:?}: Couldn't verify: {err}\n").as_bytes()),
+                None      => reporter.error(format!("{path:?}: Still there after deleting\n").as_bytes()),
+            }
+            survivors += 1;
+        }
+    }
+    let all_errors = errors.count + errors.stopped.is_some() as u64 + found.is_err() as u64;
+    if let Some(ref template) = args.flag_summary_format {
+        let elapsed = start.elapsed();
+        println!("{}", render_summary(template, |name| summary_field(name, done, total, all_errors, elapsed))?);
+    }
+    deleter.syslog.log(format!("finished: {freed} {} bytes, directories {removed}: {}, files {removed}: {}, symlinks {removed}: {}, \
+                                errors: {}{}",
+                               done.bytes, done.dirs, done.files, done.symlinks, all_errors,
+                               if errors.stopped.is_some() { " (stopped on error)" } else if errors.too_many { " (too many errors)" }
+                               else if timed_out { " (time limit reached)" } else if limited { " (limit reached)" } else { "" }));
+    timing_log.event(Instant::now(), &format!("run-finished errors={all_errors}"));
+    if let Some(err) = errors.stopped {
+        return Err(err);
+    }
+    if errors.too_many {
+        Err(anyhow!("Too many errors ({}), aborting", errors.count))?
+    }
+    if timed_out {
+        eprintln!("Stopped early: reached the time limit of {}", args.flag_time_limit.unwrap_or_default());
+        std::process::exit(EXIT_TIME_LIMIT);
+    }
+    if limited {
+        eprintln!("Stopped early: reached the limit of {} files", args.flag_limit.unwrap_or_default());
+        std::process::exit(EXIT_LIMIT);
+    }
+    found?;
+    if errors.count > 0 {
+        Err(anyhow!("Finished with {} error{}", errors.count, if errors.count == 1 { "" } else { "s" }))?
+    }
+    if let Some(target) = free.filter(|target| done.bytes < *target) {
+        eprintln!("Could only free {} of the {} asked for with --free (that's everything there was)", Size(done.bytes),
+                  Size(target));
+        std::process::exit(EXIT_FREE_SHORT);
+    }
+    if survivors > 0 {
+        Err(anyhow!("--verify found {survivors} thing{} that should have been deleted", if survivors == 1 { "" } else { "s" }))?
+    }
+    // Whether that's because the <path>s weren't there (with --force), the filters didn't match anything, or every
+    // answer to --interactive was no. With --dry-run and --count-only it's what would have been deleted.
+    if args.flag_nothing_status && done.unlinked() == 0 && done.dirs == 0 {
+        eprintln!("Nothing to delete");
+        std::process::exit(EXIT_NOTHING);
     }
     Ok(())
 }
 
+/// Where the delete loop gets things from: the finder thread as it goes, or (for --deterministic) everything it found
+/// before the deleting started.
+enum Queue {
+    Finder(Receiver<ToDelete>),
+    Found(std::collections::VecDeque<ToDelete>),
+}
 
-struct AtomicStats {
-    bytes: AtomicU64,
-    files: AtomicU64,
-    dirs:  AtomicU64,
-    done:  AtomicBool,
+impl Queue {
+    /// Waits for the next thing until `deadline` (or for as long as it takes). Disconnected means that was all of it.
+    fn recv(&mut self, deadline: Option<Instant>) -> std::result::Result<ToDelete, RecvTimeoutError> {
+        match (self, deadline) {
+            (Queue::Finder(rx), Some(deadline)) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            (Queue::Finder(rx), None) => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            (Queue::Found(found), _) => found.pop_front().ok_or(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    /// The next thing, if it's already there. For io_uring batches.
+    #[cfg(all(target_os = "linux", feature = "iouring"))]
+    fn try_recv(&mut self) -> Option<ToDelete> {
+        match self {
+            Queue::Finder(rx) => rx.try_recv().ok(),
+            Queue::Found(found) => found.pop_front(),
+        }
+    }
 }
 
-#[derive(Default, Clone, Copy)]
-struct Stats {
-    bytes: u64,
-    files: u64,
-    dirs:  u64,
+/// What the delete loop does with each thing the finder hands over: deletes it (or buries it, overwrites it first, or
+/// just pretends for --dry-run and --count-only), and keeps everything that wants to know about it up to date. When
+/// to stop, and what to do about a failure, is up to main() and [`Errors`].
+struct Deleter {
+    reporter:      Arc<dyn Reporter>,
+    json:          Option<Stream>,
+    display:       PathDisplay,
+    count_only:    bool,
+    dry_run:       bool,
+    trash_on_fail: bool,
+    file_delay:    Duration, // For --dry-run
+    dir_delay:     Duration,
+    graveyard:     Option<Graveyard>,
+    overwrite:     Option<Overwrite>,
+    parent_mtimes: Option<ParentMtimes>,
+    sync_dirs:     Option<SyncDirs>,
+    prune:         Option<SyncDirs>,
+    dir_trace:     Option<DirTrace>,
+    syslog:        Syslog,
+    done:          Stats,
+    trashed:       u64,
+    by_extension:  Option<std::collections::HashMap<Option<std::ffi::OsString>, Stats>>,
 }
 
+impl Deleter {
+    fn new(args: &Args, reporter: Arc<dyn Reporter>, display: PathDisplay, (file_delay, dir_delay): (Duration, Duration),
+           graveyard: Option<Graveyard>, syslog: Syslog, dir_trace: Option<DirTrace>) -> Deleter {
+        let deleting = !args.flag_dry_run && !args.flag_count_only;
+        Deleter {
+            reporter,
+            json:          args.flag_report_errors_json,
+            display,
+            count_only:    args.flag_count_only,
+            dry_run:       args.flag_dry_run,
+            trash_on_fail: args.flag_trash_on_fail,
+            file_delay,
+            dir_delay,
+            graveyard,
+            overwrite:     args.flag_overwrite.then(Overwrite::default),
+            parent_mtimes: (args.flag_preserve_parent_mtime && deleting).then(ParentMtimes::default),
+            sync_dirs:     (args.flag_sync && deleting).then(SyncDirs::default),
+            prune:         (args.flag_prune && deleting).then(SyncDirs::default),
+            dir_trace,
+            syslog,
+            done:          Stats::default(),
+            trashed:       0,
+            by_extension:  args.flag_by_extension.then(std::collections::HashMap::new),
+        }
+    }
 
-enum ToDelete {
-    File { size: u64, path: PathBuf },
-    Dir(PathBuf),
-    Err { path: PathBuf, err: Error },
+    /// `unlinked` is set when io_uring already deleted it. `show` is whether to put it in the progress display. What
+    /// went wrong, if anything, comes back for main() to deal with.
+    fn file(&mut self, path: PathBuf, size: u64, symlink: bool, unlinked: Option<std::io::Result<()>>, show: bool)
+            -> Option<(PathBuf, Error)> {
+        if let Some(ref mut parent_mtimes) = self.parent_mtimes { parent_mtimes.before(&path) }
+        let res = if self.count_only {
+            Ok(false)
+        } else if self.dry_run {
+            sleep(self.file_delay);
+            Ok(false)
+        } else if let Some(unlinked) = unlinked {
+            remove(|_| unlinked, &path, false, self.trash_on_fail)
+        } else {
+            remove(|p| match (&mut self.graveyard, &mut self.overwrite) {
+                       (Some(ref mut g), _) => via_short_path(p, |p| g.bury(p, false)),
+                       (None, Some(ref mut o)) => via_short_path(p, |p| o.file(p, &*self.reporter)).and_then(|_| unlink(p, false)),
+                       (None, None) => unlink(p, false),
+                   }, &path, false, self.trash_on_fail)
+        };
+        if show && !self.count_only { self.reporter.deleting("rm", &self.display.show(&path)) }
+        match res {
+            Ok(to_trash) => { self.done.bytes += size;
+                              if symlink { self.done.symlinks += 1 } else { self.done.files += 1 }
+                              self.trashed += to_trash as u64;
+                              if let (Some(by_extension), false) = (self.by_extension.as_mut(), symlink) {
+                                  *by_extension.entry(path.extension().map(|ext| ext.to_owned())).or_default()
+                                      += Stats { bytes: size, files: 1, ..Stats::default() };
+                              }
+                              if let Some(ref mut sync_dirs) = self.sync_dirs { sync_dirs.deleted(&path) }
+                              if let Some(ref mut prune) = self.prune { prune.deleted(&path) }
+                              if let Some(ref mut dir_trace) = self.dir_trace { dir_trace.item(&path, None) }
+                              self.syslog.each(|| format!("{} {path:?}", if to_trash { "trash" } else { "rm" }));
+                              None },
+            Err(e) => { let err = prefixed("rm", e);
+                        if let Some(ref mut dir_trace) = self.dir_trace { dir_trace.item(&path, Some(&err)) }
+                        Some((path, err)) },
+        }
+    }
+
+    fn dir(&mut self, path: PathBuf, show: bool) -> Option<(PathBuf, Error)> {
+        if let Some(ref mut parent_mtimes) = self.parent_mtimes { parent_mtimes.before(&path) }
+        let res = if self.count_only {
+            Ok(false)
+        } else if self.dry_run {
+            sleep(self.dir_delay);
+            Ok(false)
+        } else {
+            remove(|p| match self.graveyard { Some(ref mut g) => via_short_path(p, |p| g.bury(p, true)), None => unlink(p, true) },
+                   &path, true, self.trash_on_fail)
+        };
+        if show && !self.count_only { self.reporter.deleting("rmdir", &self.display.show(&path)) }
+        match res {
+            Ok(to_trash) => { self.done.dirs += 1;
+                              self.trashed += to_trash as u64;
+                              if let Some(ref mut parent_mtimes) = self.parent_mtimes { parent_mtimes.gone(&path) }
+                              if let Some(ref mut sync_dirs) = self.sync_dirs { sync_dirs.deleted(&path) }
+                              if let Some(ref mut prune) = self.prune { prune.deleted(&path) }
+                              if let Some(ref mut dir_trace) = self.dir_trace { dir_trace.dir(&path, None) }
+                              self.syslog.each(|| format!("{} {path:?}", if to_trash { "trash" } else { "rmdir" }));
+                              None },
+            Err(e) => { let err = prefixed("rmdir", e);
+                        if let Some(ref mut dir_trace) = self.dir_trace { dir_trace.dir(&path, Some(&err)) }
+                        Some((path, err)) },
+        }
+    }
+
+    /// Something the finder couldn't get at.
+    fn couldnt_find(&mut self, path: PathBuf, err: Error) -> Option<(PathBuf, Error)> {
+        if let Some(ref mut dir_trace) = self.dir_trace { dir_trace.item(&path, Some(&err)) }
+        Some((path, err))
+    }
+
+    /// For --prune. Only up to the directories the entries were in, and not past where those meet, so that a manifest
+    /// of one file doesn't take its parents with it all the way up to the root.
+    fn prune(&mut self, paths: &[PathBuf]) -> Result<()> {
+        let Some(prune) = self.prune.take() else { return Ok(()) };
+        let parents: Vec<_> = paths.iter().filter_map(|path| parent_dir(path)).map(Path::to_owned).collect();
+        let top = common_parent(&parents)?;
+        for dir in prune.prune(&top) {
+            self.done.dirs += 1;
+            if let Some(ref mut sync_dirs) = self.sync_dirs { sync_dirs.deleted(&dir) }
+            self.syslog.each(|| format!("rmdir {dir:?}"));
+        }
+        Ok(())
+    }
+
+    /// Everything that has to wait until the deleting is over. Returns how many errors that turned up that count
+    /// towards the exit status.
+    fn finish(&mut self, paths: &[PathBuf]) -> u64 {
+        let json = self.json;
+        if let Some(dir_trace) = self.dir_trace.take() {
+            dir_trace.finish(paths);
+        }
+        if let Some(parent_mtimes) = self.parent_mtimes.take() {
+            for (path, err) in parent_mtimes.restore() {
+                report_error(&*self.reporter, json, &error_line(&path, &os_error("Couldn't put the modification time back", err),
+                                                                json.is_some()));
+            }
+        }
+        let mut errors = 0;
+        if let Some(sync_dirs) = self.sync_dirs.take() { // After the mtimes, so those are on disk too.
+            for (path, err) in sync_dirs.sync() {
+                report_error(&*self.reporter, json, &error_line(&path, &os_error("Couldn't sync", err), json.is_some()));
+                errors += 1;
+            }
+        }
+        errors
+    }
 }
 
-impl ToDelete {
-    fn path(self) -> PathBuf {
-        match self {
-            ToDelete::File { path, .. } | ToDelete::Dir(path) | ToDelete::Err { path, .. } => path
+/// Everything that couldn't be found or deleted: it gets reported (straight away, or sorted at the end for
+/// --deterministic), handed to --on-error, kept for --list-errors, and counted towards --stop-on-error and
+/// --max-errors.
+struct Errors {
+    reporter:      Arc<dyn Reporter>,
+    on_error:      Option<OnError>,
+    raw_paths:     bool,
+    json:          Option<Stream>,
+    stop_on_error: bool,
+    max:           Option<u64>,
+    count:         u64,
+    list:          Option<Vec<Vec<u8>>>, // For --list-errors and --errors-to
+    // Held until the end and sorted by path so they come out in one block, in the same order whatever the tree
+    // looked like.
+    sorted:        Option<Vec<Vec<u8>>>,
+    stopped:       Option<Error>,
+    too_many:      bool,
+}
+
+impl Errors {
+    fn new(args: &Args, reporter: Arc<dyn Reporter>, on_error: Option<OnError>) -> Errors {
+        Errors {
+            reporter,
+            on_error,
+            raw_paths:     args.flag_raw_paths,
+            json:          args.flag_report_errors_json,
+            stop_on_error: args.flag_stop_on_error,
+            max:           args.flag_max_errors,
+            count:         0,
+            list:          (args.flag_list_errors || args.flag_errors_to.is_some()).then(Vec::new),
+            sorted:        args.flag_deterministic.then(Vec::new),
+            stopped:       None,
+            too_many:      false,
+        }
+    }
+
+    /// --stop-on-error or --max-errors says that's it.
+    fn stopping(&self) -> bool {
+        self.stopped.is_some() || self.too_many
+    }
+
+    /// True when this one means stopping.
+    fn add(&mut self, path: PathBuf, err: Error) -> bool {
+        if let Some(ref on_error) = self.on_error {
+            on_error.run(&path, &err);
         }
+        let line = if self.raw_paths {
+            let mut line = raw_path_bytes(&path).into_owned();
+            line.extend_from_slice(format!(": {err}\n").as_bytes());
+            line
+        } else {
+            error_line(&path, &err, false)
+        };
+        // Those can't go in the error that --stop-on-error ends with, so they're reported straight away.
+        let early = self.raw_paths || self.json.is_some();
+        let shown = if self.json.is_some() { error_line(&path, &err, true) } else { line.clone() };
+        if early { self.report(shown.clone()) }
+        if let Some(ref mut list) = self.list {
+            list.push(line);
+        }
+        if self.stop_on_error && self.stopped.is_none() { // Later ones are the rest of an io_uring batch.
+            self.stopped = Some(if early { anyhow!("Stopped on error") } else { anyhow!("{path:?}: {err}") });
+            return true
+        }
+        self.count += 1;
+        if !early { self.report(shown) }
+        if self.max.is_some_and(|max| self.count >= max) {
+            self.too_many = true;
+            return true
+        }
+        false
+    }
+
+    fn report(&mut self, line: Vec<u8>) {
+        match self.sorted {
+            Some(ref mut sorted) => sorted.push(line),
+            None => report_error(&*self.reporter, self.json, &line),
+        }
+    }
+
+    /// The ones --deterministic held back.
+    fn report_sorted(&mut self) {
+        let Some(mut sorted) = self.sorted.take() else { return };
+        sorted.sort();
+        sorted.iter().for_each(|line| report_error(&*self.reporter, self.json, line));
     }
 }
 
-struct Find<'a> {
-    tx: &'a SyncSender<ToDelete>,
-    interactive: Interactive,
+/// For --verify. Runs the finder over the <path>s again with the same options, so whatever it comes up with is
+/// something that should have been deleted and wasn't: the delete failed (maybe without saying so) or something
+/// put it back. A <path> that's gone is the whole point, so that's not an error here.
+fn survivors_of(paths: &[PathBuf], options: FindOptions, queue_size: usize) -> Result<Vec<(PathBuf, Option<Error>)>> {
+    let (tx, rx) = sync_channel(queue_size);
+    let paths: Vec<PathBuf> = paths.iter().filter(|path| path.symlink_metadata().is_ok()).cloned().collect();
+    let finder = thread::spawn(move || -> Result<()> {
+        let mut finder = Find::new(&tx, Interactive::new(false, false, None, Stream::Stderr, |ask| ask()), options,
+                                    Arc::new(NoReporter(Stream::Stderr)));
+        for path in paths {
+            finder.find_arg(path).map_err(found_error)?;
+        }
+        Ok(())
+    });
+    let survivors = rx.iter().filter_map(|item| match item {
+        ToDelete::Err { path, err } => Some((path, Some(err))),
+        ToDelete::DirStart { .. } | ToDelete::DirDone { .. } => None,
+        item => Some((item.path(), None)),
+    }).collect();
+    match finder.join() {
+        Ok(res) => res?,
+        Err(e) => panic::resume_unwind(e),
+    };
+    Ok(survivors)
 }
 
-type FindResult<T> = std::result::Result<T, (PathBuf, anyhow::Error)>;
+/// Audit trail for `--syslog`. Nothing here is allowed to get in the way of the actual deleting, so if syslog
+/// stops working we complain once and then carry on without it.
+#[cfg(unix)]
+struct Syslog {
+    logger: Option<syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>>,
+    severity: syslog::Severity,
+    each: bool,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    fn new(enable: bool, facility: &str, level: &str, each: bool) -> Result<Syslog> {
+        use syslog::Severity::*;
+        let facility = facility.parse().map_err(|_| anyhow!("Unknown syslog facility {facility:?}"))?;
+        let severity = match level.to_lowercase().as_str() {
+            "emerg" => LOG_EMERG, "alert" => LOG_ALERT, "crit" => LOG_CRIT, "err" => LOG_ERR,
+            "warning" => LOG_WARNING, "notice" => LOG_NOTICE, "info" => LOG_INFO, "debug" => LOG_DEBUG,
+            _ => Err(anyhow!("Unknown syslog level {level:?}"))?,
+        };
+        let logger = match enable {
+            false => None,
+            true => syslog::unix(syslog::Formatter3164 { facility, hostname: None, process: "rm-rfp".into(), pid: std::process::id() })
+                .map_err(|e| eprintln!("Couldn't connect to syslog, continuing without it: {e}"))
+                .ok(),
+        };
+        Ok(Syslog { logger, severity, each })
+    }
+
+    fn log(&mut self, msg: String) {
+        self.log_at(self.severity, msg)
+    }
+
+    fn each(&mut self, msg: impl FnOnce() -> String) {
+        if self.each && self.logger.is_some() { self.log_at(syslog::Severity::LOG_DEBUG, msg()) }
+    }
+
+    fn log_at(&mut self, severity: syslog::Severity, msg: String) {
+        use syslog::LogFormat;
+        let Some(ref mut logger) = self.logger else { return };
+        if let Err(e) = logger.formatter.format(&mut logger.backend, severity, msg) {
+            eprintln!("Couldn't log to syslog, continuing without it: {e}");
+            self.logger = None;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Syslog;
+
+#[cfg(not(unix))]
+impl Syslog {
+    fn new(enable: bool, _facility: &str, _level: &str, _each: bool) -> Result<Syslog> {
+        if enable { Err(anyhow!("--syslog is only supported on unix"))? }
+        Ok(Syslog)
+    }
+    fn log(&mut self, _msg: String) {}
+    fn each(&mut self, _msg: impl FnOnce() -> String) {}
+}
+
+/// For --timing-log: a line per event, with both the time (seconds since the epoch) and the seconds since the log was
+/// opened so the gaps are easy to see. Files aren't buffered, so each event is on disk (well, in the page cache) right
+/// away and a run that gets killed still leaves what it got through.
+struct TimingLog {
+    file:  std::sync::Mutex<Option<std::fs::File>>,
+    start: Instant,
+}
+
+impl TimingLog {
+    fn new(path: Option<&Path>) -> Result<TimingLog> {
+        let file = path.map(|path| std::fs::File::create(path).map_err(|e| anyhow!("--timing-log: {path:?}: {e}")))
+                       .transpose()?;
+        Ok(TimingLog { file: std::sync::Mutex::new(file), start: Instant::now() })
+    }
+
+    /// `at` is when it happened, which isn't always now.
+    fn event(&self, at: Instant, event: &str) {
+        let mut file = self.file.lock().unwrap();
+        let Some(ref mut f) = *file else { return };
+        let now = Instant::now();
+        let when = SystemTime::now().checked_sub(now.saturating_duration_since(at)).unwrap_or(SystemTime::UNIX_EPOCH);
+        let epoch = when.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let line = format!("{:.3} +{:.3} {event}\n", epoch.as_secs_f64(), at.saturating_duration_since(self.start).as_secs_f64());
+        if let Err(e) = f.write_all(line.as_bytes()) {
+            eprintln!("Couldn't write to the --timing-log, continuing without it: {e}");
+            *file = None;
+        }
+    }
+}
+
+/// For --trace-dirs: what happened to everything in each directory, written out when the directory itself is deleted
+/// (or isn't). When deleting it fails, whatever is in it now that never came from the finder gets listed too, since
+/// that's usually the culprit (something put it there while we were deleting). Directories that were kept on purpose
+/// (something in them was left out) are written at the end.
+struct DirTrace {
+    file: Option<std::fs::File>,
+    children: std::collections::HashMap<PathBuf, Vec<(std::ffi::OsString, Option<String>)>>, // name, error
+}
+
+impl DirTrace {
+    fn new(path: &Path) -> Result<DirTrace> {
+        let file = std::fs::File::create(path).map_err(|e| anyhow!("--trace-dirs: {path:?}: {e}"))?;
+        Ok(DirTrace { file: Some(file), children: Default::default() })
+    }
+
+    /// Something in a directory was deleted, or `err` says why not.
+    fn item(&mut self, path: &Path, err: Option<&Error>) {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else { return };
+        self.children.entry(parent.to_owned()).or_default().push((name.to_owned(), err.map(|err| err.to_string())));
+    }
+
+    fn dir(&mut self, path: &Path, err: Option<&Error>) {
+        self.item(path, err);
+        let children = self.children.remove(path).unwrap_or_default();
+        match err {
+            None      => self.write(path, "deleted", &children, false),
+            Some(err) => self.write(path, &format!("not deleted: {err}"), &children, true),
+        }
+    }
+
+    /// The directories under the <path>s that things were deleted from but that weren't deleted themselves.
+    fn finish(mut self, paths: &[PathBuf]) {
+        let mut kept: Vec<_> = std::mem::take(&mut self.children).into_iter()
+                                   .filter(|(dir, _)| paths.iter().any(|path| dir.starts_with(path))).collect();
+        kept.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (dir, children) in kept {
+            self.write(&dir, "kept", &children, false);
+        }
+    }
+
+    fn write(&mut self, path: &Path, what: &str, children: &[(std::ffi::OsString, Option<String>)], left: bool) {
+        let mut out = format!("{path:?}: {what}\n");
+        for (name, err) in children {
+            match err {
+                None      => out += &format!("  ok     {name:?}\n"),
+                Some(err) => out += &format!("  failed {name:?}: {err}\n"),
+            }
+        }
+        if left {
+            let known: std::collections::HashSet<_> = children.iter().map(|(name, _)| name).collect();
+            let mut left: Vec<_> = list_dir(path).into_iter().flatten().flatten()
+                                       .map(|(name, _)| name).filter(|name| !known.contains(name)).collect();
+            left.sort();
+            left.iter().for_each(|name| out += &format!("  left   {name:?}\n"));
+        }
+        let Some(ref mut file) = self.file else { return };
+        if let Err(e) = file.write_all(out.as_bytes()) {
+            eprintln!("Couldn't write to the --trace-dirs file, continuing without it: {e}");
+            self.file = None;
+        }
+    }
+}
+
+/// For --status-socket. Everything that connects gets a JSON line with the totals every second, and a last one (with
+/// "finished": true) at the end. The delete loop only copies the numbers in, and each client has its own thread to do
+/// the writing, so one that's slow or stuck can't hold up the deleting.
+#[cfg(unix)]
+struct StatusSocket {
+    path:    PathBuf,
+    status:  Arc<(std::sync::Mutex<SocketStatus>, std::sync::Condvar)>,
+    clients: Arc<std::sync::Mutex<Vec<thread::JoinHandle<()>>>>,
+}
+
+#[cfg(unix)]
+#[derive(Default, Clone, Copy)]
+struct SocketStatus {
+    done:     Stats,
+    errors:   u64,
+    finished: bool,
+}
+
+#[cfg(unix)]
+impl StatusSocket {
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    fn new(path: &Path) -> Result<StatusSocket> {
+        use std::os::unix::{fs::FileTypeExt, net::{UnixListener, UnixStream}};
+        // One left behind by a run that got killed would be in the way forever otherwise. But one that's still
+        // being listened on belongs to some other run, and its monitors are watching that.
+        if path.symlink_metadata().is_ok_and(|m| m.file_type().is_socket()) {
+            match UnixStream::connect(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => _ = std::fs::remove_file(path),
+                Err(_) => {}, // Let bind() say what's wrong.
+                Ok(_) => Err(anyhow!("--status-socket: {path:?}: Already in use (by another rm-rfp?)"))?,
+            }
+        }
+        let listener = UnixListener::bind(path).map_err(|e| anyhow!("--status-socket: {path:?}: {e}"))?;
+        let start = Instant::now();
+        let status = Arc::new((std::sync::Mutex::new(SocketStatus::default()), std::sync::Condvar::new()));
+        let clients = Arc::new(std::sync::Mutex::new(vec![]));
+        let (status_, clients_) = (status.clone(), clients.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming().filter_map(|stream| stream.ok()) {
+                let status = status_.clone();
+                clients_.lock().unwrap().push(thread::spawn(move || Self::client(stream, &status, start)));
+            }
+        });
+        Ok(StatusSocket { path: path.to_owned(), status, clients })
+    }
+
+    fn client(mut stream: std::os::unix::net::UnixStream, status: &(std::sync::Mutex<SocketStatus>, std::sync::Condvar),
+              start: Instant) {
+        // So that finish() isn't left waiting on a client that's stopped reading.
+        _ = stream.set_write_timeout(Some(Self::INTERVAL));
+        loop {
+            let now = *status.0.lock().unwrap();
+            // A write error is the client going away, which is its business.
+            if stream.write_all(Self::json(now, start.elapsed()).as_bytes()).is_err() || now.finished { return }
+            let current = status.0.lock().unwrap();
+            if !current.finished { _ = status.1.wait_timeout(current, Self::INTERVAL).unwrap() }
+        }
+    }
+
+    fn json(status: SocketStatus, elapsed: Duration) -> String {
+        let stats = |s: Stats| format!("{{\"bytes\":{},\"files\":{},\"dirs\":{},\"symlinks\":{}}}",
+                                       s.bytes, s.files, s.dirs, s.symlinks);
+        format!("{{\"elapsed\":{:.3},\"found\":{},\"found_all\":{},\"done\":{},\"errors\":{},\"finished\":{}}}\n",
+                elapsed.as_secs_f64(), stats(TOTAL.stats()), TOTAL.done.load(Ordering::Relaxed), stats(status.done),
+                status.errors, status.finished)
+    }
+
+    fn update(&self, done: Stats, errors: u64) {
+        let mut status = self.status.0.lock().unwrap();
+        status.done = done;
+        status.errors = errors;
+    }
+
+    /// Sends everyone connected the final totals.
+    fn finish(self, done: Stats, errors: u64) {
+        *self.status.0.lock().unwrap() = SocketStatus { done, errors, finished: true };
+        self.status.1.notify_all();
+        let clients = std::mem::take(&mut *self.clients.lock().unwrap());
+        clients.into_iter().for_each(|client| _ = client.join());
+    }
+}
+
+#[cfg(unix)]
+impl Drop for StatusSocket {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(not(unix))]
+struct StatusSocket;
+
+#[cfg(not(unix))]
+impl StatusSocket {
+    fn new(_path: &Path) -> Result<StatusSocket> {
+        Err(anyhow!("--status-socket is only supported on unix"))
+    }
+    fn update(&self, _done: Stats, _errors: u64) {}
+    fn finish(self, _done: Stats, _errors: u64) {}
+}
+
+/// For --tree. Things come from the finder in the order they'd be deleted, which means a directory comes after
+/// everything in it. That's hard to read, so a directory's line is printed as soon as the first thing inside it
+/// shows up instead. A directory that's kept (because something in it is) still gets a line if anything in it is
+/// deleted.
+#[derive(Default)]
+struct TreePrinter {
+    open: Vec<PathBuf>, // Directories whose line has been printed, outermost first.
+}
+
+impl TreePrinter {
+    fn item(&mut self, root: &Path, path: &Path, is_dir: bool) -> Vec<String> {
+        while self.open.last().is_some_and(|dir| !path.starts_with(dir)) {
+            self.open.pop();
+        }
+        if is_dir && self.open.last().is_some_and(|dir| dir == path) {
+            self.open.pop();
+            return vec![]; // Already printed when we got the first thing in it.
+        }
+        let mut lines = vec![];
+        let parents: Vec<&Path> = path.ancestors().skip(1).take_while(|dir| dir.starts_with(root)).collect();
+        for dir in parents.into_iter().rev() {
+            if self.open.iter().any(|open| open == dir) { continue }
+            lines.push(Self::line(root, dir, true));
+            self.open.push(dir.to_owned());
+        }
+        lines.push(Self::line(root, path, is_dir));
+        lines
+    }
+
+    fn line(root: &Path, path: &Path, is_dir: bool) -> String {
+        let depth = path.strip_prefix(root).map_or(0, |rel| rel.components().count());
+        let name = match depth {
+            0 => path.to_string_lossy(), // The <path> itself is shown as it was given.
+            _ => path.file_name().unwrap_or_default().to_string_lossy(),
+        };
+        format!("{:indent$}{name}{}", "", if is_dir { "/" } else { "" }, indent = depth * 2)
+    }
+}
+
+/// For --on-error. The hooks run one at a time on their own thread so a slow one (or a hundred quick ones) doesn't
+/// hold up deleting, and each gets killed if it takes too long so a stuck one can't keep us from ever exiting.
+struct OnError {
+    tx:     Option<std::sync::mpsc::Sender<(PathBuf, String)>>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl OnError {
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[cfg(unix)]
+    fn new(cmd: &str, reporter: &Arc<dyn Reporter>) -> Result<OnError> {
+        let (tx, rx) = std::sync::mpsc::channel::<(PathBuf, String)>();
+        let (cmd, reporter) = (cmd.to_owned(), reporter.clone());
+        let thread = thread::spawn(move || {
+            for (path, err) in rx {
+                if let Err(e) = Self::hook(&cmd, &path, &err) {
+                    reporter.error(format!("{path:?}: --on-error: {e}\n").as_bytes());
+                }
+            }
+        });
+        Ok(OnError { tx: Some(tx), thread })
+    }
+
+    #[cfg(not(unix))]
+    fn new(_cmd: &str, _reporter: &Arc<dyn Reporter>) -> Result<OnError> {
+        Err(anyhow!("--on-error is only supported on unix"))
+    }
+
+    /// `sh -c <cmd> rm-rfp <path> <error>`, so the path is $1 and the error is $2. They're also in $RM_RFP_PATH and
+    /// $RM_RFP_ERROR for commands that aren't shell scripts.
+    #[cfg(unix)]
+    fn hook(cmd: &str, path: &Path, err: &str) -> Result<()> {
+        let mut child = std::process::Command::new("sh")
+            .arg("-c").arg(cmd).arg("rm-rfp").arg(path).arg(err)
+            .env("RM_RFP_PATH", path).env("RM_RFP_ERROR", err)
+            .stdin(std::process::Stdio::null())
+            .spawn()?;
+        let deadline = Instant::now() + Self::TIMEOUT;
+        loop {
+            match child.try_wait()? {
+                Some(status) if status.success() => return Ok(()),
+                Some(status) => Err(anyhow!("{cmd:?} failed: {status}"))?,
+                None if Instant::now() >= deadline => {
+                    _ = child.kill();
+                    _ = child.wait();
+                    Err(anyhow!("{cmd:?} took longer than {}s, killed it", Self::TIMEOUT.as_secs()))?
+                },
+                None => sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    fn run(&self, path: &Path, err: &Error) {
+        if let Some(ref tx) = self.tx {
+            _ = tx.send((path.to_owned(), err.to_string()));
+        }
+    }
+
+    /// Waits for any hooks that are still queued up.
+    fn finish(mut self) {
+        drop(self.tx.take());
+        _ = self.thread.join();
+    }
+}
+
+/// Only for showing paths to the user. The real paths are never touched.
+struct PathDisplay {
+    base: Option<PathBuf>,
+}
+
+impl PathDisplay {
+    fn new(relative_to: Option<&Path>, relative: bool, args: &[PathBuf]) -> Result<PathDisplay> {
+        let base = match (relative_to, relative) {
+            (Some(dir), _) => Some(std::path::absolute(dir)?),
+            // Use the args' parents so that each arg still shows up as at least its own name.
+            (None, true) => Some(common_parent(args)?),
+            (None, false) => None,
+        };
+        Ok(PathDisplay { base })
+    }
+
+    fn show(&self, path: &Path) -> String {
+        let Some(ref base) = self.base else { return path.to_string_lossy().into_owned() };
+        match std::path::absolute(path).as_deref().map(|p| p.strip_prefix(base)) {
+            Ok(Ok(rel)) if rel.as_os_str().is_empty() => ".".to_owned(),
+            Ok(Ok(rel)) => rel.to_string_lossy().into_owned(),
+            _ => path.to_string_lossy().into_owned(), // not under base
+        }
+    }
+}
+
+/// For --summary-format. `{name}`s get replaced by whatever `field` says (and it's an error if it doesn't know
+/// them), `{{` and `}}` are literal braces.
+fn render_summary(template: &str, field: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        out += &rest[..i];
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+            out += &rest[..1];
+            rest = after;
+            continue
+        }
+        let Some(end) = rest.find('}').filter(|_| rest.starts_with('{')) else {
+            Err(anyhow!("--summary-format: {template:?} has an unmatched {:?} (use {{{{ or }}}} for a literal one)", &rest[..1]))?
+        };
+        let name = &rest[1..end];
+        out += &field(name).ok_or_else(|| anyhow!("--summary-format: Unknown placeholder {{{name}}}. It can have {}",
+                                                    SUMMARY_FIELDS.map(|f| format!("{{{f}}}")).join(", ")))?;
+        rest = &rest[end+1..];
+    }
+    out += rest;
+    Ok(out)
+}
+
+const SUMMARY_FIELDS: [&str; 12] = ["bytes", "size", "files", "dirs", "symlinks", "total_bytes", "total_size", "total_files",
+                                    "total_dirs", "total_symlinks", "errors", "elapsed"];
+
+/// `done` is what was actually deleted and `total` is everything that was found. Sizes are human readable, bytes
+/// are exact. Elapsed is in seconds.
+fn summary_field(name: &str, done: Stats, total: Stats, errors: u64, elapsed: Duration) -> Option<String> {
+    Some(match name {
+        "bytes"       => done.bytes.to_string(),
+        "size"        => Size(done.bytes).to_string(),
+        "files"       => done.files.to_string(),
+        "dirs"        => done.dirs.to_string(),
+        "symlinks"    => done.symlinks.to_string(),
+        "total_bytes" => total.bytes.to_string(),
+        "total_size"  => Size(total.bytes).to_string(),
+        "total_files" => total.files.to_string(),
+        "total_dirs"  => total.dirs.to_string(),
+        "total_symlinks" => total.symlinks.to_string(),
+        "errors"      => errors.to_string(),
+        "elapsed"     => format!("{:.3}", elapsed.as_secs_f64()),
+        _ => None?,
+    })
+}
+
+/// The "Total: ..." line, with whatever else this run asked to have on it.
+#[derive(Clone, Copy)]
+struct Totals {
+    freed:       &'static str,
+    removed:     &'static str,
+    show_inodes: bool,
+    throttle:    Option<Throttle>,
+    start:       Instant,
+}
+
+impl Totals {
+    fn message(&self, done: Stats) -> String {
+        let inodes = self.show_inodes.then(|| (done.unlinked() + done.dirs, self.start.elapsed()));
+        let totals = totals_message(done, inodes, self.freed, self.removed);
+        match self.throttle {
+            Some(throttle) => format!("{totals}, {}", throttle.show(done, self.start.elapsed())),
+            None => totals,
+        }
+    }
+}
+
+/// `inodes` is how many and how long it took, for --show-inodes.
+fn totals_message(done: Stats, inodes: Option<(u64, Duration)>, freed: &str, removed: &str) -> String {
+    let mut totals = match (TOTAL.done.load(Ordering::Relaxed),
+           TOTAL.bytes.load(Ordering::Relaxed),
+           TOTAL.files.load(Ordering::Relaxed),
+           TOTAL.dirs.load(Ordering::Relaxed)) {
+        (true, bytes, files, dirs) => {
+            format!("Total: {freed}: {}/{}, directories {removed}: {}/{}, files {removed}: {}/{}",
+                    Size(done.bytes), Size(bytes),
+                    done.dirs, dirs,
+                    done.files, files)
+        },
+        _ => {
+            format!("Total: {freed}: {}, directories {removed}: {}, files {removed}: {}",
+                    Size(done.bytes), done.dirs, done.files)
+        },
+    };
+    // Only once there are any, so the usual line doesn't get any longer.
+    match (TOTAL.done.load(Ordering::Relaxed), TOTAL.symlinks.load(Ordering::Relaxed)) {
+        (_, 0) => {},
+        (true, symlinks) => totals += &format!(", symlinks {removed}: {}/{symlinks}", done.symlinks),
+        (false, _) => totals += &format!(", symlinks {removed}: {}", done.symlinks),
+    }
+    match inodes {
+        Some((inodes, elapsed)) if !elapsed.is_zero() =>
+            format!("{totals}, inodes {removed}: {inodes} ({:.0}/s)", inodes as f64 / elapsed.as_secs_f64()),
+        Some((inodes, _)) => format!("{totals}, inodes {removed}: {inodes}"),
+        None => totals,
+    }
+}
+
+/// The part of the --count-only and --dry-run totals about symlinks, if there were any.
+fn symlinks(done: Stats) -> String {
+    match done.symlinks {
+        0 => String::new(),
+        symlinks => format!(", {} symlinks", HumanCount(symlinks)),
+    }
+}
+
+/// Everything the delete loop (and finder) have to show goes through here so they don't have to care how it gets
+/// shown, or if it gets shown at all. These get called from both threads.
+trait Reporter: Send + Sync {
+    /// The finder has counted everything, so there's a known end now. The units are whatever --progress-by says.
+    fn found_all(&self, length: u64);
+    /// The finder is stuck until the delete loop makes room in the queue (or isn't anymore). Only ever called
+    /// before found_all().
+    fn queue_full(&self, full: bool);
+    /// `op` is "rm" or "rmdir".
+    fn deleting(&self, op: &str, path: &str);
+    /// Called after every item. `position` and `length` are in --progress-by units. `totals` and `eta` are already
+    /// formatted.
+    fn update(&self, position: u64, length: u64, totals: &str, eta: &str);
+    /// A full line (with its newline). Bytes instead of a str so that --raw-paths can come through here too.
+    fn error(&self, line: &[u8]);
+    /// Runs `f` with the progress out of the way. See [`Prompter`].
+    fn suspend(&self, f: &mut dyn FnMut());
+    fn finish(&self, totals: &str);
+    /// Like finish() but without anything left behind.
+    fn clear(&self);
+    /// Something only worth saying to someone watching the progress bars, so everything else drops it.
+    fn note(&self, _line: &str) {}
+    /// Tidies up if neither finish() nor clear() got called, because we're bailing out. See [`ProgressCleanup`].
+    fn abandon(&self) {}
+}
+
+/// However main() ends (an error returned part way through, or the finder's panic coming back through
+/// resume_unwind()), the progress display shouldn't be left half drawn. Only does anything if the normal path didn't
+/// get to finish() or clear().
+struct ProgressCleanup(Arc<dyn Reporter>);
+
+impl Drop for ProgressCleanup {
+    fn drop(&mut self) {
+        self.0.abandon();
+    }
+}
+
+/// The usual fancy progress bars.
+struct BarReporter {
+    multi:        MultiProgress,
+    progress:     ProgressBar,
+    path_spinner: ProgressBar,
+    totals:       ProgressBar,
+    theme:        Theme,
+    to:           Stream,
+    keep:         bool, // --no-clear
+}
+
+impl BarReporter {
+    /// `hz` is --refresh-rate. Without it the bars are drawn at indicatif's usual 20 times a second and the spinner
+    /// goes round at 10.
+    fn new(theme: Theme, to: Stream, keep: bool, hz: Option<u8>) -> BarReporter {
+        let progress = ProgressBar::new_spinner().with_style(theme.counting.clone());
+        let path_spinner = ProgressBar::new_spinner().with_style(theme.path.clone());
+        path_spinner.enable_steady_tick(Duration::from_secs_f32(1.0 / hz.map_or(10.0, f32::from)));
+        let totals = ProgressBar::new_spinner().with_style(theme.totals.clone());
+        let multi = MultiProgress::with_draw_target(match to {
+            Stream::Stderr => ProgressDrawTarget::stderr_with_hz(hz.unwrap_or(20)),
+            Stream::Stdout => ProgressDrawTarget::stdout_with_hz(hz.unwrap_or(20)),
+        });
+        multi.add(progress.clone());
+        multi.add(path_spinner.clone());
+        multi.add(totals.clone());
+        BarReporter { multi, progress, path_spinner, totals, theme, to, keep }
+    }
+}
+
+impl Reporter for BarReporter {
+    fn found_all(&self, length: u64) {
+        self.progress.set_length(length);
+        self.progress.set_style(self.theme.bar.clone());
+    }
+
+    fn queue_full(&self, full: bool) {
+        self.progress.set_style(if full { self.theme.queue_full.clone() } else { self.theme.counting.clone() });
+    }
+
+    fn deleting(&self, op: &str, path: &str) {
+        self.path_spinner.set_message(path.to_owned());
+        self.path_spinner.set_prefix(op.to_owned());
+    }
+
+    fn update(&self, position: u64, length: u64, totals: &str, eta: &str) {
+        self.totals.set_message(totals.to_owned());
+        self.progress.set_position(position);
+        self.progress.set_length(length);
+        self.progress.set_message(eta.to_owned());
+    }
+
+    fn error(&self, line: &[u8]) {
+        match std::str::from_utf8(line) {
+            // multi.println() is silently dropped when hidden and we don't want to lose errors. It also prints to
+            // wherever the bars are, and errors always go to stderr.
+            Ok(line) if !self.multi.is_hidden() && self.to == Stream::Stderr =>
+                _ = self.multi.println(line.trim_end_matches('\n')),
+            _ => self.multi.suspend(|| _ = std::io::stderr().write_all(line)),
+        }
+    }
+
+    fn suspend(&self, f: &mut dyn FnMut()) {
+        self.multi.suspend(f)
+    }
+
+    // Dropped by multi.println() when the bars are hidden (not a terminal), which is what we want here.
+    fn note(&self, line: &str) {
+        _ = self.multi.println(line);
+    }
+
+    fn finish(&self, totals: &str) {
+        self.totals.set_message(totals.to_owned());
+        self.totals.finish();
+        if self.keep {
+            self.path_spinner.finish();
+            self.progress.finish();
+        } else {
+            self.path_spinner.finish_and_clear();
+            self.progress.finish_and_clear();
+        }
+    }
+
+    fn clear(&self) {
+        self.totals.finish_and_clear();
+        self.path_spinner.finish_and_clear();
+        self.progress.finish_and_clear();
+    }
+
+    fn abandon(&self) {
+        if self.totals.is_finished() { return }
+        self.clear();
+        // indicatif doesn't hide it, but whatever we were in the middle of drawing might have.
+        if self.to.is_terminal() {
+            _ = match self.to { Stream::Stderr => console::Term::stderr(), Stream::Stdout => console::Term::stdout() }.show_cursor();
+        }
+    }
+}
+
+/// A status line every so often, for logs that only ever get appended to (CI, cron mail). No ANSI codes, ever.
+struct PlainReporter {
+    start:      Instant,
+    last:       std::sync::Mutex<Instant>,
+    found_all:  AtomicBool,
+    queue_full: AtomicBool,
+    to:         Stream,
+}
+
+impl PlainReporter {
+    const INTERVAL: Duration = Duration::from_secs(5);
+
+    fn new(to: Stream) -> PlainReporter {
+        let start = Instant::now();
+        PlainReporter { start, last: std::sync::Mutex::new(start), found_all: AtomicBool::new(false),
+                        queue_full: AtomicBool::new(false), to }
+    }
+
+    fn elapsed(&self) -> String {
+        hms(self.start.elapsed().as_secs())
+    }
+}
+
+impl Reporter for PlainReporter {
+    fn found_all(&self, _length: u64) {
+        self.found_all.store(true, Ordering::Relaxed);
+    }
+
+    fn queue_full(&self, full: bool) {
+        self.queue_full.store(full, Ordering::Relaxed);
+    }
+
+    fn deleting(&self, _op: &str, _path: &str) {}
+
+    fn update(&self, position: u64, length: u64, totals: &str, eta: &str) {
+        let mut last = self.last.lock().unwrap();
+        if last.elapsed() < Self::INTERVAL { return }
+        *last = Instant::now();
+        if self.found_all.load(Ordering::Relaxed) && length > 0 {
+            self.to.print(&format!("[{}] {totals}, {}% done, ETA {eta}\n", self.elapsed(), position * 100 / length));
+        } else {
+            let paused = if self.queue_full.load(Ordering::Relaxed) { " (paused, queue full)" } else { "" };
+            let eta = if eta.is_empty() { String::new() } else { format!(", {eta}") };
+            self.to.print(&format!("[{}] {totals}, still counting{paused}{eta}\n", self.elapsed()));
+        }
+    }
+
+    fn error(&self, line: &[u8]) {
+        _ = std::io::stderr().write_all(line);
+    }
+
+    fn suspend(&self, f: &mut dyn FnMut()) {
+        f()
+    }
+
+    fn finish(&self, totals: &str) {
+        self.to.print(&format!("{totals}, elapsed: {}\n", self.elapsed()));
+    }
+
+    fn clear(&self) {}
+}
+
+/// Just errors and the final totals.
+struct NoReporter(Stream);
+
+impl Reporter for NoReporter {
+    fn found_all(&self, _length: u64) {}
+    fn queue_full(&self, _full: bool) {}
+    fn deleting(&self, _op: &str, _path: &str) {}
+    fn update(&self, _position: u64, _length: u64, _totals: &str, _eta: &str) {}
+    fn error(&self, line: &[u8]) {
+        _ = std::io::stderr().write_all(line);
+    }
+    fn suspend(&self, f: &mut dyn FnMut()) {
+        f()
+    }
+    fn finish(&self, totals: &str) {
+        self.0.print(&format!("{totals}\n"));
+    }
+    fn clear(&self) {}
+}
+
+struct Theme {
+    counting:   ProgressStyle,
+    queue_full: ProgressStyle,
+    path:       ProgressStyle,
+    totals:     ProgressStyle,
+    bar:        ProgressStyle,
+}
+
+impl Theme {
+    // The plain versions have to stay readable without any ANSI codes at all. In particular the colored bar is
+    // drawn entirely with background colors on spaces, so it needs real characters when color is off.
+    //
+    // `progress` and `spinner` are from --progress-template and --spinner-template. Those get the plain bar
+    // characters since there's no telling what colors (if any) they use.
+    fn new(color: bool, progress: Option<&str>, spinner: Option<&str>) -> Result<Theme> {
+        let style = |template| ProgressStyle::with_template(template).unwrap();
+        let custom = |template, option| ProgressStyle::with_template(template).map_err(|e| anyhow!("Bad {option}: {e}"));
+        Ok(Theme {
+            // {msg} is empty unless there's a --provisional-eta.
+            counting:   style("Counting files{spinner:3}  {len}  {msg}").tick_strings(&[".", "..", "...", ""]),
+            // No spinner: nothing is moving. {len} still goes up as the delete loop makes room.
+            queue_full: style("Counting files (paused, queue full)  {len}  {msg}"),
+            // {prefix} is "rm" or "rmdir" and {msg} is the path.
+            path:       match spinner {
+                            Some(template) => custom(template, "--spinner-template")?,
+                            None if color  => style("{spinner} {prefix:<5.245} {msg:!.214}"),
+                            None           => style("{spinner} {prefix:<5} {msg:!}"),
+                        },
+            totals:     style("{msg}, elapsed: {elapsed_precise}"),
+            // {msg} is the ETA, see Eta.
+            bar:        match progress {
+                            Some(template) => custom(template, "--progress-template")?.progress_chars("=> "),
+                            None if color  => style("{elapsed_precise} {wide_bar:.on_cyan/on_17} {msg}")
+                                                  .progress_chars("   "),
+                            None           => style("{elapsed_precise} [{wide_bar}] {msg}").progress_chars("=> "),
+                        },
+        })
+    }
+}
+
+/// indicatif's {eta} only looks at the last handful of ticks, so when deleting goes in bursts (thousands of tiny
+/// files and then one huge one) it jumps all over the place. This smooths the rate with an exponentially weighted
+/// moving average where samples older than about `window` have mostly faded out.
+#[derive(Clone, Copy)]
+struct Eta {
+    window: Duration,
+    last:   Option<(Instant, u64)>,
+    rate:   Option<f64>, // --progress-by units per second
+}
+
+impl Eta {
+    // Don't sample more often than this: the rate from a couple items over a couple microseconds is just noise.
+    const MIN_SAMPLE: Duration = Duration::from_millis(100);
+
+    fn new(window: Duration) -> Eta {
+        Eta { window, last: None, rate: None }
+    }
+
+    fn update(&mut self, now: Instant, done: u64) {
+        let Some((then, then_done)) = self.last else { self.last = Some((now, done)); return };
+        let dt = now.saturating_duration_since(then);
+        if dt < Self::MIN_SAMPLE { return }
+        let sample = done.saturating_sub(then_done) as f64 / dt.as_secs_f64();
+        let alpha = if self.window.is_zero() { 1.0 } else { 1.0 - (-dt.as_secs_f64() / self.window.as_secs_f64()).exp() };
+        self.rate = Some(self.rate.map_or(sample, |rate| rate + alpha * (sample - rate)));
+        self.last = Some((now, done));
+    }
+
+    /// Formatted like {eta_precise}.
+    fn show(&self, remaining: u64) -> String {
+        match self.rate {
+            Some(rate) if rate > 0.0 => {
+                hms((remaining as f64 / rate).round() as u64)
+            },
+            _ => "--:--:--".to_string(),
+        }
+    }
+}
+
+/// `dd` style: `kill -USR1` asks for a summary line without stopping anything. The handler only sets a flag (that's
+/// about all a signal handler is allowed to do), and a thread of its own watches for it, so the line comes out even
+/// when nothing is being deleted: while it's still counting, or waiting on an --interactive answer. SA_RESTART keeps
+/// the signal from turning into EINTR errors in the middle of deleting.
+struct SummaryOnSignal {
+    status: Arc<std::sync::Mutex<(Stats, Eta)>>, // What the delete loop has got to.
+}
+
+impl SummaryOnSignal {
+    #[cfg(unix)]
+    const POLL: Duration = Duration::from_millis(100);
+
+    fn new(reporter: &Arc<dyn Reporter>, totals: Totals, progress_by: ProgressBy, eta: Eta) -> Result<SummaryOnSignal> {
+        let status = Arc::new(std::sync::Mutex::new((Stats::default(), eta)));
+        #[cfg(unix)] {
+            extern "C" fn handler(_signal: libc::c_int) {
+                SUMMARY_WANTED.store(true, Ordering::Relaxed);
+            }
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            if unsafe { libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()) } != 0 {
+                Err(os_error("Couldn't set up the SIGUSR1 handler for --summary-on-signal", std::io::Error::last_os_error()))?
+            }
+            let (status, reporter) = (status.clone(), reporter.clone());
+            thread::spawn(move || loop {
+                sleep(Self::POLL);
+                if !SUMMARY_WANTED.swap(false, Ordering::Relaxed) { continue }
+                let (done, eta) = *status.lock().unwrap();
+                // Through error() so it ends up where they do: on stderr, above the bars, and not dropped when they're
+                // hidden.
+                reporter.error(Self::line(totals, progress_by, done, eta).as_bytes());
+            });
+        }
+        #[cfg(not(unix))] { _ = (reporter, totals, progress_by); }
+        Ok(SummaryOnSignal { status })
+    }
+
+    #[cfg(unix)]
+    fn line(totals: Totals, progress_by: ProgressBy, done: Stats, eta: Eta) -> String {
+        let elapsed = totals.start.elapsed();
+        let per_sec = |n: u64| if elapsed.is_zero() { 0 } else { (n as f64 / elapsed.as_secs_f64()) as u64 };
+        let eta = match TOTAL.done.load(Ordering::Relaxed) {
+            true => format!("ETA {}", eta.show(progress_by.of(TOTAL.stats()).saturating_sub(progress_by.of(done)))),
+            false => "still counting".to_owned(),
+        };
+        format!("[{}] {}, {}/s, {} items/s, {eta}\n", hms(elapsed.as_secs()), totals.message(done),
+                Size(per_sec(done.bytes)), per_sec(done.unlinked() + done.dirs))
+    }
+
+    fn update(&self, done: Stats, eta: Eta) {
+        *self.status.lock().unwrap() = (done, eta);
+    }
+}
+
+/// Like indicatif's {elapsed_precise}.
+fn hms(secs: u64) -> String {
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+struct AtomicStats {
+    bytes: AtomicU64,
+    files: AtomicU64,
+    dirs:  AtomicU64,
+    symlinks: AtomicU64,
+    done:  AtomicBool,
+}
+
+impl AtomicStats {
+    fn stats(&self) -> Stats {
+        Stats { bytes: self.bytes.load(Ordering::Relaxed),
+                files: self.files.load(Ordering::Relaxed),
+                dirs:  self.dirs.load(Ordering::Relaxed),
+                symlinks: self.symlinks.load(Ordering::Relaxed) }
+    }
+}
+
+/// Symlinks are counted on their own, and not in `bytes` (removing one frees next to nothing, and its "size" is
+/// just the length of the path it holds).
+#[derive(Default, Clone, Copy)]
+struct Stats {
+    bytes: u64,
+    files: u64,
+    dirs:  u64,
+    symlinks: u64,
+}
+
+impl Stats {
+    /// Everything that isn't a directory, which is what --limit and --progress-by=count go by.
+    fn unlinked(&self) -> u64 {
+        self.files + self.symlinks
+    }
+}
+
+impl std::ops::Sub for Stats {
+    type Output = Stats;
+    fn sub(self, rhs: Stats) -> Stats {
+        Stats { bytes: self.bytes - rhs.bytes,
+                files: self.files - rhs.files,
+                dirs:  self.dirs  - rhs.dirs,
+                symlinks: self.symlinks - rhs.symlinks }
+    }
+}
+
+impl std::ops::AddAssign for Stats {
+    fn add_assign(&mut self, rhs: Stats) {
+        self.bytes += rhs.bytes;
+        self.files += rhs.files;
+        self.dirs  += rhs.dirs;
+        self.symlinks += rhs.symlinks;
+    }
+}
+
+
+enum ToDelete {
+    File { size: u64, path: PathBuf, symlink: bool },
+    Dir(PathBuf),
+    Err { path: PathBuf, err: Error },
+    /// Only with `FindOptions::dir_events`: brackets what's found in a directory, for something that wants to
+    /// show progress by directory. `entries` is how many are in it, if that was known up front (it was sorted).
+    /// `bytes` is how much was found in it, all the way down. The `Dir` for deleting the directory itself, if
+    /// there is one, comes after the `DirDone`.
+    #[allow(dead_code)] // The binary skips these, see `dir_events`.
+    DirStart { path: PathBuf, entries: Option<usize> },
+    #[allow(dead_code)]
+    DirDone { path: PathBuf, bytes: u64 },
+}
+
+impl ToDelete {
+    fn path(self) -> PathBuf {
+        match self {
+            ToDelete::File { path, .. } | ToDelete::Dir(path) | ToDelete::Err { path, .. } |
+            ToDelete::DirStart { path, .. } | ToDelete::DirDone { path, .. } => path
+        }
+    }
+}
+
+struct Find<'a> {
+    tx: &'a SyncSender<ToDelete>,
+    interactive: Interactive,
+    options: FindOptions,
+    reporter: Arc<dyn Reporter>,
+    queue_full: bool,
+    base: PathBuf, // The current <path>'s directory.
+    held: Option<Vec<ToDelete>>, // For --all-or-nothing and --free, everything found so far.
+}
+
+#[derive(Clone)]
+struct FindOptions {
+    sort: Sort,
+    sort_threshold: usize,
+    seed: u64, // For Sort::Random
+    only_broken_symlinks: bool,
+    files_only: bool,
+    empty_dirs_only: bool,
+    respect_xattr: Option<String>,
+    skip_hidden: bool,
+    owned_by: Option<u32>,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+    open_files: Option<Arc<std::collections::HashSet<(u64, u64)>>>, // (device, inode)
+    protect_from: Option<Arc<Protected>>,
+    regex: Option<(regex::bytes::Regex, RegexScope)>,
+    include: Option<Globs>,
+    exclude: Option<Globs>,
+    check_mounts: bool, // Compare each directory's device with its parent's. See mounts_inside().
+    dereference: Option<bool>, // None warns
+    rmdir: bool,
+    dir_events: bool, // Send DirStart and DirDone. Nothing in here shows them (yet), so they're off.
+    apparent_size: bool,
+    trim_to_depth: Option<usize>,
+}
+
+impl FindOptions {
+    fn new(args: &Args, protect_from: Option<Arc<Protected>>) -> Result<FindOptions> {
+        // Bytes rather than str so that names that aren't UTF-8 can still match.
+        let regex = args.flag_regex.as_deref().map(|re| regex::bytes::Regex::new(re).map_err(|e| anyhow!("--regex: {e}")))
+                                              .transpose()?;
+        Ok(FindOptions {
+            sort:                 if args.flag_deterministic { Sort::Name } else { args.flag_sort },
+            sort_threshold:       if args.flag_deterministic { usize::MAX } else { args.flag_sort_threshold },
+            seed:                 args.flag_seed.unwrap_or_else(|| {
+                                      let now = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+                                      now.as_nanos() as u64 ^ (std::process::id() as u64) << 32
+                                  }),
+            only_broken_symlinks: args.flag_only_broken_symlinks,
+            files_only:           args.flag_files_only || args.flag_free.is_some(),
+            empty_dirs_only:      args.flag_empty_dirs_only,
+            respect_xattr:        args.flag_respect_xattr.clone(),
+            skip_hidden:          args.flag_skip_hidden,
+            owned_by:             args.flag_owned_by.as_deref().map(uid_for).transpose()?,
+            newer_than:           args.flag_newer_than_file.as_deref().map(|file| mtime_of(file, "--newer-than-file"))
+                                                                     .transpose()?,
+            older_than:           args.flag_older_than_file.as_deref().map(|file| mtime_of(file, "--older-than-file"))
+                                                                     .transpose()?,
+            open_files:           args.flag_skip_open.then(open_files).transpose()?.map(Arc::new),
+            protect_from,
+            regex:                regex.map(|regex| (regex, args.flag_regex_scope)),
+            include:              args.flag_include.as_deref().map(|globs| Globs::new(globs, "--include")).transpose()?,
+            exclude:              args.flag_exclude.as_deref().map(|globs| Globs::new(globs, "--exclude")).transpose()?,
+            check_mounts:         cfg!(unix) && !args.flag_cross_mounts && mounts_inside(&args.arg_path) != Some(false),
+            dereference:          match (args.flag_dereference, args.flag_no_dereference) {
+                                      (true, _) => Some(true),
+                                      (_, true) => Some(false),
+                                      _         => None,
+                                  },
+            rmdir:                args.flag_dir,
+            dir_events:           false,
+            apparent_size:        args.flag_apparent_size == YesNo::Yes,
+            trim_to_depth:        args.flag_trim_to_depth,
+        })
+    }
+
+    // Filesystems that don't do xattrs at all just return an error here, which means nothing is protected.
+    fn protected(&self, path: &Path) -> bool {
+        #[cfg(unix)]
+        if let Some(ref name) = self.respect_xattr {
+            return matches!(via_short_path(path, |path| xattr::get(path, name)), Ok(Some(_)));
+        }
+        _ = path;
+        false
+    }
+
+    // Only for things found inside a directory: a <path> that was asked for by name gets deleted even if it's hidden.
+    fn hidden(&self, path: &Path) -> bool {
+        self.skip_hidden && path.file_name().is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+    }
+
+    fn want_file(&self, path: &Path, meta: &Metadata) -> bool {
+        !self.empty_dirs_only && (!self.only_broken_symlinks || is_broken_symlink(path, meta)) && self.owned(meta) &&
+            self.modified_in_range(meta) && !self.open(meta)
+    }
+
+    /// `base` is the directory the <path> this came from is in, for --regex-scope=relative.
+    fn matches(&self, path: &Path, base: &Path) -> bool {
+        if self.include.as_ref().is_some_and(|include| !include.matches(path, base)) { return false }
+        let Some((ref regex, scope)) = self.regex else { return true };
+        match scope {
+            RegexScope::Name => path.file_name().is_some_and(|name| regex.is_match(&raw_path_bytes(Path::new(name)))),
+            RegexScope::Relative => regex.is_match(&raw_path_bytes(path.strip_prefix(base).unwrap_or(path))),
+            RegexScope::Absolute => std::path::absolute(path).is_ok_and(|path| regex.is_match(&raw_path_bytes(&path))),
+        }
+    }
+
+    /// For --trim-to-depth: whether `path` is in the top levels that stay. `base` is the directory the <path> is in,
+    /// so the <path> itself is 1 level down from it, and always stays.
+    fn too_shallow(&self, path: &Path, base: &Path) -> bool {
+        self.trim_to_depth.is_some_and(|depth| path.strip_prefix(base).unwrap_or(path).components().count() <= depth + 1)
+    }
+
+    fn owned(&self, meta: &Metadata) -> bool {
+        #[cfg(unix)]
+        if let Some(uid) = self.owned_by {
+            use std::os::unix::fs::MetadataExt;
+            return meta.uid() == uid;
+        }
+        _ = meta;
+        true
+    }
+
+    /// Strictly newer or older, like `find -newer`, so the reference file itself is neither.
+    fn modified_in_range(&self, meta: &Metadata) -> bool {
+        if self.newer_than.is_none() && self.older_than.is_none() { return true }
+        let Ok(mtime) = meta.modified() else { return false };
+        self.newer_than.is_none_or(|newer_than| mtime > newer_than) &&
+            self.older_than.is_none_or(|older_than| mtime < older_than)
+    }
+
+    fn open(&self, meta: &Metadata) -> bool {
+        #[cfg(unix)]
+        if let Some(ref open_files) = self.open_files {
+            use std::os::unix::fs::MetadataExt;
+            return open_files.contains(&(meta.dev(), meta.ino()));
+        }
+        _ = meta;
+        false
+    }
+
+    fn want_dirs(&self) -> bool {
+        !self.files_only && !self.only_broken_symlinks
+    }
+}
+
+type FindResult<T> = std::result::Result<T, (PathBuf, anyhow::Error)>;
+type Dirent = (PathBuf, Option<FileType>);
+
+/// What the finder gets when the deleting side has stopped listening. That only happens when it stopped early (on
+/// an error, or a limit), and it reports why itself, so this is never the interesting error and main() drops it.
+#[derive(Debug)]
+struct ChannelClosed;
+
+impl std::fmt::Display for ChannelClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "finder tx channel was closed")
+    }
+}
+
+impl std::error::Error for ChannelClosed {}
+
+fn channel_closed(e: SendError<ToDelete>) -> (PathBuf, anyhow::Error) {
+    (e.0.path(), anyhow::Error::new(ChannelClosed))
+}
+
+/// For what the finder thread returns. The path goes in front, except on a ChannelClosed, which has to stay
+/// recognizable.
+fn found_error((path, err): (PathBuf, anyhow::Error)) -> anyhow::Error {
+    if err.is::<ChannelClosed>() { err } else { anyhow!("{path:?};{err}") }
+}
+
+impl<'a> Find<'a> {
+    fn new(tx: &'a SyncSender<ToDelete>, interactive: Interactive, options: FindOptions, reporter: Arc<dyn Reporter>)
+           -> Find<'a> {
+        Find { tx, interactive, options, reporter, queue_full: false, base: PathBuf::new(), held: None }
+    }
+
+    /// For --all-or-nothing and --free: hang on to everything found instead of sending it, until [`Find::release`].
+    fn hold(&mut self) {
+        self.held = Some(vec![]);
+    }
+
+    /// For --free: cuts what was held down to the files that add up to `target` bytes, biggest (or oldest) first, so
+    /// they're also deleted in that order. Symlinks go, since deleting them frees next to nothing. The errors stay so
+    /// they still get reported. TOTAL becomes what's left, so the progress is out of that.
+    fn choose_to_free(&mut self, target: u64, order: FreeOrder) {
+        let Some(held) = self.held.take() else { return };
+        let (mut files, mut chosen) = (vec![], vec![]);
+        for item in held {
+            match item {
+                ToDelete::File { path, size, symlink: false } => files.push((size, path)),
+                ToDelete::File { .. } => {},
+                item => chosen.push(item),
+            }
+        }
+        match order {
+            FreeOrder::Biggest => files.sort_by(|(a, a_path), (b, b_path)| b.cmp(a).then_with(|| a_path.cmp(b_path))),
+            // Whatever can't be stat()ed now is going to fail to delete anyway, so it might as well be last.
+            FreeOrder::Oldest => files.sort_by_cached_key(|(_, path)| {
+                (symlink_metadata(path).and_then(|m| m.modified()).unwrap_or(SystemTime::now()),
+                 path.clone())
+            }),
+        }
+        let mut total = Stats::default();
+        for (size, path) in files {
+            if total.bytes >= target { break }
+            total += Stats { bytes: size, files: 1, ..Stats::default() };
+            chosen.push(ToDelete::File { path, size, symlink: false });
+        }
+        TOTAL.bytes.store(total.bytes, Ordering::Relaxed);
+        TOTAL.files.store(total.files, Ordering::Relaxed);
+        TOTAL.symlinks.store(0, Ordering::Relaxed);
+        self.held = Some(chosen);
+    }
+
+    /// Sends everything that was held. With `all_or_nothing`, only if all of it was found without an error (and
+    /// `found` says the finding itself went ok). Otherwise only the errors go, so they get reported, and nothing gets
+    /// deleted.
+    fn release(&mut self, found: Result<()>, all_or_nothing: bool) -> Result<()> {
+        let (held, found) = self.take_held(found, all_or_nothing);
+        // Not through send(): counting is over by now, and a full queue is just the deleting catching up.
+        for item in held {
+            self.tx.send(item).map_err(channel_closed).map_err(found_error)?;
+        }
+        found
+    }
+
+    /// What [`Find::release`] would send, and what it would return, for when nothing is on the other end of the
+    /// queue yet (--deterministic).
+    fn take_held(&mut self, found: Result<()>, all_or_nothing: bool) -> (Vec<ToDelete>, Result<()>) {
+        let Some(held) = self.held.take() else { return (vec![], found) };
+        let errors = match all_or_nothing {
+            true  => held.iter().filter(|item| matches!(item, ToDelete::Err { .. })).count() + found.is_err() as usize,
+            false => 0,
+        };
+        let held = held.into_iter().filter(|item| errors == 0 || matches!(item, ToDelete::Err { .. })).collect();
+        let found = found.and_then(|()| match errors {
+            0 => Ok(()),
+            _ => Err(anyhow!("--all-or-nothing: Couldn't find everything without errors, so nothing was deleted")),
+        });
+        (held, found)
+    }
+
+    /// Blocks when the queue is full, which is fine (it's what keeps memory bounded), but it'd look like counting
+    /// had hung, so say so. Once the queue has filled up it tends to stay that way, so the "not full" only goes out
+    /// when something fits without waiting, otherwise it'd flip back and forth on every item.
+    fn send(&mut self, item: ToDelete) -> FindResult<()> {
+        if let Some(ref mut held) = self.held {
+            held.push(item);
+            return Ok(())
+        }
+        match self.tx.try_send(item) {
+            Ok(()) => {
+                if self.queue_full { self.reporter.queue_full(false) }
+                self.queue_full = false;
+                Ok(())
+            },
+            Err(TrySendError::Full(item)) => {
+                if !self.queue_full { self.reporter.queue_full(true) }
+                self.queue_full = true;
+                self.tx.send(item).map_err(channel_closed)
+            },
+            Err(TrySendError::Disconnected(item)) => Err(channel_closed(SendError(item))),
+        }
+    }
+
+    /// For the top level <path>s. Errors get reported like any other so that one bad argument doesn't stop the
+    /// others from being deleted. The only error that comes back is the channel closing.
+    fn find_arg(&mut self, path: PathBuf) -> FindResult<()> {
+        self.base = path.parent().unwrap_or(Path::new("")).to_owned();
+        let res = if self.options.rmdir && path.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+            match read_dir(&path).map(|mut d| d.next().is_none()) {
+                Ok(true)  => self.find(path, None, None),
+                Ok(false) => Err((path, anyhow!("Directory not empty (and --dir doesn't recurse)"))),
+                Err(e)    => Err((path, os_error("read_dir", e))),
+            }
+        } else if !self.options.rmdir && self.options.dereference != Some(false) && is_symlink_to_dir(&path) {
+            self.find_symlinked_dir(path)
+        } else {
+            self.find(path, None, None)
+        };
+        match res {
+            Err((path, err)) => self.send(ToDelete::Err { path, err }),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// For --manifest: exactly `path`, without going into it. A directory gets deleted if it's empty by the time
+    /// it's reached (presumably because the entries before it emptied it). The filters and --interactive still apply.
+    fn find_listed(&mut self, path: PathBuf, size: Option<u64>) -> FindResult<()> {
+        self.base = path.parent().unwrap_or(Path::new("")).to_owned();
+        let res = match path.symlink_metadata() {
+            Err(e) => Err((path, os_error("Listed in the manifest, but", e))),
+            Ok(meta) if self.options.protect_from.as_ref().is_some_and(|protect| protect.contains(&meta)) => Ok(()),
+            Ok(meta) if meta.is_dir() => {
+                if self.options.want_dirs() && self.options.matches(&path, &self.base) &&
+                    self.interactive.ask(&path, &meta, false)? == Directive::Delete {
+                    TOTAL.dirs.fetch_add(1, Ordering::Relaxed);
+                    self.send(ToDelete::Dir(path))?;
+                }
+                Ok(())
+            },
+            Ok(meta) if size.is_some_and(|size| size != meta.len()) =>
+                Err((path, anyhow!("Is {} bytes but the manifest says {}, so not deleting it", meta.len(), size.unwrap()))),
+            Ok(_) => self.find(path, None, None).map(|_| ()),
+        };
+        match res {
+            Err((path, err)) => self.send(ToDelete::Err { path, err }),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// A <path> that's a symlink to a directory. People who wanted the directory cleared out tend to be surprised
+    /// when only the link goes, so either say so or (with --dereference) go through the link first.
+    fn find_symlinked_dir(&mut self, path: PathBuf) -> FindResult<bool> {
+        if self.options.dereference.is_none() {
+            self.reporter.error(format!("{path:?}: Is a symlink to a directory, so only the link is being deleted \
+                                         (not what's in the directory). Use `--dereference` to delete both, or \
+                                         `--no-dereference` to not be told\n").as_bytes());
+            return self.find(path, None, None);
+        }
+        let meta = path.metadata().map_err(|e| (path.clone(), os_error("stat", e)))?;
+        if self.options.protect_from.as_ref().is_some_and(|protect| protect.contains(&meta)) {
+            return self.find(path, None, None) // Just the link, then.
+        }
+        if self.find_contents(&path, Some(&meta), device(&meta))? { return Ok(true) } // The link still leads somewhere.
+        self.find(path, None, None)
+    }
+
+    // `file_type` comes from the readdir (when the OS gives it to us there). If it says we've got a directory
+    // and nothing needs the rest of the stat info then we can skip the stat entirely. Files always need it for
+    // their size.
+    //
+    // `parent_dev` is the device of the directory this is in, None for the <path>s (the Validator already looked at
+    // those).
+    fn find(&mut self, path: PathBuf, file_type: Option<FileType>, parent_dev: Option<u64>) -> FindResult<bool> {
+        let meta = match file_type {
+            Some(ft) if ft.is_dir() && !self.need_dir_metadata() => None,
+            _ => Some(symlink_metadata(&path).map_err(|e| (path.clone(), os_error("stat", e)))?),
+        };
+        let is_dir = meta.as_ref().is_none_or(|m| m.is_dir());
+
+        // Skipping (as opposed to filtering) happens before asking since we're never going to delete it anyway.
+        if self.options.protected(&path) { return Ok(true) }
+        if self.options.protect_from.as_ref().zip(meta.as_ref()).is_some_and(|(protect, meta)| protect.contains(meta)) {
+            return Ok(true)
+        }
+        if self.options.exclude.as_ref().is_some_and(|exclude| exclude.matches(&path, &self.base)) { return Ok(true) }
+
+        // Something mounted inside what's being deleted almost certainly wasn't meant to be emptied along with it.
+        let dev = meta.as_ref().and_then(device);
+        if is_dir && self.options.check_mounts && parent_dev.is_some() && dev.is_some() && dev != parent_dev {
+            return Err((path, anyhow!("Another filesystem is mounted here, not going in. You can override with `--cross-mounts`")));
+        }
+
+        if let Some(ref meta) = meta {
+            if !is_dir && !self.options.want_file(&path, meta) { return Ok(true) }
+            if !is_dir && !self.options.matches(&path, &self.base) { return Ok(true) }
+            if !is_dir && self.options.too_shallow(&path, &self.base) { return Ok(true) }
+            if self.interactive.ask(&path, meta, true)? == Directive::Skip { return Ok(true) }
+        }
+
+        if is_dir {
+            let skipped_any = self.find_contents(&path, meta.as_ref(), dev)?;
+            if skipped_any { return Ok(true) } // Directory is not empty so don't bother asking or trying to delete it.
+            if !self.options.want_dirs() || !self.options.matches(&path, &self.base) { return Ok(true) }
+            if self.options.too_shallow(&path, &self.base) { return Ok(true) }
+            if let Some(ref meta) = meta {
+                // Someone else's directory stays, but whatever of ours was in it is already gone.
+                if !self.options.owned(meta) { return Ok(true) }
+                if self.interactive.ask(&path, meta, true)? == Directive::Skip { return Ok(true) }
+            }
+
+            TOTAL.dirs.fetch_add(1, Ordering::Relaxed);
+            self.send(ToDelete::Dir(path))?;
+        } else { // symlinks are more or less just files
+            let symlink = meta.as_ref().is_some_and(|m| m.file_type().is_symlink());
+            let bytes = if symlink { 0 } else { meta.map_or(0, |m| size_of(&m, self.options.apparent_size)) };
+            self.send(ToDelete::File { path, size: bytes, symlink })?;
+            if symlink {
+                TOTAL.symlinks.fetch_add(1, Ordering::Relaxed);
+            } else {
+                TOTAL.files.fetch_add(1, Ordering::Relaxed);
+                TOTAL.bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Everything in `dir` (which is on device `dev`). True if anything in it was skipped, so it won't be empty.
+    fn find_contents(&mut self, dir: &Path, meta: Option<&Metadata>, dev: Option<u64>) -> FindResult<bool> {
+        let mut skipped_any = false;
+        let dirents = Self::readdir_sorted(dir, meta, self.options.sort, self.options.sort_threshold, self.options.seed)?;
+        let bytes_before = TOTAL.bytes.load(Ordering::Relaxed); // Only the finder adds to it.
+        if self.options.dir_events {
+            let entries = match dirents.size_hint() { (low, Some(high)) if low == high => Some(low), _ => None };
+            self.send(ToDelete::DirStart { path: dir.to_owned(), entries })?;
+        }
+        for dirent in dirents {
+            if dirent.as_ref().is_ok_and(|(dirent, _)| self.options.hidden(dirent)) {
+                skipped_any = true;
+                continue
+            }
+            // Report errors and carry on with the rest of the directory. Whatever failed is still there, so
+            // count it as skipped, otherwise we'd try to delete this directory and get a second, less useful,
+            // "Directory not empty" error.
+            match dirent.and_then(|(dirent, file_type)| self.find(dirent, file_type, dev)) {
+                Err((path, err)) => { self.send(ToDelete::Err { path, err })?;
+                                      skipped_any = true },
+                Ok(true) => skipped_any = true,
+                Ok(false) => {},
+            }
+        }
+        if self.options.dir_events {
+            let bytes = TOTAL.bytes.load(Ordering::Relaxed) - bytes_before;
+            self.send(ToDelete::DirDone { path: dir.to_owned(), bytes })?;
+        }
+        Ok(skipped_any)
+    }
+
+    fn need_dir_metadata(&self) -> bool {
+        self.interactive.enable || self.options.owned_by.is_some() || self.options.protect_from.is_some() ||
+            self.options.check_mounts ||
+            (self.options.sort != Sort::None && self.options.sort_threshold > 0)
+    }
+
+    fn readdir_sorted<'p>(path: &'p Path, meta: Option<&Metadata>, sort: Sort, threshold: usize, seed: u64) -> FindResult<Box<dyn Iterator<Item=FindResult<Dirent>> + 'p>> {
+        let ctx = |e| (path.to_owned(), os_error("read_dir", e));
+
+        // Sort the entries so the user can tell how far we've gotten even if the progress bar isn't
+        // going. However, don't waste time and memory sorting directories that are massive. If you've ever
+        // done "ls" in a directory and had it take multiple seconds before printing anything you know what
+        // we're trying to avoid here. The cutoff point is somewhat arbitrary. We want it high enough that
+        // most things get sorted but low enough that the time and memory spent reading the entries and
+        // sorting is negligible. It defaults to 5000 but can be changed with --sort-threshold.
+        //
+        // If the we're unix we can get the number of directory entries quickly from the nlink stat field. If
+        // we're not, then pretend it's right at the default cutoff so we only sort if the user raised it.
+        #[cfg(unix)] use std::os::unix::fs::MetadataExt;
+        #[cfg(unix)] let nlink = meta.map_or(usize::MAX, |m| m.nlink() as usize); // No metadata means we're not sorting.
+        #[cfg(not(unix))] let nlink = 5000_usize;
+        if nlink < threshold && sort != Sort::None {
+            let mut dirents = Vec::with_capacity(nlink); // oversized by 2 (., ..) but who cares.
+
+            for f in list_dir(path).map_err(ctx)? {
+                let (name, file_type) = f.map_err(ctx)?;
+                dirents.push((path.join(name), file_type));
+            }
+            // Size and mtime have to stat everything up front. If the stat fails, just sort it wherever and let
+            // find() report the error when it gets there. Like `ls -S` and `ls -t`, biggest and newest go first.
+            match sort {
+                Sort::Name|Sort::None => dirents.sort_by(|(a, _), (b, _)| a.cmp(b)),
+                Sort::Size => dirents.sort_by_cached_key(|(p, _)| std::cmp::Reverse(symlink_metadata(p).map(|m| m.len()).unwrap_or(0))),
+                Sort::Mtime => dirents.sort_by_cached_key(|(p, _)| std::cmp::Reverse(symlink_metadata(p).and_then(|m| m.modified()).ok())),
+                Sort::Random => {
+                    // Sorted first so the same seed gives the same order whatever order readdir gave.
+                    dirents.sort_by(|(a, _), (b, _)| a.cmp(b));
+                    shuffle(&mut dirents, seed ^ fnv1a(&raw_path_bytes(path)));
+                },
+            }
+
+            Ok(Box::new(dirents.into_iter().map(Ok)))
+        } else {
+            Ok(Box::new(list_dir(path).map_err(ctx)?
+                                       .map(|res_de| res_de.map(|(name, file_type)| (path.join(name), file_type))
+                                                           .map_err(|e| (path.to_owned(), anyhow!(e))))))
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Response {
+    Delete,
+    Skip,
+    DeleteFromNowOn,
+    Quit,
+    DeleteThisDir(PathBuf),
+    SkipThisDir(PathBuf),
+    DeleteThisKind(Kind),
+    SkipThisKind(Kind),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Directive {
+    Delete,
+    Skip,
+}
+
+/// What the "t" and "k" answers apply to the rest of.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Special, // fifos, sockets, devices, ...
+}
+
+impl Kind {
+    fn of(meta: &Metadata) -> Kind {
+        match meta.file_type() {
+            ft if ft.is_dir()     => Kind::Dir,
+            ft if ft.is_file()    => Kind::File,
+            ft if ft.is_symlink() => Kind::Symlink,
+            _                     => Kind::Special,
+        }
+    }
+
+    fn plural(self) -> &'static str {
+        match self {
+            Kind::File    => "files",
+            Kind::Dir     => "directories",
+            Kind::Symlink => "symbolic links",
+            Kind::Special => "special files (fifos, sockets, devices)",
+        }
+    }
+}
+
+pub struct Interactive {
+    enable: bool,
+    state: Option<Response>,
+    by_kind: std::collections::HashMap<Kind, Directive>, // From "t" and "k", which last for the whole run.
+    ask_ctx: AskerContext,
+    ask_user: UserAsker,
+}
+
+type Asker<'a> = &'a (dyn Fn() -> Result<Response> + 'a);
+type AskerContext = Box<dyn Fn(Asker<'_>) -> Result<Response>>;
+type UserAsker = Box<dyn Fn(&Path, &Metadata, bool) -> Result<Response>>;
+
+impl Interactive {
+    pub fn new<F>(enable: bool, assume_yes: bool, timeout: Option<Duration>, to: Stream, ask_ctx: F) -> Interactive
+    where F: Fn(Asker) -> Result<Response> + 'static,
+    {
+        Self::with_asker(enable, ask_ctx,
+                         move |path, meta, traverse| Self::ask_user(path, meta, traverse, assume_yes, timeout, to))
+    }
+
+    /// Like [`new`](Self::new) but with something other than the terminal answering the questions. This is
+    /// the seam the unit tests use to script responses.
+    pub fn with_asker<F, A>(enable: bool, ask_ctx: F, ask_user: A) -> Interactive
+    where F: Fn(Asker) -> Result<Response> + 'static,
+          A: Fn(&Path, &Metadata, bool) -> Result<Response> + 'static,
+    {
+        Interactive {
+            enable,
+            ask_ctx: Box::new(ask_ctx),
+            ask_user: Box::new(ask_user),
+            state: None,
+            by_kind: Default::default(),
+        }
+    }
+
+    /// Called between args to reset the state of DeleteThisDir or SkipThisDir back to None. The only other
+    /// states that it could be are DeleteFromNowOn and Quit. Neither of these will be reset.
+    ///
+    /// The reasoning is that the args are separate and it might be confusing for "everything in this dir" to
+    /// also mean some argument down the line that we haven't processed yet.
+    pub fn reset_state(&mut self) {
+        match self.state {
+            Some(Response::Quit) |
+            Some(Response::DeleteFromNowOn) => {},
+            _ => { self.state = None },
+        }
+    }
+
+    /// What's already been answered wins in this order: "a" and "q" (everything), then "d" and "s" (a directory),
+    /// then "t" and "k" (a kind of file). So a "d" still deletes the symlinks in that directory after a "k" for
+    /// symlinks, and a "t" for files doesn't undo a "q".
+    pub fn ask(&mut self, path: &Path, meta: &Metadata, traverse: bool) -> FindResult<Directive> {
+        if self.enable {
+            match &self.state {
+                Some(Response::DeleteFromNowOn)                                    => { return Ok(Directive::Delete) },
+                Some(Response::DeleteThisDir(ref skip)) if is_same_dir(skip, path) => { return Ok(Directive::Delete) },
+                Some(Response::SkipThisDir(ref skip))   if is_same_dir(skip, path) => { return Ok(Directive::Skip) },
+                Some(Response::Quit)                                               => { return Ok(Directive::Skip) },
+                _ if self.by_kind.contains_key(&Kind::of(meta))                    => { return Ok(self.by_kind[&Kind::of(meta)]) },
+                _ => {
+                    match (self.ask_ctx)(&|| (self.ask_user)(path, meta, traverse)).map_err(|e| (path.to_owned(), anyhow!(e)))? {
+                        Response::Delete             => { return Ok(Directive::Delete) },
+                        Response::Skip               => { return Ok(Directive::Skip) },
+                        d@Response::DeleteFromNowOn |
+                        d@Response::DeleteThisDir(_) =>  { self.state = Some(d); return Ok(Directive::Delete) },
+                        d@Response::Quit            |
+                        d@Response::SkipThisDir(_)   =>  { self.state = Some(d); return Ok(Directive::Skip) },
+                        Response::DeleteThisKind(kind) => { self.by_kind.insert(kind, Directive::Delete); return Ok(Directive::Delete) },
+                        Response::SkipThisKind(kind)   => { self.by_kind.insert(kind, Directive::Skip); return Ok(Directive::Skip) },
+                    }
+                }
+            }
+        }
+        Ok(Directive::Delete)
+    }
+
+
+  fn ask_user(path: &Path, meta: &Metadata, traverse: bool, assume_yes: bool, timeout: Option<Duration>, to: Stream)
+              -> Result<Response> {
+    let (path, prompt) = match (meta.is_dir(), traverse) {
+        (false, _) => {
+            #[cfg(unix)]
+            use std::os::unix::fs::FileTypeExt;
+            let ft = meta.file_type();
+            (path,
+             if ft.is_file() && meta.len() == 0 {
+                 format!("remove empty file {path:?}")
+             } else if ft.is_file() {
+                 format!("remove file {path:?} [{}]", Size(meta.len()))
+             } else if ft.is_symlink() && is_broken_symlink(path, meta) {
+                 format!("remove broken symbolic link {path:?} -> {:?}", std::fs::read_link(path).unwrap_or_default())
+             } else if ft.is_symlink() {
+                 format!("remove symbolic link {path:?}")
+             } else {
+                 #[cfg(unix)]
+                 if ft.is_fifo() {
+                     format!("remove fifo {path:?}")
+                 } else if ft.is_socket() {
+                     format!("remove socket {path:?}")
+                 } else if is_whiteout(meta) {
+                     format!("remove whiteout entry {path:?}")
+                 } else if ft.is_char_device() {
+                     format!("remove character device {path:?}")
+                 } else if ft.is_block_device() {
+                     format!("remove block device {path:?}")
+                 } else {
+                     format!("remove unknown file {path:?}") // can't happen?
+                 }
+
+                 #[cfg(not(unix))]
+                 format!("remove {path:?}")
+             })
+        }
+        (true, true) => (path, format!("descend into directory {path:?}")),
+        (true, false) => (path, format!("remove directory {path:?}")),
+    };
+    let (default, choices) = if assume_yes { (Response::Delete, "Y/n") } else { (Response::Skip, "y/N") };
+    loop {
+        let Some(input) = read_line(&format!("{}? ({choices}/a/q/d/s/t/k/v/?) ", prompt), to, timeout)? else {
+            // Nobody's left to answer. Without --assume-yes taking the default here would silently skip the whole
+            // rest of the run one prompt at a time, so just stop.
+            return Ok(if assume_yes { default } else { Response::Quit })
+        };
+        match input.to_lowercase().trim() {
+            ""  => return Ok(default),
+            "y" => return Ok(Response::Delete),
+            "n" => return Ok(Response::Skip),
+            "a" => return Ok(Response::DeleteFromNowOn),
+            "q" => return Ok(Response::Quit),
+            "d" => return Ok(Response::DeleteThisDir(path.to_owned())),
+            "s" => return Ok(Response::SkipThisDir(path.to_owned())),
+            "t" => return Ok(Response::DeleteThisKind(Kind::of(meta))),
+            "k" => return Ok(Response::SkipThisKind(Kind::of(meta))),
+            "v" => to.print(&view(path, meta)),
+            "?" => to.print(&format!("y - Yes, delete it\n\
+                                      n - No, don't delete it\n\
+                                      a - Delete this and everything else (without any further prompts)\n\
+                                      q - Quit without deleting this nor anything else\n\
+                                      d - Delete this and the rest of its directory without further prompts\n\
+                                      s - Don't delete this or anything else in its directory, but continue asking about other items\n\
+                                      t - Delete this and all the other {kind} without further prompts, but keep asking about the rest\n\
+                                      k - Keep this and all the other {kind}, but keep asking about the rest\n\
+                                      v - View the start of the file (or what's in the directory) and then ask again\n\
+                                      ? - Show help\n", kind = Kind::of(meta).plural())),
+            _ => to.print("Bad input. Enter \"?\" for help\n"),
+        }
+    }
+  }
+}
+
+/// For the "v" answer to a prompt. Always ends in a newline.
+fn view(path: &Path, meta: &Metadata) -> String {
+    const ENTRIES: usize = 10;
+    if meta.is_dir() {
+        let mut entries = match read_dir(path) {
+            Ok(d) => d.filter_map(|e| e.ok()).map(|e| e.file_name()).collect::<Vec<_>>(),
+            Err(e) => return format!("Couldn't read the directory: {e}\n"),
+        };
+        entries.sort();
+        let more = entries.len().saturating_sub(ENTRIES);
+        entries.truncate(ENTRIES);
+        let mut out = entries.iter().map(|name| format!("  {}\n", Path::new(name).display())).collect::<String>();
+        if entries.is_empty() { out += "(empty)\n" }
+        if more > 0 { out += &format!("…and {more} more\n") }
+        return out;
+    }
+    if meta.is_symlink() {
+        return format!("-> {:?}\n", std::fs::read_link(path).unwrap_or_default());
+    }
+    if !meta.is_file() {
+        return "Nothing to show for this kind of file\n".to_string();
+    }
+    let mut head = Vec::new();
+    match std::fs::File::open(path).and_then(|f| std::io::Read::read_to_end(&mut std::io::Read::take(f, 4096), &mut head)) {
+        Ok(_) => preview(&head),
+        Err(e) => format!("Couldn't read the file: {e}\n"),
+    }
+}
+
+/// The first few lines of text, or a hex dump of the first few bytes of anything that doesn't look like text.
+fn preview(head: &[u8]) -> String {
+    const LINES: usize = 10;
+    const BYTES: usize = 64;
+    if head.is_empty() {
+        return "(empty)\n".to_string();
+    }
+    // A multibyte character cut off at the end of `head` is fine, anything else wrong is binary.
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => Some(text),
+        Err(e) if e.error_len().is_none() => Some(std::str::from_utf8(&head[..e.valid_up_to()]).unwrap()),
+        Err(_) => None,
+    };
+    if let Some(text) = text.filter(|text| !text.contains('\0')) {
+        return text.lines().take(LINES).map(|line| format!("  {line}\n")).collect();
+    }
+    head.chunks(16).take(BYTES / 16).enumerate().map(|(i, chunk)| {
+        let hex = chunk.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(" ");
+        let ascii = chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect::<String>();
+        format!("  {:08x}  {hex:<47}  {ascii}\n", i * 16)
+    }).collect()
+}
+
+/// Anything that needs to ask the user something while the progress bars are up should go through here so the
+/// bars get out of the way first (and come back after).
+#[derive(Clone)]
+pub struct Prompter {
+    reporter: Arc<dyn Reporter>,
+}
+
+impl Prompter {
+    fn new(reporter: &Arc<dyn Reporter>) -> Prompter {
+        Prompter { reporter: reporter.clone() }
+    }
+
+    /// Runs `f` with the progress bars hidden. Use this to wrap a whole conversation (like a prompt that loops on
+    /// bad input) and call [`read_line`] inside it. Don't nest these.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        let (mut f, mut result) = (Some(f), None);
+        self.reporter.suspend(&mut || result = f.take().map(|f| f()));
+        result.expect("Reporter::suspend() didn't call f")
+    }
+}
+
+/// Prints `prompt` to `to` and reads a line from stdin, or `None` at EOF. This doesn't hide the progress bars, see
+/// [`Prompter`] for that. If nothing is answered within `timeout` it's as if the answer were empty.
+fn read_line(prompt: &str, to: Stream, timeout: Option<Duration>) -> Result<Option<String>> {
+    to.print(prompt);
+    let input = match timeout {
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            input
+        },
+        Some(timeout) => match read_line_until(Instant::now() + timeout)? {
+            Some(input) => input,
+            None => {
+                to.print("(timed out)\n");
+                return Ok(Some(String::new()));
+            },
+        },
+    };
+    if !to.is_terminal() { to.print("\n") } // hack to make tests easier
+    Ok(if input.is_empty() { None } else { Some(input) })
+}
+
+/// For --prompt-timeout: a line from stdin, or `None` if `deadline` comes first. This reads the file descriptor
+/// directly, a byte at a time, since whatever std's buffered stdin had read ahead wouldn't wake up poll(). So once
+/// there's a timeout, every prompt has to come through here.
+#[cfg(unix)]
+fn read_line_until(deadline: Instant) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() { return Ok(None) }
+        let mut fd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+        let ms = (left.as_millis() + 1).min(i32::MAX as u128) as i32;
+        if unsafe { libc::poll(&mut fd, 1, ms) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted { continue }
+            Err(err)?
+        }
+        if fd.revents == 0 { continue }
+        let mut byte = 0u8;
+        match unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+            0 => break, // EOF
+            1 => {
+                line.push(byte);
+                if byte == b'\n' { break }
+            },
+            _ => {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted { continue }
+                Err(err)?
+            },
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+#[cfg(not(unix))]
+fn read_line_until(_deadline: Instant) -> Result<Option<String>> {
+    Err(anyhow!("--prompt-timeout is only supported on unix"))
+}
+
+/// For --newer-than-file and --older-than-file. Looked at once, up front, so it doesn't matter if the reference is
+/// in what's being deleted. A symlink counts as what it points to.
+fn mtime_of(file: &Path, option: &str) -> Result<SystemTime> {
+    file.metadata().and_then(|meta| meta.modified()).map_err(|e| anyhow!("{option}: {file:?}: {e}"))
+}
+
+/// For --owned-by. Like `chown`, a name wins over a number that happens to look like a uid.
+#[cfg(unix)]
+fn uid_for(user: &str) -> Result<u32> {
+    let name = std::ffi::CString::new(user).map_err(|_| anyhow!("--owned-by: Unknown user {user:?}"))?;
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !pw.is_null() {
+        return Ok(unsafe { (*pw).pw_uid });
+    }
+    user.parse().map_err(|_| anyhow!("--owned-by: Unknown user {user:?}"))
+}
+
+#[cfg(not(unix))]
+fn uid_for(_user: &str) -> Result<u32> {
+    Err(anyhow!("--owned-by is only supported on unix"))
+}
+
+/// For --protect-from. Like --precious, what's listed is matched by device and inode, so it doesn't matter which path
+/// leads to it (through symlinks, "..", bind mounts or hard links). A symlink in the list protects both the link and
+/// what it points to. Things in the list that don't exist (yet) can't be deleted anyway, so they're skipped.
+struct Protected(std::collections::HashSet<(u64, u64)>);
+
+impl Protected {
+    #[cfg(unix)]
+    fn load(file: &Path) -> Result<Protected> {
+        use std::os::unix::fs::MetadataExt;
+        let list = std::fs::read(file).map_err(|e| anyhow!("--protect-from: {file:?}: {e}"))?;
+        let mut protected = std::collections::HashSet::new();
+        // Blank lines and #comments are allowed, since this is the kind of list that gets shared around.
+        for line in list.split(|&b| b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                        .filter(|line| !line.is_empty() && !line.starts_with(b"#")) {
+            let path = path_from_raw_bytes(line);
+            if !path.is_absolute() {
+                Err(anyhow!("--protect-from: {file:?}: {path:?} isn't an absolute path"))?
+            }
+            for meta in [path.symlink_metadata(), path.metadata()].into_iter().flatten() {
+                protected.insert((meta.dev(), meta.ino()));
+            }
+        }
+        Ok(Protected(protected))
+    }
+
+    #[cfg(not(unix))]
+    fn load(_file: &Path) -> Result<Protected> {
+        Err(anyhow!("--protect-from is only supported on unix"))
+    }
+
+    fn contains(&self, meta: &Metadata) -> bool {
+        #[cfg(unix)] {
+            use std::os::unix::fs::MetadataExt;
+            self.0.contains(&(meta.dev(), meta.ino()))
+        }
+        #[cfg(not(unix))] {
+            _ = meta;
+            false
+        }
+    }
+}
+
+/// For --skip-open. Every regular file that any process has open, going by /proc/<pid>/fd. It's a snapshot from
+/// when we start, since going through all of /proc for every file would take forever. Other users' processes can't be
+/// looked at without root, and processes come and go while we look, so those errors just mean there's less to see.
+#[cfg(target_os = "linux")]
+fn open_files() -> Result<std::collections::HashSet<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+    let mut open = std::collections::HashSet::new();
+    for proc in read_dir("/proc").map_err(|e| anyhow!("--skip-open: /proc: {e}"))?.flatten() {
+        if !proc.file_name().as_encoded_bytes().iter().all(u8::is_ascii_digit) { continue }
+        let Ok(fds) = read_dir(proc.path().join("fd")) else { continue };
+        for fd in fds.flatten() {
+            // Following the link gets to the file itself, wherever it is (or was: deleted ones are still open).
+            let Ok(meta) = std::fs::metadata(fd.path()) else { continue };
+            if meta.is_file() { open.insert((meta.dev(), meta.ino())); }
+        }
+    }
+    Ok(open)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_files() -> Result<std::collections::HashSet<(u64, u64)>> {
+    Err(anyhow!("--skip-open is only supported on Linux"))
+}
+
+/// For --preserve-parent-mtime. Every directory we delete something from gets its mtime saved first, and the ones
+/// that are still around at the end get it back. Since directories are deleted right after everything in them, this
+/// only ever holds the directories along the current path plus the ones that are staying.
+#[derive(Default)]
+struct ParentMtimes {
+    mtimes: std::collections::HashMap<PathBuf, std::time::SystemTime>,
+}
+
+impl ParentMtimes {
+    fn before(&mut self, path: &Path) {
+        let Some(parent) = parent_dir(path) else { return };
+        if self.mtimes.contains_key(parent) { return }
+        if let Ok(mtime) = parent.metadata().and_then(|m| m.modified()) {
+            self.mtimes.insert(parent.to_owned(), mtime);
+        }
+    }
+
+    fn gone(&mut self, dir: &Path) {
+        self.mtimes.remove(dir);
+    }
+
+    /// The access time is left alone: it's already been changed by reading the directory, and a backup or build
+    /// tool isn't going to care about it anyway.
+    fn restore(self) -> Vec<(PathBuf, std::io::Error)> {
+        self.mtimes.into_iter().filter_map(|(dir, mtime)| {
+            match std::fs::File::open(&dir).and_then(|f| f.set_modified(mtime)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Some((dir, e)),
+                _ => None,
+            }
+        }).collect()
+    }
+}
+
+/// For --sync. An unlink (or rename, for --bury) isn't on disk until the directory it was in is, so every directory
+/// something was deleted from gets an fsync at the end. Doing it once per directory rather than once per delete keeps
+/// the cost down, and directories that were deleted themselves are dropped since their parent covers them.
+#[derive(Default)]
+struct SyncDirs {
+    dirs: std::collections::HashSet<PathBuf>,
+}
+
+impl SyncDirs {
+    fn deleted(&mut self, path: &Path) {
+        self.dirs.remove(path);
+        if let Some(parent) = parent_dir(path) {
+            if !self.dirs.contains(parent) { self.dirs.insert(parent.to_owned()); }
+        }
+    }
+
+    /// For --prune, which wants the same directories: deletes the ones under `top` that are empty now, then their
+    /// parents if that emptied them, and so on. Anything that can't be deleted (usually because it isn't empty) just
+    /// stays. Returns what was deleted.
+    fn prune(mut self, top: &Path) -> Vec<PathBuf> {
+        let mut pruned = vec![];
+        let top_len = top.components().count();
+        // Deepest first, so a directory's turn comes after everything that might have been in it.
+        while let Some(dir) = self.dirs.iter().max_by_key(|dir| dir.components().count()).cloned() {
+            self.dirs.remove(&dir);
+            let Ok(abs) = std::path::absolute(&dir) else { continue };
+            if !abs.starts_with(top) || abs.components().count() <= top_len { continue }
+            if remove_dir(&dir).is_ok() {
+                self.deleted(&dir);
+                pruned.push(dir);
+            }
+        }
+        pruned
+    }
+
+    fn sync(self) -> Vec<(PathBuf, std::io::Error)> {
+        self.dirs.into_iter().filter_map(|dir| {
+            std::fs::File::open(&dir).and_then(|f| f.sync_all()).err().map(|e| (dir, e))
+        }).collect()
+    }
+}
+
+/// For -P. BSD `rm -P` overwrites a file three times before unlinking it: with 0xff bytes, then 0x00, then 0xff
+/// again, syncing after each pass so they all actually reach the disk. Only regular files (never what a symlink points
+/// to), and not ones with other hard links, since the data isn't going away with this name. Filesystems that never
+/// write in place (copy-on-write ones like btrfs, ZFS and APFS) leave the original data where it was, so there it's
+/// skipped with a warning (once per filesystem) rather than pretending.
+#[derive(Default)]
+struct Overwrite {
+    warned: std::collections::HashSet<u64>, // Devices already warned about.
+}
+
+impl Overwrite {
+    const PASSES: [u8; 3] = [0xff, 0x00, 0xff];
+
+    fn file(&mut self, path: &Path, reporter: &dyn Reporter) -> std::io::Result<()> {
+        // Opening a device or a fifo to write to it can do things, so don't.
+        if !path.symlink_metadata()?.is_file() { return Ok(()) }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true);
+        // In case it got swapped for a symlink since.
+        #[cfg(unix)] std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NOFOLLOW);
+        let mut file = options.open(path)?;
+        let meta = file.metadata()?;
+        if !meta.is_file() { return Ok(()) }
+        #[cfg(unix)]
+        if std::os::unix::fs::MetadataExt::nlink(&meta) > 1 {
+            reporter.error(format!("{path:?}: Has other hard links, so deleting it without overwriting it\n").as_bytes());
+            return Ok(())
+        }
+        if !overwrites_in_place(&file) {
+            if self.warned.insert(device(&meta).unwrap_or_default()) {
+                reporter.error(format!("{path:?}: Is on a copy-on-write filesystem where overwriting wouldn't touch the \
+                                        original data, so -P is just deleting here (and on the rest of this \
+                                        filesystem)\n").as_bytes());
+            }
+            return Ok(())
+        }
+        for pass in Self::PASSES {
+            let buf = vec![pass; 65536];
+            std::io::Seek::rewind(&mut file)?;
+            let mut left = meta.len();
+            while left > 0 {
+                let n = left.min(buf.len() as u64) as usize;
+                file.write_all(&buf[..n])?;
+                left -= n as u64;
+            }
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether writing to a file replaces the data that was there, rather than putting the new data somewhere else.
+fn overwrites_in_place(file: &std::fs::File) -> bool {
+    cow_filesystem(file).is_none()
+}
+
+/// The name of the copy-on-write filesystem `file` is on, if it's on one. Those put new data somewhere else instead of
+/// over the old, and can have snapshots that keep hold of what's deleted.
+#[cfg(target_os = "linux")]
+fn cow_filesystem(file: &std::fs::File) -> Option<&'static str> {
+    use std::os::fd::AsRawFd;
+    const BTRFS: i64 = 0x9123683e;
+    const ZFS: i64 = 0x2fc12fc1;
+    const BCACHEFS: i64 = 0xca451a4e;
+    let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut fs) } != 0 { return None }
+    match fs.f_type as i64 {
+        BTRFS    => Some("btrfs"),
+        ZFS      => Some("ZFS"),
+        BCACHEFS => Some("bcachefs"),
+        _        => None,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn cow_filesystem(file: &std::fs::File) -> Option<&'static str> {
+    use std::os::fd::AsRawFd;
+    let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut fs) } != 0 { return None }
+    match unsafe { std::ffi::CStr::from_ptr(fs.f_fstypename.as_ptr()) }.to_bytes() {
+        b"apfs" => Some("APFS"),
+        b"zfs"  => Some("ZFS"),
+        _       => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn cow_filesystem(_file: &std::fs::File) -> Option<&'static str> {
+    None
+}
+
+/// Deleting from a filesystem that has snapshots can free nothing at all, since a snapshot still has the data, and
+/// then "freed" doesn't match what `df` says. Whether there actually are any snapshots isn't something we can find out
+/// without root (or at all, portably), so this is a note rather than a warning, and only once. It goes through
+/// [`Reporter::note`], so only the progress bars show it: on btrfs or ZFS it'd be in every run, which nobody wants in
+/// their logs.
+fn snapshot_note(paths: &[PathBuf]) -> Option<String> {
+    paths.iter().find_map(|path| {
+        let file = std::fs::File::open(path).ok().or_else(|| std::fs::File::open(parent_dir(path)?).ok())?;
+        let fs = cow_filesystem(&file)?;
+        Some(format!("Note: {path:?} is on {fs}, where snapshots can hold on to what's deleted, so the space \
+                      freed might not show up as free until they're gone"))
+    })
+}
+
+/// For --backend=iouring. Files that are already waiting in the queue get unlinked together, with one syscall for the
+/// lot instead of one each. They then go back through the main loop one at a time (in the order they were found,
+/// whatever order the kernel finished them in), so the counting and error handling are the same as without it.
+/// Anything that isn't a file ends the batch and waits for it, since a directory won't be empty until then.
+#[cfg(all(target_os = "linux", feature = "iouring"))]
+struct Uring {
+    ring: io_uring::IoUring,
+    done: std::collections::VecDeque<(ToDelete, Option<std::io::Result<()>>)>,
+}
+
+#[cfg(all(target_os = "linux", feature = "iouring"))]
+impl Uring {
+    const ENTRIES: u32 = 256;
+
+    /// None (with a warning) if the kernel can't do it, so things carry on the standard way.
+    fn start() -> Result<Option<Uring>> {
+        let new = || -> std::io::Result<Uring> {
+            let ring = io_uring::IoUring::new(Self::ENTRIES)?;
+            let mut probe = io_uring::Probe::new();
+            ring.submitter().register_probe(&mut probe)?;
+            if !probe.is_supported(io_uring::opcode::UnlinkAt::CODE) { Err(std::io::ErrorKind::Unsupported)? }
+            Ok(Uring { ring, done: Default::default() })
+        };
+        match new() {
+            Ok(uring) => Ok(Some(uring)),
+            Err(e) => {
+                eprintln!("io_uring isn't available ({e}), deleting one file at a time instead");
+                Ok(None)
+            },
+        }
+    }
+
+    /// Whether the next thing is one that's already been deleted (or failed to be).
+    fn deleted_some(&self) -> bool {
+        self.done.front().is_some_and(|(_, unlinked)| unlinked.is_some())
+    }
+
+    /// Something from the last batch, and whether it was deleted (None for the thing that ended the batch).
+    fn next(&mut self) -> Option<(ToDelete, Option<std::io::Result<()>>)> {
+        self.done.pop_front()
+    }
+
+    /// `first`, plus up to `max` files in all from what's already in `queue`.
+    fn batch(&mut self, first: ToDelete, queue: &mut Queue, max: usize) -> Result<()> {
+        use std::os::fd::AsRawFd;
+        let mut files = vec![first];
+        let mut after = None;
+        while files.len() < max.min(Self::ENTRIES as usize) {
+            match queue.try_recv() {
+                Some(file@ToDelete::File { .. }) => files.push(file),
+                Some(other) => { after = Some(other); break },
+                None => break,
+            }
+        }
+        // These have to stay put until the kernel is done with them. Past PATH_MAX the unlink is relative to the
+        // directory it's in (see LongPath), and if that can't be opened it's already failed.
+        let targets = files.iter().map(|file| -> std::io::Result<_> {
+            let ToDelete::File { path, .. } = file else { unreachable!() };
+            Ok(match LongPath::new(path)? {
+                Some(LongPath { dir, name }) => (Some(dir), name),
+                None => (None, std::ffi::CString::new(raw_path_bytes(path).into_owned())?),
+            })
+        });
+        let (mut results, targets): (Vec<Option<std::io::Result<()>>>, Vec<_>) = targets.map(|target| match target {
+            Ok(target) => (None, Some(target)),
+            Err(e) => (Some(Err(e)), None),
+        }).unzip();
+        for (i, target) in targets.iter().enumerate() {
+            let Some((dir, name)) = target else { continue };
+            let dir = io_uring::types::Fd(dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.as_raw_fd()));
+            let unlink = io_uring::opcode::UnlinkAt::new(dir, name.as_ptr()).build().user_data(i as u64);
+            // Can't be full: there are never more than ENTRIES, and the last batch was all collected.
+            unsafe { self.ring.submission().push(&unlink) }.map_err(|e| anyhow!("io_uring: {e}"))?;
+        }
+        let mut left = results.iter().filter(|r| r.is_none()).count();
+        while left > 0 {
+            match self.ring.submit_and_wait(left) {
+                Ok(_) => {},
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY)) => {},
+                Err(e) => Err(anyhow!("io_uring: {e}"))?,
+            }
+            for done in self.ring.completion() {
+                results[done.user_data() as usize] = Some(match done.result() {
+                    err if err < 0 => Err(std::io::Error::from_raw_os_error(-err)),
+                    _ => Ok(()),
+                });
+                left -= 1;
+            }
+        }
+        self.done.extend(files.into_iter().zip(results));
+        self.done.extend(after.map(|after| (after, None)));
+        Ok(())
+    }
+}
+
+/// Without io_uring there's never one of these.
+#[cfg(not(all(target_os = "linux", feature = "iouring")))]
+enum Uring {}
+
+#[cfg(not(all(target_os = "linux", feature = "iouring")))]
+impl Uring {
+    fn start() -> Result<Option<Uring>> {
+        Err(anyhow!("--backend=iouring needs Linux, and rm-rfp built with the `iouring` feature"))
+    }
+
+    fn deleted_some(&self) -> bool { match *self {} }
+    fn next(&mut self) -> Option<(ToDelete, Option<std::io::Result<()>>)> { match *self {} }
+    fn batch(&mut self, _first: ToDelete, _queue: &mut Queue, _max: usize) -> Result<()> { match *self {} }
+}
+
+/// For --bury. Each run gets its own directory in the graveyard: `files/` mirrors the original (absolute) paths of
+/// everything buried, and `manifest` lists them in the order they were buried, each as `<unix time> <path>` and
+/// ending with a NUL (since a path can have anything else in it).
+struct Graveyard {
+    run:      PathBuf,
+    manifest: std::fs::File,
+}
+
+impl Graveyard {
+    fn new(graveyard: &Path, paths: &[PathBuf]) -> Result<Graveyard> {
+        std::fs::create_dir_all(graveyard).map_err(|e| anyhow!("{graveyard:?}: Couldn't create the graveyard: {e}"))?;
+        let real = std::fs::canonicalize(graveyard)?;
+        // We'd end up burying the graveyard (or finding what we just buried and burying it again).
+        if let Some(path) = paths.iter().find(|path| std::fs::canonicalize(path).is_ok_and(|path| real.starts_with(path))) {
+            Err(anyhow!("{path:?}: The graveyard ({graveyard:?}) is in here"))?
+        }
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+        let run = graveyard.join(format!("{now}-{}", std::process::id()));
+        std::fs::create_dir_all(run.join("files")).map_err(|e| anyhow!("{run:?}: {e}"))?;
+        let manifest = std::fs::OpenOptions::new().create_new(true).append(true).open(run.join("manifest"))
+            .map_err(|e| anyhow!("{run:?}: Couldn't create the manifest: {e}"))?;
+        Ok(Graveyard { run, manifest })
+    }
+
+    /// Directories come after everything that was in them (like deleting), so by now they're empty and all that's
+    /// needed is for them to exist in the graveyard so that --restore can recreate them even if they were empty.
+    fn bury(&mut self, path: &Path, is_dir: bool) -> std::io::Result<()> {
+        let real = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => std::fs::canonicalize(if parent == Path::new("") { Path::new(".") } else { parent })?.join(name),
+            _ => std::fs::canonicalize(path)?,
+        };
+        let grave = grave(&self.run, &real);
+        if is_dir {
+            std::fs::create_dir_all(&grave)?;
+            remove_dir(path)?;
+        } else {
+            std::fs::create_dir_all(grave.parent().unwrap_or(&self.run))?;
+            move_path(path, &grave)?;
+        }
+        // After the move so that the manifest never lists something that isn't there.
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut entry = format!("{now} ").into_bytes();
+        entry.extend_from_slice(&raw_path_bytes(&real));
+        entry.push(0);
+        self.manifest.write_all(&entry)
+    }
+}
+
+/// Where `path` (which has to be absolute) lives in the graveyard `run`.
+fn grave(run: &Path, path: &Path) -> PathBuf {
+    run.join("files").join(path.components().filter(|c| matches!(c, std::path::Component::Normal(_))).collect::<PathBuf>())
+}
+
+fn default_graveyard() -> Result<PathBuf> {
+    match (std::env::var_os("XDG_DATA_HOME"), std::env::var_os("HOME")) {
+        (Some(xdg), _) => Ok(PathBuf::from(xdg).join("rm-rfp/graveyard")),
+        (None, Some(home)) => Ok(PathBuf::from(home).join(".local/share/rm-rfp/graveyard")),
+        (None, None) => Err(anyhow!("Neither $XDG_DATA_HOME nor $HOME is set, so use --graveyard to say where it is")),
+    }
+}
+
+/// rename() if we can, otherwise (the graveyard is on another filesystem) copy it and delete the original.
+fn move_path(from: &Path, to: &Path) -> std::io::Result<()> {
+    let Err(e) = std::fs::rename(from, to) else { return Ok(()) };
+    if e.kind() != std::io::ErrorKind::CrossesDevices { return Err(e) }
+    let meta = from.symlink_metadata()?;
+    if meta.is_symlink() {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)?;
+        #[cfg(not(unix))]
+        return Err(e);
+    } else if meta.is_file() {
+        std::fs::copy(from, to)?;
+    } else {
+        return Err(std::io::Error::new(e.kind(), "Only files and symlinks can be moved to another filesystem"));
+    }
+    remove_file(from)
+}
+
+/// --restore. Goes through the manifest backwards so each directory gets recreated before what was in it is put back.
+/// Nothing that's there now gets overwritten. If everything made it back the run is removed from the graveyard,
+/// otherwise it's left alone so nothing gets lost.
+fn restore(graveyard: &Path, run: Option<&Path>) -> Result<()> {
+    let run = match run {
+        Some(run) => run.to_owned(),
+        None => read_dir(graveyard).map_err(|e| anyhow!("{graveyard:?}: {e}"))?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| { let name = entry.file_name().into_string().ok()?;
+                                  let (time, pid) = name.split_once('-')?;
+                                  Some(((time.parse::<u64>().ok()?, pid.parse::<u32>().ok()?), entry.path())) })
+            .max()
+            .map(|(_, run)| run)
+            .ok_or_else(|| anyhow!("{graveyard:?}: Nothing has been buried"))?,
+    };
+    let manifest = std::fs::read(run.join("manifest")).map_err(|e| anyhow!("{run:?}: Couldn't read the manifest: {e}"))?;
+    let (mut restored, mut errors) = (0_u64, 0_u64);
+    for entry in manifest.split(|&b| b == 0).filter(|entry| !entry.is_empty()).rev() {
+        let Some(space) = entry.iter().position(|&b| b == b' ') else {
+            Err(anyhow!("{run:?}: The manifest is corrupt"))?
+        };
+        let path = path_from_raw_bytes(&entry[space+1..]);
+        let grave = grave(&run, &path);
+        let there = path.symlink_metadata().ok();
+        let res = if grave.symlink_metadata().is_ok_and(|m| m.is_dir()) && there.as_ref().is_none_or(|m| m.is_dir()) {
+            std::fs::create_dir_all(&path)
+        } else if there.is_some() {
+            Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "Something else is there now, not overwriting it"))
+        } else {
+            path.parent().map_or(Ok(()), std::fs::create_dir_all).and_then(|_| move_path(&grave, &path))
+        };
+        match res {
+            Ok(()) => restored += 1,
+            Err(e) => { eprintln!("{path:?}: {e}"); errors += 1 },
+        }
+    }
+    eprintln!("Restored {restored} files and directories from {run:?}");
+    if errors > 0 {
+        Err(anyhow!("Finished with {errors} error{}. Anything that wasn't restored is still in {run:?}", if errors == 1 { "" } else { "s" }))?
+    }
+    std::fs::remove_dir_all(&run).map_err(|e| anyhow!("{run:?}: Everything was restored but removing it failed: {e}"))
+}
+
+/// Takes advisory locks for the `paths`: an exclusive one on each path, and a shared one on every directory it's in,
+/// all the way up. So two runs on the same tree collide, and so do runs on a tree and something inside it, but runs on
+/// trees side by side don't. The lock files live in [`lock_dir`] rather than the tree itself (where we'd delete them)
+/// and are named after the device and inode so that every spelling of a path gets the same lock. Lock files are left
+/// behind since removing them would race with someone else opening them.
+#[cfg(unix)]
+fn lock(paths: &[PathBuf]) -> Result<Vec<std::fs::File>> {
+    use std::os::{fd::AsRawFd, unix::fs::{MetadataExt, OpenOptionsExt}};
+    let lock_dir = lock_dir()?;
+    // (device, inode) => (the <path> it's for, what's being locked, exclusive). Each one is only locked once, since a
+    // second flock() of our own would collide with the first, and being a <path> wins over being a directory one is in.
+    let mut wanted = BTreeMap::new();
+    for path in paths {
+        let parent = match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => Some(Path::new(".")),
+            parent => parent,
+        };
+        let parent = parent.map(Path::canonicalize).transpose().map_err(|e| anyhow!("{path:?}: Couldn't find the directory it's in: {e}"))?;
+        for dir in parent.iter().flat_map(|parent| parent.ancestors()) {
+            let m = dir.metadata().map_err(|e| anyhow!("{dir:?}: {e}"))?;
+            wanted.entry((m.dev(), m.ino())).or_insert((path, dir.to_owned(), false));
+        }
+        let m = path.symlink_metadata().map_err(|e| anyhow!("{path:?}: {e}"))?;
+        wanted.insert((m.dev(), m.ino()), (path, path.clone(), true));
+    }
+    wanted.into_iter().map(|((dev, ino), (path, locking, exclusive))| {
+        let lock_path = lock_dir.join(format!("rm-rfp-{dev:x}-{ino:x}.lock"));
+        let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC).open(&lock_path)
+            .map_err(|e| anyhow!("{path:?}: Couldn't open lock file {lock_path:?}: {e}"))?;
+        let how = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+        if unsafe { libc::flock(file.as_raw_fd(), how | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            match (err.kind(), exclusive) {
+                (std::io::ErrorKind::WouldBlock, true) =>
+                    Err(anyhow!("{path:?}: Another rm-rfp is already deleting this, or something in it (locked with {lock_path:?})"))?,
+                (std::io::ErrorKind::WouldBlock, false) =>
+                    Err(anyhow!("{path:?}: Another rm-rfp is already deleting {locking:?}, which this is in (locked with {lock_path:?})"))?,
+                _ => Err(anyhow!("{path:?}: Couldn't lock {lock_path:?}: {err}"))?,
+            }
+        }
+        Ok(file)
+    }).collect()
+}
+
+/// Somewhere only we can write, so nobody else can hold our locks (or plant a symlink where one goes). That's
+/// $XDG_RUNTIME_DIR, or failing that a directory of our own in the temp dir. The catch is that two different users
+/// deleting the same thing don't see each other's locks.
+#[cfg(unix)]
+fn lock_dir() -> Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    let uid = unsafe { libc::geteuid() };
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) if !runtime.is_empty() => PathBuf::from(runtime).join("rm-rfp"),
+        _ => std::env::temp_dir().join(format!("rm-rfp-{uid}")),
+    };
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(anyhow!("Couldn't create lock directory {dir:?}: {e}"))?,
+        _ => {},
+    }
+    // If it was already there, it might not have been us that made it.
+    let m = dir.symlink_metadata().map_err(|e| anyhow!("Lock directory {dir:?}: {e}"))?;
+    if !m.is_dir() || m.uid() != uid || m.permissions().mode() & 0o077 != 0 {
+        Err(anyhow!("Lock directory {dir:?}: Not using it, since it isn't a directory that only we can get into"))?
+    }
+    Ok(dir)
+}
+
+#[cfg(not(unix))]
+fn lock(_paths: &[PathBuf]) -> Result<Vec<()>> {
+    eprintln!("--lock isn't supported on this platform, continuing without it");
+    Ok(vec![])
+}
+
+/// An option from the usage string. Just enough to know what can go in the config file and how to spot it on the
+/// command line.
+struct OptSpec {
+    short: Option<char>,
+    long:  String, // without the "--"
+    takes_value: bool,
+}
+
+fn option_specs(usage: &str) -> Vec<OptSpec> {
+    usage.lines().map(str::trim_start).filter(|line| line.starts_with('-')).map(|line| {
+        let spec = line.split("  ").next().unwrap_or_default();
+        let mut opt = OptSpec { short: None, long: String::new(), takes_value: spec.contains('=') };
+        for part in spec.split(", ") {
+            match part.strip_prefix("--") {
+                Some(long) => opt.long = long.split('=').next().unwrap_or_default().to_owned(),
+                None => opt.short = part.chars().nth(1),
+            }
+        }
+        opt
+    }).collect()
+}
+
+/// These turn off a safety check. That's fine for one run, but left in a config file or an exported variable it'd
+/// quietly turn it off for every run after, so they only count when they're on the command line.
+const COMMAND_LINE_ONLY: &[&str] = &["no-preserve-root", "yes-really", "allow-delete-cwd", "allow-delete-self",
+                                     "ignore-readonly", "cross-mounts"];
+
+/// Default options from the config file, overridden by the environment. Keys are the long option names without
+/// the "--". A missing config file is fine (unless $RM_RFP_CONFIG pointed at it specifically).
+fn read_defaults(usage: &str) -> Result<BTreeMap<String, String>> {
+    let (path, explicit) = match (std::env::var_os("RM_RFP_CONFIG"), std::env::var_os("XDG_CONFIG_HOME"), std::env::var_os("HOME")) {
+        (Some(config), _, _) => (PathBuf::from(config), true),
+        (None, Some(xdg), _) => (PathBuf::from(xdg).join("rm-rfp/config.toml"), false),
+        (None, None, Some(home)) => (PathBuf::from(home).join(".config/rm-rfp/config.toml"), false),
+        (None, None, None) => (PathBuf::new(), false), // Reading "" fails with NotFound.
+    };
+    let mut defaults = BTreeMap::new();
+    match std::fs::read_to_string(&path) {
+        Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => Err(anyhow!("{path:?}: {e}"))?,
+        Ok(config) => {
+            for (key, value) in config.parse::<toml::Table>().map_err(|e| anyhow!("{path:?}: {e}"))? {
+                let value = match value {
+                    toml::Value::String(s) => s,
+                    toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => value.to_string(),
+                    _ => Err(anyhow!("{path:?}: {key} should be a string, number, or boolean"))?,
+                };
+                if COMMAND_LINE_ONLY.contains(&key.as_str()) {
+                    Err(anyhow!("{path:?}: {key} turns off a safety check, so it can only be given on the command line"))?
+                }
+                defaults.insert(key, value);
+            }
+        },
+    }
+    for opt in option_specs(usage) {
+        let var = format!("RM_RFP_{}", opt.long.to_uppercase().replace('-', "_"));
+        if let Some(value) = std::env::var_os(&var) {
+            if COMMAND_LINE_ONLY.contains(&opt.long.as_str()) {
+                Err(anyhow!("${var}: --{} turns off a safety check, so it can only be given on the command line", opt.long))?
+            }
+            defaults.insert(opt.long, value.into_string().map_err(|_| anyhow!("${var} isn't valid UTF-8"))?);
+        }
+    }
+    Ok(defaults)
+}
+
+/// For --dedupe-args: `(inside, outer)` index pairs for the `paths` that are in another one of the `paths` (or are the
+/// same thing as one that came earlier). Paths are compared with the directory they're in resolved, but not the last
+/// component, so a symlink is its own thing and not whatever it points to. Ones that don't exist are left alone.
+fn overlapping(paths: &[PathBuf]) -> Vec<(usize, usize)> {
+    let real: Vec<Option<PathBuf>> = paths.iter().map(|path| {
+        let name = path.file_name()?;
+        let dir = match path.parent() { Some(p) if p != Path::new("") => p, _ => Path::new(".") };
+        path.symlink_metadata().ok()?;
+        Some(dir.canonicalize().ok()?.join(name))
+    }).collect();
+    real.iter().enumerate().filter_map(|(i, path)| {
+        let path = path.as_ref()?;
+        real.iter().enumerate().find(|&(j, outer)| {
+            outer.as_ref().is_some_and(|outer| j != i && path.starts_with(outer) && (path != outer || j < i))
+        }).map(|(j, _)| (i, j))
+    }).collect()
+}
+
+/// When we're run as `rm` (a symlink called that, say), the `rm` options that mean something different here (or nothing)
+/// are turned into what they mean to us, so scripts and fingers that say `rm -rf` still work:
+///
+///   -r, -R, --recursive      Dropped: it always recurses.
+///   -f, --force              -f (ignore <path>s that don't exist), and like rm's it means never asking.
+///   -i, --interactive[=always]  -i
+///   -I, --interactive=once   One question up front (the bool that comes back), like rm's.
+///   --interactive=never      Never asking.
+///   -d, --dir                -d
+///   -v, --verbose            Dropped: there's the progress display instead.
+///   --one-file-system, --preserve-root[=all]  Dropped: those are already what happens.
+///
+/// As with rm, whichever of -f, -i, -I, and --interactive comes last decides the asking. Bunched up short options are
+/// taken apart, so ours can be in there too (`-rfn`). Anything else (including our long options) goes through
+/// untouched, as does everything after `--`. Docopt won't take an option twice, so `rm -f -rf` only passes one -f.
+fn from_rm_args(usage: &str, argv: Vec<String>) -> (Vec<String>, bool) {
+    if argv.first().and_then(|argv0| Path::new(argv0).file_stem()).is_none_or(|name| name != "rm") {
+        return (argv, false);
+    }
+    #[derive(PartialEq)]
+    enum Ask { Never, Once, Always }
+    let takes_value: Vec<char> = option_specs(usage).into_iter().filter(|opt| opt.takes_value).filter_map(|opt| opt.short)
+                                                    .collect();
+    let (mut flags, mut rest) = (vec![], vec![argv[0].clone()]);
+    fn add(flags: &mut Vec<&str>, flag: &'static str) { if !flags.contains(&flag) { flags.push(flag) } }
+    let mut ask = None;
+    let mut args = argv.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => { rest.push(arg.clone()); break },
+            "--recursive" | "--verbose" | "--one-file-system" | "--preserve-root" | "--preserve-root=all" => {},
+            "--force" => { add(&mut flags, "-f"); ask = Some(Ask::Never) },
+            "--dir" => add(&mut flags, "-d"),
+            "--interactive" | "--interactive=always" => ask = Some(Ask::Always),
+            "--interactive=once" => ask = Some(Ask::Once),
+            "--interactive=never" => ask = Some(Ask::Never),
+            short if short.len() > 1 && short.starts_with('-') && !short.starts_with("--") => {
+                let mut ours = String::new();
+                for (i, c) in short.char_indices().skip(1) {
+                    match c {
+                        'r' | 'R' | 'v' => {},
+                        'f' => { add(&mut flags, "-f"); ask = Some(Ask::Never) },
+                        'i' => ask = Some(Ask::Always),
+                        'I' => ask = Some(Ask::Once),
+                        'd' => add(&mut flags, "-d"),
+                        // The rest is its value. If there isn't any, the next argument is, whatever it looks like.
+                        c if takes_value.contains(&c) => {
+                            ours.push_str(&short[i..]);
+                            rest.push(format!("-{ours}"));
+                            if i + c.len_utf8() == short.len() { rest.extend(args.next().cloned()) }
+                            ours.clear();
+                            break
+                        },
+                        c => ours.push(c),
+                    }
+                }
+                if !ours.is_empty() { rest.push(format!("-{ours}")) }
+            },
+            _ => rest.push(arg.clone()),
+        }
+    }
+    rest.extend(args.cloned());
+    if ask == Some(Ask::Always) { add(&mut flags, "-i") }
+    rest.splice(1..1, flags.into_iter().map(String::from));
+    (rest, ask == Some(Ask::Once))
+}
+
+/// Docopt doesn't know about defaults from anywhere but the usage string and it won't take an option twice, so
+/// `defaults` are turned into flags and put in front of the real ones, skipping any that are on the command line.
+fn with_defaults(usage: &str, defaults: BTreeMap<String, String>, argv: Vec<String>) -> Result<Vec<String>> {
+    let specs = option_specs(usage);
+    let given = |opt: &OptSpec| argv.iter().skip(1).take_while(|arg| *arg != "--").any(|arg| {
+        match (arg.strip_prefix("--"), arg.strip_prefix('-')) {
+            (Some(long), _) => long.split('=').next() == Some(&opt.long),
+            (None, Some(shorts)) => {
+                for c in shorts.chars() {
+                    if opt.short == Some(c) { return true }
+                    if specs.iter().any(|s| s.short == Some(c) && s.takes_value) { break } // the rest is its value
+                }
+                false
+            },
+            (None, None) => false,
+        }
+    });
+    if specs.iter().any(|opt| opt.long == "help" && given(opt)) {
+        return Ok(argv); // "rm-rfp --help" is its own usage pattern and won't take anything else.
+    }
+    let mut flags = vec![];
+    for (name, value) in defaults {
+        let opt = specs.iter().find(|opt| opt.long == name).ok_or_else(|| anyhow!("Unknown option {name:?} in the config file or environment"))?;
+        if given(opt) { continue }
+        if opt.takes_value {
+            flags.push(format!("--{name}={value}"));
+        } else {
+            match value.to_lowercase().as_str() {
+                "true" | "yes" | "1" => flags.push(format!("--{name}")),
+                "false" | "no" | "0" | "" => {},
+                _ => Err(anyhow!("{name} should be true or false, not {value:?}"))?,
+            }
+        }
+    }
+    Ok(argv.iter().take(1).cloned().chain(flags).chain(argv.iter().skip(1).cloned()).collect())
+}
+
+/// Expands each of `args` as a glob, for when there was no shell around to do it. A pattern that doesn't match
+/// anything is an error (unless `force`) since a literal "*.log" getting silently ignored is surprising.
+fn expand_globs(args: &[PathBuf], force: bool) -> Result<Vec<PathBuf>> {
+    // Like the shell, * shouldn't match dot files.
+    let options = glob::MatchOptions { require_literal_leading_dot: true, ..Default::default() };
+    let mut paths = vec![];
+    for arg in args {
+        let Some(pattern) = arg.to_str() else { paths.push(arg.clone()); continue }; // glob only takes str
+        let mut matched = false;
+        for pattern in expand_braces(pattern) {
+            for path in glob::glob_with(&pattern, options).map_err(|e| anyhow!("{arg:?}: Bad pattern: {e}"))? {
+                paths.push(path.map_err(|e| anyhow!("{:?}: {}", e.path(), e.error()))?);
+                matched = true;
+            }
+        }
+        if !matched && !force {
+            Err(anyhow!("{arg:?}: No matches"))?
+        }
+    }
+    Ok(paths)
+}
+
+/// "a{b,c{d,e}}" -> ["ab", "acd", "ace"]. Unbalanced braces are left alone.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let Some(open) = pattern.find('{') else { return vec![pattern.to_owned()] };
+    let mut depth = 0;
+    let mut commas = vec![];
+    for (i, c) in pattern[open..].char_indices().map(|(i, c)| (open + i, c)) {
+        match c {
+            '{' => depth += 1,
+            ',' if depth == 1 => commas.push(i),
+            '}' if depth == 1 && commas.is_empty() => { // "{a}" isn't a list, same as in the shell.
+                return expand_braces(&pattern[i+1..]).into_iter().map(|rest| format!("{}{rest}", &pattern[..=i])).collect();
+            },
+            '}' if depth == 1 => {
+                let (prefix, suffix) = (&pattern[..open], &pattern[i+1..]);
+                let bounds: Vec<usize> = std::iter::once(open).chain(commas).chain(std::iter::once(i)).collect();
+                return bounds.windows(2)
+                    .flat_map(|w| expand_braces(&format!("{prefix}{}{suffix}", &pattern[w[0]+1..w[1]])))
+                    .collect();
+            },
+            '}' => depth -= 1,
+            _ => {},
+        }
+    }
+    vec![pattern.to_owned()]
+}
+
+/// "90", "90s", "1500ms", "5m", "1h", or "1.5h".
+fn parse_duration(s: &str) -> Result<Duration> {
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().map_err(|_| anyhow!("Bad duration {s:?}"))?;
+    let secs = match unit.trim() {
+        "ms"      => num / 1000.0,
+        "" | "s"  => num,
+        "m"       => num * 60.0,
+        "h"       => num * 60.0 * 60.0,
+        _ => Err(anyhow!("Bad duration {s:?}: unit should be ms, s, m, or h"))?,
+    };
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// "1000,80" (file, directory) or just "0" to use the same for both. In microseconds, since real deletes are that
+/// quick.
+fn parse_dry_run_delay(s: &str) -> Result<(Duration, Duration)> {
+    let micros = |us: &str| us.trim().parse().map(Duration::from_micros).map_err(|_| anyhow!("Bad --dry-run-delay {s:?}"));
+    match s.split_once(',') {
+        Some((file, dir)) => Ok((micros(file)?, micros(dir)?)),
+        None              => Ok((micros(s)?, micros(s)?)),
+    }
+}
+
+/// For --include and --exclude. Like rsync, a pattern without a '/' is matched against names and one with a '/' is
+/// matched against the path starting from the <path> it was found in. Either way, matching a directory counts for
+/// everything in it.
+#[derive(Clone)]
+struct Globs(Vec<glob::Pattern>);
+
+impl Globs {
+    fn new(globs: &str, option: &str) -> Result<Globs> {
+        Ok(Globs(globs.split(':').filter(|glob| !glob.is_empty())
+                   .map(|glob| glob::Pattern::new(glob).map_err(|e| anyhow!("{option}: Bad pattern {glob:?}: {e}")))
+                   .collect::<Result<_>>()?))
+    }
+
+    fn matches(&self, path: &Path, base: &Path) -> bool {
+        let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+        let relative = path.strip_prefix(base).unwrap_or(path);
+        self.0.iter().any(|glob| match glob.as_str().contains('/') {
+            true  => relative.ancestors().any(|p| glob.matches_path_with(p, options)),
+            false => relative.iter().any(|name| glob.matches_path_with(Path::new(name), options)),
+        })
+    }
+}
+
+/// "B", "kB", "MiB" and so on (any case, so "mb" too), and "k", "m" and "g" on their own.
+fn size_unit(unit: &str) -> Option<f64> {
+    Some(match unit.trim().to_lowercase().as_str() {
+        "b" => 1.0,
+        "k" | "kb" => 1e3, "kib" => 1024.0,
+        "m" | "mb" => 1e6, "mib" => 1024.0 * 1024.0,
+        "g" | "gb" => 1e9, "gib" => 1024.0 * 1024.0 * 1024.0,
+        "t" | "tb" => 1e12, "tib" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => None?,
+    })
+}
+
+/// For --free: a number of bytes, with a unit or without.
+fn parse_size(s: &str) -> Option<u64> {
+    let split = s.trim().find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.trim().len());
+    let (num, unit) = s.trim().split_at(split);
+    let num: f64 = num.parse().ok()?;
+    let size = num * if unit.trim().is_empty() { 1.0 } else { size_unit(unit)? };
+    (size.is_finite() && size > 0.0).then_some(size.round() as u64)
+}
+
+/// For --throttle. Rather than pausing after each item, this works out when we'd be back under the limit overall,
+/// so a slow stretch (a big directory taking a while to read, say) doesn't make us go even slower after it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Throttle {
+    Items(f64), // per second
+    Bytes(f64), // per second
+}
+
+impl Throttle {
+    fn parse(s: &str) -> Result<Throttle> {
+        let rate = s.trim().strip_suffix("/s").unwrap_or(s.trim());
+        let split = rate.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rate.len());
+        let (num, unit) = rate.split_at(split);
+        let num: f64 = num.parse().map_err(|_| anyhow!("Bad --throttle {s:?}"))?;
+        if !(num > 0.0 && num.is_finite()) { Err(anyhow!("Bad --throttle {s:?}: should be more than 0"))? }
+        if unit.trim().is_empty() { return Ok(Throttle::Items(num)) }
+        let multiplier = size_unit(unit)
+            .ok_or_else(|| anyhow!("Bad --throttle {s:?}: should be a number of files (eg: 500/s) or bytes (eg: 20MB/s)"))?;
+        Ok(Throttle::Bytes(num * multiplier))
+    }
+
+    /// How long to wait so that what's been `done` in `elapsed` works out to no more than the limit.
+    fn wait(&self, done: Stats, elapsed: Duration) -> Duration {
+        let should_take = match *self {
+            Throttle::Items(rate) => (done.unlinked() + done.dirs) as f64 / rate,
+            Throttle::Bytes(rate) => done.bytes as f64 / rate,
+        };
+        Duration::from_secs_f64(should_take).saturating_sub(elapsed)
+    }
+
+    /// The rate so far, in the same units as the limit.
+    fn show(&self, done: Stats, elapsed: Duration) -> String {
+        let secs = elapsed.as_secs_f64().max(0.001);
+        match *self {
+            Throttle::Items(_) => format!("{:.0} files/s", (done.unlinked() + done.dirs) as f64 / secs),
+            Throttle::Bytes(_) => format!("{}/s", Size((done.bytes as f64 / secs) as u64)),
+        }
+    }
+}
+
+/// The path exactly as the filesystem has it, so it can be fed back to other tools.
+fn raw_path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    #[cfg(unix)]
+    return Cow::Borrowed(std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()));
+    #[cfg(not(unix))]
+    return Cow::Owned(path.to_string_lossy().into_owned().into_bytes());
+}
+
+/// The deepest directory that all the `paths` are in (not counting the paths themselves), as an absolute path.
+fn common_parent(paths: &[PathBuf]) -> Result<PathBuf> {
+    let mut parents = paths.iter().map(|p| std::path::absolute(p).map(|p| p.parent().map(|p| p.to_owned()).unwrap_or(p)));
+    let first = parents.next().transpose()?.unwrap_or_default();
+    parents.try_fold(first, |common, p| -> Result<PathBuf> {
+        Ok(common.components().zip(p?.components()).take_while(|(a, b)| a == b).map(|(a, _)| a).collect())
+    })
+}
+
+/// For --manifest. One path per line, optionally followed by a tab and the size it should be. Paths are bytes, like
+/// everywhere else, so anything but a newline can be in one. Blank lines are skipped.
+fn read_manifest(file: &Path) -> Result<Vec<(PathBuf, Option<u64>)>> {
+    let manifest = std::fs::read(file).map_err(|e| anyhow!("{file:?}: Couldn't read the manifest: {e}"))?;
+    Ok(manifest.split(|&b| b == b'\n').filter(|line| !line.is_empty()).map(|line| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let size = line.iter().rposition(|&b| b == b'\t')
+                       .and_then(|tab| Some((tab, std::str::from_utf8(&line[tab+1..]).ok()?.parse().ok()?)));
+        match size {
+            Some((tab, size)) => (path_from_raw_bytes(&line[..tab]), Some(size)),
+            None => (path_from_raw_bytes(line), None),
+        }
+    }).collect())
+}
+
+/// For --by-inode: "<dir>:<inode>" to the names of whatever has that inode in <dir>, for names that can't be typed
+/// (control characters, or garbage left by filesystem corruption). Only <dir> itself is searched. Hard links in the
+/// same directory all count, since they're all the same file.
+#[cfg(unix)]
+fn named_by_inode(spec: &str) -> Result<Vec<PathBuf>> {
+    use std::os::unix::fs::DirEntryExt;
+    let (dir, inode) = spec.rsplit_once(':').ok_or_else(|| anyhow!("--by-inode: Expected <dir>:<inode>, not {spec:?}"))?;
+    let inode: u64 = inode.parse().map_err(|_| anyhow!("--by-inode: Bad inode number {inode:?}"))?;
+    let dir = Path::new(if dir.is_empty() { "." } else { dir });
+    let mut found = vec![];
+    for dirent in read_dir(dir).map_err(|e| anyhow!("--by-inode: {dir:?}: {e}"))? {
+        let dirent = dirent.map_err(|e| anyhow!("--by-inode: {dir:?}: {e}"))?;
+        if dirent.ino() == inode { found.push(dirent.path()) }
+    }
+    if found.is_empty() {
+        Err(anyhow!("--by-inode: Nothing in {dir:?} has inode {inode}"))?
+    }
+    Ok(found)
+}
+
+#[cfg(not(unix))]
+fn named_by_inode(_spec: &str) -> Result<Vec<PathBuf>> {
+    Err(anyhow!("--by-inode is only supported on unix"))
+}
+
+/// For --sort=random. Each directory gets its own shuffle, seeded from --seed and the directory's path, so a
+/// directory is shuffled the same way every time with the same seed no matter what else is going on. Not remotely
+/// cryptographic; this only has to look random.
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    // splitmix64
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let z = (state ^ (state >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        let z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    for i in (1..items.len()).rev() { // Fisher-Yates
+        items.swap(i, (next() % (i as u64 + 1)) as usize);
+    }
+}
+
+/// A hash that's the same in every build (unlike std's, which doesn't promise that).
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Whether anything is mounted somewhere inside the `paths`. Only then does the finder have to stat every directory
+/// to notice it's about to cross onto another filesystem, which would otherwise undo the point of --sort=none
+/// skipping those stats. None when there's no mount table to look at (or a <path> can't be resolved), and then it
+/// has to check everything. Something mounted after we start isn't noticed, but that's a race anyway.
+#[cfg(target_os = "linux")]
+fn mounts_inside(paths: &[PathBuf]) -> Option<bool> {
+    let mountinfo = std::fs::read("/proc/self/mountinfo").ok()?;
+    let paths = paths.iter().map(|path| path.canonicalize()).collect::<Result<Vec<_>, _>>().ok()?;
+    Some(mount_points(&mountinfo).iter().any(|mount| paths.iter().any(|path| mount.starts_with(path) && mount != path)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mounts_inside(_paths: &[PathBuf]) -> Option<bool> {
+    None
+}
+
+/// The mount points (the 5th field) in /proc/self/mountinfo. Spaces and such in them are written as octal escapes.
+#[cfg(target_os = "linux")]
+fn mount_points(mountinfo: &[u8]) -> Vec<PathBuf> {
+    mountinfo.split(|&b| b == b'\n').filter_map(|line| line.split(|&b| b == b' ').nth(4)).map(|field| {
+        let mut path = Vec::with_capacity(field.len());
+        let mut i = 0;
+        while i < field.len() {
+            match field[i..] {
+                [b'\\', a @ b'0'..=b'3', b @ b'0'..=b'7', c @ b'0'..=b'7', ..] => {
+                    path.push((a - b'0') * 64 + (b - b'0') * 8 + (c - b'0'));
+                    i += 4;
+                },
+                _ => { path.push(field[i]); i += 1 },
+            }
+        }
+        path_from_raw_bytes(&path)
+    }).collect()
+}
+
+/// The other direction of [`raw_path_bytes`].
+fn path_from_raw_bytes(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    return PathBuf::from(<std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(bytes));
+    #[cfg(not(unix))]
+    return PathBuf::from(String::from_utf8_lossy(bytes).into_owned());
+}
+
+/// An io::Error with what was being done in front of it, like `anyhow!("{what}: {err}")` but still with the errno
+/// underneath for --report-errors-json.
+#[derive(Debug)]
+struct OsError {
+    what: String,
+    err:  std::io::Error,
+}
+
+impl std::fmt::Display for OsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.what, self.err)
+    }
+}
+
+impl std::error::Error for OsError {}
+
+fn os_error(what: impl Into<String>, err: std::io::Error) -> anyhow::Error {
+    anyhow::Error::new(OsError { what: what.into(), err })
+}
+
+/// `os_error()` for something that's already an anyhow::Error (which might not have an errno at all).
+fn prefixed(what: &str, err: anyhow::Error) -> anyhow::Error {
+    match err.downcast::<std::io::Error>() {
+        Ok(err) => os_error(what, err),
+        Err(err) => anyhow!("{what}: {err}"),
+    }
+}
+
+fn errno(err: &anyhow::Error) -> Option<i32> {
+    err.downcast_ref::<OsError>().map(|e| &e.err).or_else(|| err.downcast_ref::<std::io::Error>())?.raw_os_error()
+}
+
+/// `"<path>": <error>`, or for --report-errors-json, `{"path":"<path>","error":"<error>","errno":<errno>}` (`null`
+/// when it didn't come from the OS). JSON can't hold a path that isn't UTF-8, so that gets the replacement character.
+fn error_line(path: &Path, err: &anyhow::Error, json: bool) -> Vec<u8> {
+    if !json {
+        return format!("{path:?}: {err}\n").into_bytes();
+    }
+    format!("{{\"path\":{},\"error\":{},\"errno\":{}}}\n", json_string(&path.to_string_lossy()),
+            json_string(&err.to_string()), errno(err).map_or("null".to_owned(), |errno| errno.to_string())).into_bytes()
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"'  => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Errors go to stderr, unless --report-errors-json asked for stdout.
+fn report_error(reporter: &dyn Reporter, json: Option<Stream>, line: &[u8]) {
+    match json {
+        Some(Stream::Stdout) => reporter.suspend(&mut || { let mut stdout = std::io::stdout().lock();
+                                                           _ = stdout.write_all(line);
+                                                           _ = stdout.flush() }),
+        _ => reporter.error(line),
+    }
+}
+
+/// --list-errors and --errors-to. The file gets just the errors so it's easy to feed to something else, and it's
+/// written even when there weren't any so there's never a stale one lying around from last time.
+fn write_error_list(errors: &[Vec<u8>], file: Option<&Path>) -> Result<()> {
+    match file {
+        Some(file) => std::fs::write(file, errors.concat()).map_err(|e| anyhow!("{file:?}: Couldn't write the errors: {e}"))?,
+        None if errors.is_empty() => {},
+        None => {
+            let mut stderr = std::io::stderr().lock();
+            _ = writeln!(stderr, "Errors ({}):", errors.len());
+            errors.iter().for_each(|line| _ = stderr.write_all(line));
+        },
+    }
+    Ok(())
+}
+
+/// `Ok(true)` means it couldn't be deleted but --trash-on-fail got it out of the way. If the trash doesn't work either
+/// the original error is the interesting one, so it comes first.
+fn remove(remove: impl FnOnce(&Path) -> std::io::Result<()>, path: &Path, is_dir: bool, trash_on_fail: bool)
+          -> Result<bool> {
+    let Err(e) = remove(path) else { return Ok(false) };
+    if let Some(changed) = changed_type(path, is_dir) {
+        return Err(anyhow!(changed)); // Not something to put in the trash either.
+    }
+    if !trash_on_fail {
+        return Err(e.into());
+    }
+    match trash::delete(path) {
+        Ok(()) => Ok(true),
+        Err(trash_err) => Err(anyhow!("{e} (and moving it to the trash didn't work either: {trash_err})")),
+    }
+}
+
+/// The finder runs ahead of the deleting, so something else can swap a file for a directory (or the other way
+/// around) in between. Whatever is there now hasn't been looked at (or asked about), so it's not safe to delete it
+/// instead. This is for explaining the failure that results.
+fn changed_type(path: &Path, was_dir: bool) -> Option<&'static str> {
+    match (was_dir, symlink_metadata(path).ok()?.is_dir()) {
+        (false, true) => Some("Changed from a file to a directory since it was found, leaving it alone"),
+        (true, false) => Some("Changed from a directory to a file since it was found, leaving it alone"),
+        _ => None,
+    }
+}
+
+/// A path too long for the kernel to take (past PATH_MAX it's ENAMETOOLONG even though every component is fine, which a
+/// deep enough tree gets to): the directory it's in, opened a PATH_MAX sized piece at a time with openat(), and its
+/// name in there. The *at() calls take it from there, which works on every unix.
+#[cfg(unix)]
+struct LongPath {
+    dir:  std::os::fd::OwnedFd,
+    name: std::ffi::CString,
+}
+
+#[cfg(unix)]
+impl LongPath {
+    const MAX: usize = libc::PATH_MAX as usize - 1; // Not counting the NUL.
+
+    // For the directories on the way down, which only need to be gone through.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SEARCH: libc::c_int = libc::O_PATH;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SEARCH: libc::c_int = libc::O_RDONLY;
+
+    /// None if `path` is short enough to use as it is.
+    fn new(path: &Path) -> std::io::Result<Option<LongPath>> {
+        use std::os::unix::ffi::OsStrExt;
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else { return Ok(None) };
+        if path.as_os_str().len() <= Self::MAX { return Ok(None) }
+        let (mut dir, mut piece) = (None, PathBuf::new());
+        for component in parent.components() {
+            if piece.as_os_str().len() + 1 + component.as_os_str().len() > Self::MAX {
+                dir = Some(Self::openat(dir.as_ref(), piece.as_os_str().as_bytes(), Self::SEARCH | libc::O_DIRECTORY)?);
+                piece = PathBuf::new();
+            }
+            piece.push(component);
+        }
+        let dir = Self::openat(dir.as_ref(), piece.as_os_str().as_bytes(), Self::SEARCH | libc::O_DIRECTORY)?;
+        Ok(Some(LongPath { dir, name: std::ffi::CString::new(name.as_bytes())? }))
+    }
 
-impl<'a> Find<'a> {
-    fn new(tx: &'a SyncSender<ToDelete>, interactive: Interactive) -> Find<'a> {
-        Find { tx, interactive }
+    fn openat(dir: Option<&std::os::fd::OwnedFd>, name: &[u8], flags: libc::c_int) -> std::io::Result<std::os::fd::OwnedFd> {
+        use std::os::fd::{AsRawFd, FromRawFd};
+        let name = std::ffi::CString::new(name)?;
+        let fd = unsafe { libc::openat(dir.map_or(libc::AT_FDCWD, |dir| dir.as_raw_fd()), name.as_ptr(), flags | libc::O_CLOEXEC) };
+        if fd < 0 { return Err(std::io::Error::last_os_error()) }
+        Ok(unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) })
     }
 
-    fn find(&mut self, path: PathBuf) -> FindResult<bool> {
-        let meta = (&path).symlink_metadata().map_err(|e| (path.clone(), anyhow!("stat: {e}")))?;
-        fn channel_closed(e: std::sync::mpsc::SendError<ToDelete>) -> (PathBuf, anyhow::Error) {
-            (e.0.path(), anyhow!("finder tx channel was closed"))
+    fn stat(&self, flags: libc::c_int) -> std::io::Result<libc::stat> {
+        use std::os::fd::AsRawFd;
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstatat(self.dir.as_raw_fd(), self.name.as_ptr(), &mut stat, flags) } != 0 {
+            return Err(std::io::Error::last_os_error())
         }
+        Ok(stat)
+    }
 
-        if self.interactive.ask(&path, &meta, true)? == Directive::Skip { return Ok(true) }
-
-        if meta.is_dir() {
-            let mut skipped_any = false;
-            for dirent in Self::readdir_sorted(&path, &meta)? {
-                match self.find(dirent?) {
-                    Err((path, err)) => self.tx.send(ToDelete::Err { path, err }).map_err(channel_closed)?,
-                    Ok(true) => skipped_any = true,
-                    Ok(false) => {},
-                }
+    /// std's Metadata can only come from a path or an open file, so this opens it (relative to the directory) and
+    /// fstat()s that. O_PATH gets anything at all without following it or needing to be able to read it. Elsewhere
+    /// it has to be readable, and only macOS (with O_SYMLINK) can open a symlink itself, so those are errors down here.
+    /// Devices and the like aren't opened at all, since that can do things (rewinding a tape, say).
+    fn symlink_metadata(&self) -> std::io::Result<Metadata> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let flags = libc::O_PATH | libc::O_NOFOLLOW;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let flags = {
+            let kind = self.stat(libc::AT_SYMLINK_NOFOLLOW)?.st_mode & libc::S_IFMT;
+            if ![libc::S_IFREG, libc::S_IFDIR, libc::S_IFLNK].contains(&kind) {
+                Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Too far down to look at anything but files and directories"))?
             }
+            #[cfg(target_vendor = "apple")] { libc::O_RDONLY | libc::O_NONBLOCK | libc::O_SYMLINK }
+            #[cfg(not(target_vendor = "apple"))] { libc::O_RDONLY | libc::O_NONBLOCK | libc::O_NOFOLLOW }
+        };
+        std::fs::File::from(Self::openat(Some(&self.dir), self.name.as_bytes(), flags)?).metadata()
+    }
 
-            if skipped_any { return Ok(true) } // Directory is not empty so don't bother asking or trying to delete it.
-            if self.interactive.ask(&path, &meta, true)? == Directive::Skip { return Ok(true) }
-
-            TOTAL.dirs.fetch_add(1, Ordering::Relaxed);
-            self.tx.send(ToDelete::Dir(path)).map_err(channel_closed)?;
-        } else { // symlinks are more or less just files
-            let bytes = meta.len();
-            self.tx.send(ToDelete::File { path, size: bytes }).map_err(channel_closed)?;
-            TOTAL.files.fetch_add(1, Ordering::Relaxed);
-            TOTAL.bytes.fetch_add(bytes, Ordering::Relaxed);
+    fn unlink(&self, is_dir: bool) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+        if unsafe { libc::unlinkat(self.dir.as_raw_fd(), self.name.as_ptr(), if is_dir { libc::AT_REMOVEDIR } else { 0 }) } != 0 {
+            return Err(std::io::Error::last_os_error())
         }
-        Ok(false)
+        Ok(())
     }
 
-    fn readdir_sorted<'p>(path: &'p Path, meta: &Metadata) -> FindResult<Box<dyn Iterator<Item=FindResult<PathBuf>> + 'p>> {
-        let ctx = |e| (path.to_owned(), anyhow!("read_dir: {e}"));
+    fn read_dir(&self) -> std::io::Result<DirAt> {
+        use std::os::fd::IntoRawFd;
+        let fd = Self::openat(Some(&self.dir), self.name.as_bytes(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW)?;
+        let dir = unsafe { libc::fdopendir(fd.into_raw_fd()) }; // Which closedir() closes.
+        if dir.is_null() { return Err(std::io::Error::last_os_error()) }
+        Ok(DirAt(dir))
+    }
+}
 
-        // Sort the entries so the user can tell how far we've gotten even if the progress bar isn't
-        // going. However, don't waste time and memory sorting directories that are massive. If you've ever
-        // done "ls" in a directory and had it take multiple seconds before printing anything you know what
-        // we're trying to avoid here. The cutoff point is somewhat arbitrary. We want it high enough that
-        // most things get sorted but low enough that the time and memory spent reading the entries and
-        // sorting is negligible.
-        //
-        // If the we're unix we can get the number of directory entries quickly from the nlink stat field. If
-        // we're not, then don't bother sorting.
-        #[cfg(unix)] use std::os::unix::fs::MetadataExt;
-        #[cfg(unix)] let nlink = meta.nlink() as usize;
-        #[cfg(not(unix))] let nlink = 5000_usize;
-        if nlink < 5000 {
-            let mut dirents = Vec::with_capacity(nlink); // oversized by 2 (., ..) but who cares.
+/// readdir() on a directory from [`LongPath::read_dir`]. No file types, since std's can't be made from a d_type, so
+/// everything in here gets stat()ed.
+#[cfg(unix)]
+struct DirAt(*mut libc::DIR);
 
-            for f in read_dir(&path).map_err(ctx)? {
-                dirents.push(f.map_err(ctx)?.path());
-            }
-            dirents.sort();
+#[cfg(unix)]
+impl Iterator for DirAt {
+    type Item = std::io::Result<std::ffi::OsString>;
 
-            return Ok(Box::new(dirents.into_iter().map(|ent| Ok(ent))));
-        } else {
-            return Ok(Box::new(read_dir(&path).map_err(ctx)?
-                                              .map(|res_de| res_de.map(|de| de.path())
-                                                                  .map_err(|e| (path.to_owned(), anyhow!(e))))))
+    fn next(&mut self) -> Option<Self::Item> {
+        use std::os::unix::ffi::OsStrExt;
+        loop {
+            // NULL is both the end and an error, and only errno says which.
+            let know = clear_errno();
+            let entry = unsafe { libc::readdir(self.0) };
+            if entry.is_null() {
+                let err = std::io::Error::last_os_error();
+                return (know && err.raw_os_error() != Some(0)).then_some(Err(err))
+            }
+            let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) };
+            if name.to_bytes() == b"." || name.to_bytes() == b".." { continue }
+            return Some(Ok(std::ffi::OsStr::from_bytes(name.to_bytes()).to_owned()))
         }
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
-pub enum Response {
-    Delete,
-    Skip,
-    DeleteFromNowOn,
-    Quit,
-    DeleteThisDir(PathBuf),
-    SkipThisDir(PathBuf),
+#[cfg(unix)]
+impl Drop for DirAt {
+    fn drop(&mut self) {
+        unsafe { libc::closedir(self.0) };
+    }
 }
 
-#[derive(PartialEq, Eq, Debug)]
-pub enum Directive {
-    Delete,
-    Skip,
+/// Sets errno to 0, or says it can't (there's no portable way to get at it).
+#[cfg(unix)]
+fn clear_errno() -> bool {
+    #[cfg(any(target_os = "linux", target_os = "dragonfly"))]
+    unsafe { *libc::__errno_location() = 0 };
+    #[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+    unsafe { *libc::__errno() = 0 };
+    #[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+    unsafe { *libc::__error() = 0 };
+    cfg!(any(target_os = "linux", target_os = "android", target_os = "netbsd", target_os = "openbsd",
+             target_vendor = "apple", target_os = "freebsd", target_os = "dragonfly"))
 }
 
-pub struct Interactive {
-    enable: bool,
-    state: Option<Response>,
-    ask_ctx: AskerContext,
+/// `path.symlink_metadata()`, even past PATH_MAX.
+fn symlink_metadata(path: &Path) -> std::io::Result<Metadata> {
+    #[cfg(unix)]
+    if let Some(long) = LongPath::new(path)? { return long.symlink_metadata() }
+    path.symlink_metadata()
 }
 
-type Asker<'a> = &'a (dyn Fn() -> Result<Response> + 'a);
-type AskerContext = Box<dyn Fn(Asker<'_>) -> Result<Response>>;
+/// Whether `path` leads anywhere, following symlinks, even past PATH_MAX.
+fn resolves(path: &Path) -> bool {
+    #[cfg(unix)]
+    if let Ok(Some(long)) = LongPath::new(path) { return long.stat(0).is_ok() }
+    path.metadata().is_ok()
+}
 
-impl Interactive {
-    pub fn new<F>(enable: bool, ask_ctx: F) -> Interactive
-    where F: Fn(Asker) -> Result<Response> + 'static,
-    {
-        Interactive {
-            enable,
-            ask_ctx: Box::new(ask_ctx),
-            state: None,
-        }
-    }
+/// remove_file() or remove_dir(), even past PATH_MAX.
+fn unlink(path: &Path, is_dir: bool) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Some(long) = LongPath::new(path)? { return long.unlink(is_dir) }
+    if is_dir { remove_dir(path) } else { remove_file(path) }
+}
 
-    /// Called between args to reset the state of DeleteThisDir or SkipThisDir back to None. The only other
-    /// states that it could be are DeleteFromNowOn and Quit. Neither of these will be reset.
-    ///
-    /// The reasoning is that the args are separate and it might be confusing for "everything in this dir" to
-    /// also mean some argument down the line that we haven't processed yet.
-    pub fn reset_state(&mut self) {
-        match self.state {
-            Some(Response::Quit) |
-            Some(Response::DeleteFromNowOn) => {},
-            _ => { self.state = None },
+type DirName = (std::ffi::OsString, Option<FileType>);
+
+/// The names in a directory, and their types when it's cheap to say, even past PATH_MAX.
+fn list_dir(path: &Path) -> std::io::Result<Box<dyn Iterator<Item=std::io::Result<DirName>>>> {
+    #[cfg(unix)]
+    if let Some(long) = LongPath::new(path)? { return Ok(Box::new(long.read_dir()?.map(|name| name.map(|name| (name, None))))) }
+    Ok(Box::new(read_dir(path)?.map(|entry| entry.map(|entry| (entry.file_name(), entry.file_type().ok())))))
+}
+
+/// For everything else that takes a path: calls `f` with `path`, or on Linux with something shorter that leads to the
+/// same place if `path` is too long, using /proc/self/fd to get into the directory from a [`LongPath`]. Without /proc
+/// (or off Linux) it gets the whole thing and fails.
+fn via_short_path<T>(path: &Path, f: impl FnOnce(&Path) -> std::io::Result<T>) -> std::io::Result<T> {
+    #[cfg(target_os = "linux")] {
+        use std::os::fd::AsRawFd;
+        if let Some(long) = LongPath::new(path)? {
+            let proc = Path::new("/proc/self/fd").join(long.dir.as_raw_fd().to_string());
+            if proc.exists() { return f(&proc.join(path.file_name().unwrap_or_default())) }
         }
     }
+    f(path)
+}
 
-    pub fn ask(&mut self, path: &Path, meta: &Metadata, traverse: bool) -> FindResult<Directive> {
-        if self.enable {
-            match &self.state {
-                Some(Response::DeleteFromNowOn)                                    => { return Ok(Directive::Delete) },
-                Some(Response::DeleteThisDir(ref skip)) if is_same_dir(skip, path) => { return Ok(Directive::Delete) },
-                Some(Response::SkipThisDir(ref skip))   if is_same_dir(skip, path) => { return Ok(Directive::Skip) },
-                Some(Response::Quit)                                               => { return Ok(Directive::Skip) },
-                _ => {
-                    match (self.ask_ctx)(&|| self.ask_user(&path, &meta, traverse)).map_err(|e| (path.to_owned(), anyhow!(e)))? {
-                        Response::Delete             => { return Ok(Directive::Delete) },
-                        Response::Skip               => { return Ok(Directive::Skip) },
-                        d@Response::DeleteFromNowOn |
-                        d@Response::DeleteThisDir(_) =>  { self.state = Some(d); return Ok(Directive::Delete) },
-                        d@Response::Quit            |
-                        d@Response::SkipThisDir(_)   =>  { self.state = Some(d); return Ok(Directive::Skip) },
-                    }
-                }
-            }
-        }
-        Ok(Directive::Delete)
+/// The directory `path` is in, with "." for a plain name (whose parent is "").
+fn parent_dir(path: &Path) -> Option<&Path> {
+    match path.parent() {
+        Some(parent) if parent == Path::new("") => Some(Path::new(".")),
+        parent => parent,
     }
+}
 
+fn device(meta: &Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    return Some(std::os::unix::fs::MetadataExt::dev(meta));
+    #[cfg(not(unix))]
+    return { _ = meta; None };
+}
 
-  fn ask_user(&self, path: &Path, meta: &Metadata, traverse: bool) -> Result<Response> {
-    let (path, prompt) = match (meta.is_dir(), traverse) {
-        (false, _) => {
-            #[cfg(unix)]
-            use std::os::unix::fs::FileTypeExt;
-            let ft = meta.file_type();
-            (path,
-             if ft.is_file() && meta.len() == 0 {
-                 format!("remove empty file {path:?}")
-             } else if ft.is_file() {
-                 format!("remove file {path:?} [{}]", HumanBytes(meta.len()))
-             } else if ft.is_symlink() {
-                 format!("remove symbolic link {path:?}")
-             } else {
-                 #[cfg(unix)]
-                 if ft.is_fifo() {
-                     format!("remove fifo {path:?}")
-                 } else if ft.is_socket() {
-                     format!("remove socket {path:?}")
-                 } else if ft.is_char_device() {
-                     format!("remove character device {path:?}")
-                 } else if ft.is_block_device() {
-                     format!("remove block device {path:?}")
-                 } else {
-                     format!("remove unknown file {path:?}") // can't happen?
-                 }
+/// What deleting the file frees: its length, or without `apparent`, the blocks it has on disk (like `du`), which is
+/// less for a sparse file and can be more for a small one.
+fn size_of(meta: &Metadata, apparent: bool) -> u64 {
+    #[cfg(unix)]
+    if !apparent { return std::os::unix::fs::MetadataExt::blocks(meta) * 512 }
+    _ = apparent;
+    meta.len()
+}
 
-                 #[cfg(not(unix))]
-                 format!("remove {path:?}")
-             })
-        }
-        (true, true) => (path, format!("descend into directory {path:?}")),
-        (true, false) => (path, format!("remove directory {path:?}")),
-    };
-    loop {
-        print!("{}? (y/N/a/q/d/s/?) ", prompt);
-        _ = std::io::stdout().flush();
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input)?;
-        if !std::io::stdout().is_terminal() { print!("\n") } // hack to make tests easier
-        match input.to_lowercase().trim() {
-            "y" => return Ok(Response::Delete),
-            ""  | /* default */
-            "n" => return Ok(Response::Skip),
-            "a" => return Ok(Response::DeleteFromNowOn),
-            "q" => return Ok(Response::Quit),
-            "d" => return Ok(Response::DeleteThisDir(path.to_owned())),
-            "s" => return Ok(Response::SkipThisDir(path.to_owned())),
-            "?" => println!("y - Yes, delete it\n\
-                             n - No, don't delete it\n\
-                             a - Delete this and everything else (without any further prompts)\n\
-                             q - Quit without deleting this nor anything else\n\
-                             d - Delete this and the rest of its directory without further prompts\n\
-                             s - Don't delete this or anything else in its directory, but continue asking about other items\n\
-                             ? - Show help"),
-            _ => println!("Bad input. Enter \"?\" for help"),
-        }
-    }
-  }
+fn is_symlink_to_dir(path: &Path) -> bool {
+    path.symlink_metadata().is_ok_and(|m| m.is_symlink()) && path.metadata().is_ok_and(|m| m.is_dir())
 }
 
+fn is_broken_symlink(path: &Path, meta: &Metadata) -> bool {
+    meta.file_type().is_symlink() && !resolves(path)
+}
+
+/// Overlayfs marks a file deleted from a lower layer with a 0/0 character device in the upper one.
+#[cfg(target_os = "linux")]
+fn is_whiteout(meta: &Metadata) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_whiteout(_meta: &Metadata) -> bool { false }
+
 fn is_same_dir(p1: &Path, p2: &Path) -> bool {
     match (p1.parent(), p2) {
         (Some(dir), path) => {
@@ -399,10 +4321,20 @@ struct Validator {
     root_inode: Option<u64>,
     root_device: Option<u64>,
     preserve_all_roots: bool,
+    precious: Vec<(PathBuf, u64, u64)>, // (path, device, inode)
+    protect_from: Option<Arc<Protected>>,
+    cwd: Vec<(u64, u64)>, // (device, inode) of the current directory and everything above it
+    exe: Vec<(u64, u64)>, // The same for our own executable
+    check_readonly: bool,
 }
 
 impl Validator {
-    fn new(preserve_root: bool, preserve_all_roots: bool) -> Result<Validator> {
+    fn new(preserve_root: bool, preserve_all_roots: bool, preserve_cwd: bool, preserve_self: bool, check_readonly: bool,
+           precious: &str, protect_from: Option<Arc<Protected>>) -> Result<Validator> {
+        let precious = Self::precious_ids(precious);
+        let cwd = if preserve_cwd { Self::cwd_ids()? } else { vec![] };
+        let exe = if preserve_self { Self::exe_ids() } else { vec![] };
+
         #[cfg(unix)]
         if preserve_root {
             use std::os::unix::fs::MetadataExt;
@@ -411,16 +4343,115 @@ impl Validator {
                 root_inode: Some(m.ino()),
                 root_device: Some(m.dev()),
                 preserve_all_roots,
+                precious,
+                protect_from,
+                cwd,
+                exe,
+                check_readonly,
             })
         }
 
-        return Ok(Validator {
+        Ok(Validator {
             root_inode: None,
             root_device: None,
             preserve_all_roots,
+            precious,
+            protect_from,
+            cwd,
+            exe,
+            check_readonly,
         })
     }
 
+    // "/" contains everything, but whether it can be deleted is up to --no-preserve-root, not us.
+    #[cfg(unix)]
+    fn cwd_ids() -> Result<Vec<(u64, u64)>> {
+        use std::os::unix::fs::MetadataExt;
+        let cwd = std::env::current_dir().map_err(|e| anyhow!("Couldn't get the current directory: {e}"))?;
+        Ok(cwd.ancestors().filter(|dir| dir.parent().is_some())
+                          .filter_map(|dir| dir.symlink_metadata().ok())
+                          .map(|m| (m.dev(), m.ino()))
+                          .collect())
+    }
+
+    #[cfg(not(unix))]
+    fn cwd_ids() -> Result<Vec<(u64, u64)>> {
+        Ok(vec![])
+    }
+
+    // The executable itself and every directory it's in, except "/" (like the cwd). It's canonicalized first, since
+    // the ancestors of a symlink to it (eg: ~/bin/rm-rfp -> ../src/rm-rfp/target/release/rm-rfp) aren't where it
+    // really is. If we can't tell where we are there's nothing to check.
+    #[cfg(unix)]
+    fn exe_ids() -> Vec<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        let Ok(exe) = std::env::current_exe().and_then(|exe| exe.canonicalize()) else { return vec![] };
+        exe.ancestors().filter(|dir| dir.parent().is_some())
+                       .filter_map(|dir| dir.symlink_metadata().ok())
+                       .map(|m| (m.dev(), m.ino()))
+                       .collect()
+    }
+
+    #[cfg(not(unix))]
+    fn exe_ids() -> Vec<(u64, u64)> {
+        vec![]
+    }
+
+    // Compared by device and inode (like "/" is) so that symlinks, bind mounts, and "../.." style paths don't get
+    // around it. Directories that don't exist can't be deleted so they're just dropped.
+    #[cfg(unix)]
+    fn precious_ids(list: &str) -> Vec<(PathBuf, u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        list.split(':').filter(|dir| !dir.is_empty()).filter_map(|dir| {
+            let path = match dir.strip_prefix('~') {
+                Some(rest) => PathBuf::from(std::env::var_os("HOME")?).join(rest.trim_start_matches('/')),
+                None => PathBuf::from(dir),
+            };
+            let m = path.metadata().ok()?;
+            Some((path, m.dev(), m.ino()))
+        }).collect()
+    }
+
+    #[cfg(not(unix))]
+    fn precious_ids(_list: &str) -> Vec<(PathBuf, u64, u64)> {
+        vec![]
+    }
+
+    /// Which --precious directory `path` is, if any.
+    fn precious(&self, path: &Path) -> Option<&Path> {
+        #[cfg(unix)] {
+            use std::os::unix::fs::MetadataExt;
+            let m = path.symlink_metadata().ok()?; // A symlink to a precious dir only deletes the symlink.
+            self.precious.iter().find(|(_, dev, ino)| *dev == m.dev() && *ino == m.ino()).map(|(p, _, _)| p.as_path())
+        }
+        #[cfg(not(unix))] {
+            _ = path;
+            None
+        }
+    }
+
+    // Deleting something takes it out of the directory it's in, and deleting what's in a directory takes it out of that
+    // directory, so those are what need to be writable. Otherwise every single delete fails with EROFS.
+    #[cfg(unix)]
+    fn read_only(path: &Path, meta: &std::fs::Metadata) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = match meta.is_dir() {
+            true => path.to_owned(),
+            false => match path.parent() {
+                Some(p) if p != Path::new("") => p.to_owned(),
+                _ => PathBuf::from("."),
+            },
+        };
+        let Ok(dir) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else { return false };
+        let mut fs: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(dir.as_ptr(), &mut fs) } != 0 { return false } // Let the deletes say what's wrong.
+        fs.f_flag & libc::ST_RDONLY != 0
+    }
+
+    fn protected(&self, path: &Path) -> bool {
+        self.protect_from.as_ref().is_some_and(|protect| path.symlink_metadata().is_ok_and(|m| protect.contains(&m)))
+    }
+
     // These checks are how coreutils checks for `rm -rf` sanity.
     // --no-preserve-root appears to be POSIX, as does the ".", ".." check.
     // The preserve_all_roots check seems to be a GNU addition but seems reasonable.
@@ -441,20 +4472,22 @@ impl Validator {
             _ => {},
         }
 
+        // A directory's parent is "dir/.." (which works even if it's "." or ends in a symlink). Anything else is
+        // compared against the directory it's in, which only differs if it's been bind mounted right there.
         #[cfg(unix)]
         if self.preserve_all_roots {
-            if let Some(parent) = // This can only be None if they passed in "/", which should be caught above.
-                if m.is_dir() {
-                    Some(path.join(".."))
-                } else {
-                    path.parent().map(|p| p.join(".."))
-                }
-            {
-                let parent = parent.symlink_metadata().map_err(|e| anyhow!("{path:?}: Couldn't stat parent {parent:?}: {e}"))?;
-                if parent.dev() != m.dev() {
-                    Err(anyhow!("{path:?}: Refusing to delete because it is the root of a mounted filesystem. \
-                                 You can override with `--no-preserve-root`"))?
+            let parent = if m.is_dir() {
+                path.join("..")
+            } else {
+                match path.parent() {
+                    Some(p) if p != Path::new("") => p.to_owned(),
+                    _ => PathBuf::from("."), // plain "file" has a parent of "".
                 }
+            };
+            let parent = parent.symlink_metadata().map_err(|e| anyhow!("{path:?}: Couldn't stat parent {parent:?}: {e}"))?;
+            if parent.dev() != m.dev() {
+                Err(anyhow!("{path:?}: Refusing to delete because it is the root of a mounted filesystem. \
+                             You can override with `--no-preserve-root`"))?
             }
         }
 
@@ -462,6 +4495,26 @@ impl Validator {
             Err(anyhow!("{path:?}: Refusing to delete \"\" or \"\" directory."))?
         }
 
+        // Whoever ran us would be left sitting in a directory that doesn't exist anymore.
+        #[cfg(unix)]
+        if self.cwd.contains(&(m.dev(), m.ino())) {
+            Err(anyhow!("{path:?}: Refusing to delete the current directory (or a directory containing it). \
+                         You can override with `--allow-delete-cwd`"))?
+        }
+
+        // It keeps running fine (the inode lives on until we exit), but it's almost certainly not what was meant.
+        #[cfg(unix)]
+        if self.exe.contains(&(m.dev(), m.ino())) {
+            Err(anyhow!("{path:?}: Refusing to delete rm-rfp itself (or a directory containing it). \
+                         You can override with `--allow-delete-self`"))?
+        }
+
+        #[cfg(unix)]
+        if self.check_readonly && Self::read_only(path, &m) {
+            Err(anyhow!("{path:?}: Refusing to delete because it is on a read-only filesystem, so nothing would get deleted. \
+                         You can try anyway with `--ignore-readonly`"))?
+        }
+
         Ok(())
     }
 
@@ -476,8 +4529,458 @@ impl Validator {
         let mut comp_iter = bytes.rsplit(is_separator_byte);
         loop {
             let Some(comp) = comp_iter.next() else { return false }; // this should only happen if the whole path is separators.
-            if comp.len() == 0 { continue } // means separator bumped against start, end, or another separator. Ignore it.
+            if comp.is_empty() { continue } // means separator bumped against start, end, or another separator. Ignore it.
             return comp == b"."; // The first real component we find is the last one so check it against "."
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    // Real metadata is needed, but nothing looks at it once the asker is scripted, so any file will do.
+    fn meta() -> Metadata {
+        std::env::temp_dir().symlink_metadata().unwrap()
+    }
+
+    /// An `Interactive` that answers with `script`, in order. The returned log records every path it was asked about.
+    fn scripted(script: Vec<Response>) -> (Interactive, Rc<RefCell<Vec<PathBuf>>>) {
+        let script = RefCell::new(VecDeque::from(script));
+        let asked = Rc::new(RefCell::new(vec![]));
+        let log = asked.clone();
+        let interactive = Interactive::with_asker(true, |f| f(), move |path, _, _| {
+            log.borrow_mut().push(path.to_owned());
+            script.borrow_mut().pop_front().ok_or(anyhow!("ran out of scripted responses at {path:?}"))
+        });
+        (interactive, asked)
+    }
+
+    fn ask(interactive: &mut Interactive, path: &str) -> Directive {
+        interactive.ask(Path::new(path), &meta(), true).map_err(|(_, e)| e).unwrap()
+    }
+
+    #[test]
+    fn disabled_never_asks() {
+        let mut interactive = Interactive::with_asker(false, |f| f(), |_, _, _| panic!("shouldn't ask"));
+        assert_eq!(ask(&mut interactive, "a/b"), Directive::Delete);
+    }
+
+    #[test]
+    fn yes_and_no() {
+        let (mut interactive, asked) = scripted(vec![Response::Delete, Response::Skip]);
+        assert_eq!(ask(&mut interactive, "a"), Directive::Delete);
+        assert_eq!(ask(&mut interactive, "b"), Directive::Skip);
+        assert_eq!(*asked.borrow(), vec![PathBuf::from("a"), PathBuf::from("b")]);
+        assert_eq!(interactive.state, None);
+    }
+
+    #[test]
+    fn delete_this_dir_is_sticky_within_the_dir() {
+        let (mut interactive, asked) = scripted(vec![Response::DeleteThisDir("a/b/x".into()), Response::Skip]);
+        assert_eq!(ask(&mut interactive, "a/b/x"), Directive::Delete);
+        assert_eq!(ask(&mut interactive, "a/b/y"), Directive::Delete);
+        assert_eq!(ask(&mut interactive, "a/b/c/z"), Directive::Delete);
+        assert_eq!(ask(&mut interactive, "a/q"), Directive::Skip); // outside of a/b so this one gets asked
+        assert_eq!(*asked.borrow(), vec![PathBuf::from("a/b/x"), PathBuf::from("a/q")]);
+    }
+
+    #[test]
+    fn skip_this_dir_is_sticky_within_the_dir() {
+        let (mut interactive, asked) = scripted(vec![Response::SkipThisDir("a/b/x".into()), Response::Delete]);
+        assert_eq!(ask(&mut interactive, "a/b/x"), Directive::Skip);
+        assert_eq!(ask(&mut interactive, "a/b/c/z"), Directive::Skip);
+        assert_eq!(ask(&mut interactive, "a/c"), Directive::Delete);
+        assert_eq!(asked.borrow().len(), 2);
+    }
+
+    #[test]
+    fn delete_from_now_on_and_quit_survive_reset() {
+        let (mut interactive, asked) = scripted(vec![Response::DeleteFromNowOn]);
+        assert_eq!(ask(&mut interactive, "a"), Directive::Delete);
+        interactive.reset_state();
+        assert_eq!(ask(&mut interactive, "b/c"), Directive::Delete);
+        assert_eq!(asked.borrow().len(), 1);
+
+        let (mut interactive, asked) = scripted(vec![Response::Quit]);
+        assert_eq!(ask(&mut interactive, "a"), Directive::Skip);
+        interactive.reset_state();
+        assert_eq!(ask(&mut interactive, "b/c"), Directive::Skip);
+        assert_eq!(asked.borrow().len(), 1);
+    }
+
+    #[test]
+    fn dir_states_reset_between_args() {
+        let (mut interactive, asked) = scripted(vec![Response::DeleteThisDir("a/x".into()), Response::Skip]);
+        assert_eq!(ask(&mut interactive, "a/x"), Directive::Delete);
+        interactive.reset_state();
+        assert_eq!(interactive.state, None);
+        assert_eq!(ask(&mut interactive, "a/y"), Directive::Skip);
+        assert_eq!(asked.borrow().len(), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn kind_answers_last_the_whole_run() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        std::os::unix::fs::symlink("file", dir.path().join("link")).unwrap();
+        let [file, link] = ["file", "link"].map(|name| dir.path().join(name).symlink_metadata().unwrap());
+        let ask = |interactive: &mut Interactive, path: &str, meta: &Metadata| {
+            interactive.ask(Path::new(path), meta, true).map_err(|(_, e)| e).unwrap()
+        };
+        let (mut interactive, asked) = scripted(vec![Response::DeleteThisKind(Kind::File), Response::SkipThisKind(Kind::Dir),
+                                                     Response::SkipThisDir("c/x".into())]);
+        assert_eq!(ask(&mut interactive, "a/x", &file), Directive::Delete);
+        assert_eq!(ask(&mut interactive, "a/y", &meta()), Directive::Skip);
+        assert_eq!(ask(&mut interactive, "a/z", &file), Directive::Delete);
+        assert_eq!(ask(&mut interactive, "b/y", &meta()), Directive::Skip);
+        interactive.reset_state();
+        assert_eq!(ask(&mut interactive, "b/z", &file), Directive::Delete);
+        assert_eq!(asked.borrow().len(), 2);
+        // Answers about a directory come before the ones about kinds.
+        assert_eq!(ask(&mut interactive, "c/x", &link), Directive::Skip);
+        assert_eq!(ask(&mut interactive, "c/y", &file), Directive::Skip);
+        assert_eq!(ask(&mut interactive, "d/y", &file), Directive::Delete);
+        assert_eq!(asked.borrow().len(), 3);
+    }
+
+    #[test]
+    fn asker_errors_carry_the_path() {
+        let (mut interactive, _) = scripted(vec![]);
+        let (path, _) = interactive.ask(Path::new("a/b"), &meta(), true).unwrap_err();
+        assert_eq!(path, PathBuf::from("a/b"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn relative_display() {
+        let display = PathDisplay::new(Some(Path::new("/a/b")), false, &[]).unwrap();
+        assert_eq!(display.show(Path::new("/a/b/c/d")), "c/d");
+        assert_eq!(display.show(Path::new("/a/b")), ".");
+        assert_eq!(display.show(Path::new("/a/x")), "/a/x");
+
+        let display = PathDisplay::new(None, true, &[PathBuf::from("/a/b/c/d"), PathBuf::from("/a/b/x")]).unwrap();
+        assert_eq!(display.show(Path::new("/a/b/c/d/e")), "c/d/e");
+        assert_eq!(display.show(Path::new("/a/b/x/y")), "x/y");
+
+        let display = PathDisplay::new(None, true, &[PathBuf::from("/a/b/c")]).unwrap();
+        assert_eq!(display.show(Path::new("/a/b/c/d")), "c/d");
+
+        let display = PathDisplay::new(None, false, &[PathBuf::from("/a/b/c")]).unwrap();
+        assert_eq!(display.show(Path::new("/a/b/c/d")), "/a/b/c/d");
+    }
+
+    #[test]
+    fn defaults() {
+        let defaults = |pairs: &[(&str, &str)], argv: &str| {
+            let defaults = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            with_defaults(&usage(), defaults, argv.split(' ').map(str::to_owned).collect()).map(|argv| argv.join(" "))
+        };
+        assert_eq!(defaults(&[("dry-run", "true"), ("sort", "size")], "rm-rfp x").unwrap(), "rm-rfp --dry-run --sort=size x");
+        assert_eq!(defaults(&[("dry-run", "false"), ("sort", "size")], "rm-rfp --sort=name x").unwrap(), "rm-rfp --sort=name x");
+        assert_eq!(defaults(&[("sort", "size")], "rm-rfp --sort name x").unwrap(), "rm-rfp --sort name x");
+        assert_eq!(defaults(&[("dry-run", "yes")], "rm-rfp -in x").unwrap(), "rm-rfp -in x");
+        // The "n" here is -C's value, not -n.
+        assert_eq!(defaults(&[("dry-run", "yes")], "rm-rfp -Cn x").unwrap(), "rm-rfp --dry-run -Cn x");
+        // After "--" it's a <path>.
+        assert_eq!(defaults(&[("dry-run", "1")], "rm-rfp -- --dry-run").unwrap(), "rm-rfp --dry-run -- --dry-run");
+        assert_eq!(defaults(&[("dry-run", "1")], "rm-rfp -h").unwrap(), "rm-rfp -h");
+        assert!(defaults(&[("no-such-thing", "1")], "rm-rfp x").is_err());
+        assert!(defaults(&[("dry-run", "maybe")], "rm-rfp x").is_err());
+    }
+
+    #[test]
+    fn tree() {
+        let mut tree = TreePrinter::default();
+        let root = Path::new("top");
+        let mut lines = vec![];
+        for (path, is_dir) in [("top/a/aa", false), ("top/a/b/aa", false), ("top/a/b", true), ("top/a/bb", false),
+                               ("top/a/c/aa", false), /* top/a/c is kept */ ("top/a/d", true), ("top/a", true),
+                               ("top/zz", false), ("top", true)] {
+            lines.extend(tree.item(root, Path::new(path), is_dir));
+        }
+        assert_eq!(lines, vec!["top/", "  a/", "    aa", "    b/", "      aa", "    bb", "    c/", "      aa", "    d/", "  zz"]);
+
+        // A file argument is just itself.
+        assert_eq!(TreePrinter::default().item(Path::new("f"), Path::new("f"), false), vec!["f"]);
+    }
+
+    #[test]
+    fn overlapping_args() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+        std::fs::create_dir_all(dir.path().join("ab")).unwrap();
+        #[cfg(unix)] std::os::unix::fs::symlink("a/b", dir.path().join("link")).unwrap();
+        let paths: Vec<PathBuf> = ["a/b/c", "a", "ab", "a/b/../b", "link", "a/", "nope/x"].iter()
+            .map(|p| dir.path().join(p)).collect();
+        // "ab" only starts with "a" as a string, "link" is just a symlink, and "a/" is "a" again (the later one goes).
+        assert_eq!(overlapping(&paths), vec![(0, 1), (3, 1), (5, 1)]);
+    }
+
+    #[test]
+    fn rm_args() {
+        let argv = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let rm = |args: &[&str]| from_rm_args(&usage(), argv(args));
+        assert_eq!(rm(&["rm-rfp", "-rf", "x"]), (argv(&["rm-rfp", "-rf", "x"]), false)); // Only as rm.
+        // Repeats only go once.
+        assert_eq!(rm(&["/bin/rm", "-rf", "x", "-R", "--verbose", "-f", "--force", "--dry-run"]),
+                   (argv(&["/bin/rm", "-f", "x", "--dry-run"]), false));
+        // Nothing after -- is touched.
+        assert_eq!(rm(&["rm", "-Rvi", "--one-file-system", "--", "-rf", "-I"]), (argv(&["rm", "-i", "--", "-rf", "-I"]), false));
+        // Clusters get taken apart, and the letters that aren't rm's are kept, values and all.
+        assert_eq!(rm(&["rm", "-rfn", "x"]), (argv(&["rm", "-f", "-n", "x"]), false));
+        assert_eq!(rm(&["rm", "-rnPv", "x"]), (argv(&["rm", "-nP", "x"]), false));
+        assert_eq!(rm(&["rm", "-rCdir", "x"]), (argv(&["rm", "-Cdir", "x"]), false));
+        assert_eq!(rm(&["rm", "-rC", "-dir", "x"]), (argv(&["rm", "-C", "-dir", "x"]), false));
+        assert_eq!(rm(&["rm", "-d", "-x", "-"]), (argv(&["rm", "-d", "-x", "-"]), false));
+        // The last of -f, -i, -I, and --interactive wins.
+        assert_eq!(rm(&["rm", "-rI", "x"]), (argv(&["rm", "x"]), true));
+        assert_eq!(rm(&["rm", "-Ii", "x"]), (argv(&["rm", "-i", "x"]), false));
+        assert_eq!(rm(&["rm", "-if", "x"]), (argv(&["rm", "-f", "x"]), false));
+        assert_eq!(rm(&["rm", "-f", "--interactive=once", "x"]), (argv(&["rm", "-f", "x"]), true));
+        assert_eq!(rm(&["rm", "-i", "--interactive=never", "x"]), (argv(&["rm", "x"]), false));
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(expand_braces("plain"), vec!["plain"]);
+        assert_eq!(expand_braces("*.{log,tmp}"), vec!["*.log", "*.tmp"]);
+        assert_eq!(expand_braces("a{b,c{d,e}}f"), vec!["abf", "acdf", "acef"]);
+        assert_eq!(expand_braces("{a,b}{c,d}"), vec!["ac", "ad", "bc", "bd"]);
+        assert_eq!(expand_braces("a{,b}"), vec!["a", "ab"]);
+        assert_eq!(expand_braces("a{b"), vec!["a{b"]);
+        assert_eq!(expand_braces("{a}{b,c}"), vec!["{a}b", "{a}c"]);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::from_secs(5400));
+        assert!(parse_duration("5 fortnights").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn summary_format() {
+        let done = Stats { files: 9, dirs: 4, bytes: 2048, symlinks: 0 };
+        let field = |name: &str| summary_field(name, done, done, 1, Duration::from_millis(1500));
+        assert_eq!(render_summary("{files},{dirs},{bytes},{errors},{elapsed}", field).unwrap(), "9,4,2048,1,1.500");
+        assert_eq!(render_summary("freed {size} of {total_size}", field).unwrap(), "freed 2.00 KiB of 2.00 KiB");
+        assert_eq!(render_summary("{{files}} {files}", field).unwrap(), "{files} 9");
+        assert_eq!(render_summary("no placeholders", field).unwrap(), "no placeholders");
+        assert!(render_summary("{file}", field).unwrap_err().to_string().contains("Unknown placeholder {file}"));
+        assert!(render_summary("{files", field).is_err());
+        assert!(render_summary("files}", field).is_err());
+        // Everything it says it can have, it can.
+        assert!(SUMMARY_FIELDS.iter().all(|f| field(f).is_some()));
+    }
+
+    #[test]
+    fn throttles() {
+        assert_eq!(Throttle::parse("500/s").unwrap(), Throttle::Items(500.0));
+        assert_eq!(Throttle::parse("500").unwrap(), Throttle::Items(500.0));
+        assert_eq!(Throttle::parse("20MB/s").unwrap(), Throttle::Bytes(20e6));
+        assert_eq!(Throttle::parse("1.5 GiB/s").unwrap(), Throttle::Bytes(1.5 * 1024.0 * 1024.0 * 1024.0));
+        assert!(Throttle::parse("0/s").is_err());
+        assert!(Throttle::parse("5 parsecs/s").is_err());
+        let done = Stats { files: 90, dirs: 10, bytes: 0, symlinks: 0 };
+        assert_eq!(Throttle::Items(10.0).wait(done, Duration::from_secs(4)), Duration::from_secs(6));
+        assert_eq!(Throttle::Items(10.0).wait(done, Duration::from_secs(20)), Duration::ZERO);
+        assert_eq!(Throttle::Items(10.0).show(done, Duration::from_secs(20)), "5 files/s");
+    }
+
+    #[test]
+    fn changed_type_during_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let (file, sub) = (dir.path().join("file"), dir.path().join("sub"));
+        std::fs::write(&file, "").unwrap();
+        std::fs::create_dir(&sub).unwrap();
+        // As if the finder had found a file at "sub" and a directory at "file".
+        let err = remove(|p| std::fs::remove_file(p), &sub, false, false).unwrap_err();
+        assert!(err.to_string().starts_with("Changed from a file"), "{err}");
+        let err = remove(|p| std::fs::remove_dir(p), &file, true, false).unwrap_err();
+        assert!(err.to_string().starts_with("Changed from a directory"), "{err}");
+        assert!(std::fs::exists(&sub).unwrap() && std::fs::exists(&file).unwrap());
+        assert_eq!(changed_type(&file, false), None);
+        assert_eq!(changed_type(&dir.path().join("gone"), false), None);
+        assert!(!remove(|p| std::fs::remove_file(p), &file, false, false).unwrap());
+    }
+
+    #[test]
+    fn dir_trace() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sub, trace) = (dir.path().join("sub"), dir.path().join("trace"));
+        std::fs::create_dir_all(sub.join("kept")).unwrap();
+        std::fs::write(sub.join("new"), "").unwrap();
+        let mut dir_trace = DirTrace::new(&trace).unwrap();
+        dir_trace.item(&sub.join("a"), None);
+        dir_trace.item(&sub.join("kept/b"), None);
+        dir_trace.item(&sub.join("kept"), Some(&anyhow!("Nope")));
+        dir_trace.dir(&sub, Some(&anyhow!("rmdir: Directory not empty")));
+        dir_trace.finish(std::slice::from_ref(&sub));
+        assert_eq!(std::fs::read_to_string(&trace).unwrap(),
+                   format!("{sub:?}: not deleted: rmdir: Directory not empty\n  ok     \"a\"\n  failed \"kept\": Nope\n  left   \"new\"\n\
+                            {:?}: kept\n  ok     \"b\"\n", sub.join("kept")));
+    }
+
+    #[test]
+    fn dir_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().join("d");
+        std::fs::create_dir_all(top.join("sub")).unwrap();
+        std::fs::write(top.join("f"), "abc").unwrap();
+        std::fs::write(top.join("sub/g"), "hello").unwrap();
+        let args: Args = Docopt::new(usage()).and_then(|d| d.argv(["rm-rfp", "x"]).deserialize()).unwrap();
+        let mut options = FindOptions::new(&args, None).unwrap();
+        options.dir_events = true;
+        let (tx, rx) = sync_channel(100);
+        let mut finder = Find::new(&tx, Interactive::with_asker(false, |f| f(), |_, _, _| unreachable!()), options,
+                                   Arc::new(NoReporter(Stream::Stderr)));
+        finder.find_arg(top.clone()).map_err(|(_, e)| e).unwrap();
+        drop(finder);
+        drop(tx);
+        let rel = |path: &Path| path.strip_prefix(dir.path()).unwrap().display().to_string();
+        let events: Vec<String> = rx.iter().map(|item| match item {
+            ToDelete::File { path, size, .. } => format!("file {} {size}", rel(&path)),
+            ToDelete::Dir(path) => format!("dir {}", rel(&path)),
+            ToDelete::Err { path, err } => format!("err {} {err}", rel(&path)),
+            ToDelete::DirStart { path, entries } => format!("start {} {entries:?}", rel(&path)),
+            ToDelete::DirDone { path, bytes } => format!("done {} {bytes}", rel(&path)),
+        }).collect();
+        assert_eq!(events, ["start d Some(2)", "file d/f 3", "start d/sub Some(1)", "file d/sub/g 5", "done d/sub 5",
+                            "dir d/sub", "done d 8", "dir d"]);
+    }
+
+    #[test]
+    fn release_isnt_queue_full() {
+        struct QueueFull(std::sync::Mutex<Vec<bool>>);
+        impl Reporter for QueueFull {
+            fn found_all(&self, _length: u64) {}
+            fn queue_full(&self, full: bool) { self.0.lock().unwrap().push(full) }
+            fn deleting(&self, _op: &str, _path: &str) {}
+            fn update(&self, _position: u64, _length: u64, _totals: &str, _eta: &str) {}
+            fn error(&self, _line: &[u8]) {}
+            fn suspend(&self, f: &mut dyn FnMut()) { f() }
+            fn finish(&self, _totals: &str) {}
+            fn clear(&self) {}
+        }
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c", "d", "e"] { std::fs::write(dir.path().join(name), name).unwrap() }
+        let args: Args = Docopt::new(usage()).and_then(|d| d.argv(["rm-rfp", "x"]).deserialize()).unwrap();
+        let reporter = Arc::new(QueueFull(std::sync::Mutex::new(vec![])));
+        let (tx, rx) = sync_channel(1); // Way less than what's found.
+        let finder = thread::spawn({
+            let (reporter, top) = (reporter.clone(), dir.path().to_owned());
+            move || {
+                let mut finder = Find::new(&tx, Interactive::with_asker(false, |f| f(), |_, _, _| unreachable!()),
+                                           FindOptions::new(&args, None).unwrap(), reporter);
+                finder.hold();
+                finder.find_arg(top).map_err(|(_, e)| e).unwrap();
+                finder.release(Ok(()), true)
+            }
+        });
+        sleep(Duration::from_millis(50)); // So the queue is full by the time anything's taken out of it.
+        assert_eq!(rx.iter().count(), 6);
+        finder.join().unwrap().unwrap();
+        assert_eq!(*reporter.0.lock().unwrap(), Vec::<bool>::new());
+    }
+
+    #[test]
+    fn sync_dirs() {
+        let mut sync_dirs = SyncDirs::default();
+        for path in ["a/b/c/f", "a/b/c/g", "a/b/c", "a/x", "y"] {
+            sync_dirs.deleted(Path::new(path));
+        }
+        let mut dirs: Vec<_> = sync_dirs.dirs.into_iter().collect();
+        dirs.sort();
+        assert_eq!(dirs, [".", "a", "a/b"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn shuffles() {
+        let mut a: Vec<u32> = (0..100).collect();
+        let mut b = a.clone();
+        shuffle(&mut a, 1);
+        shuffle(&mut b, 1);
+        assert_eq!(a, b);
+        assert_ne!(a, (0..100).collect::<Vec<_>>());
+        a.sort();
+        assert_eq!(a, (0..100).collect::<Vec<_>>());
+        shuffle(&mut [0; 0], 1); // Nothing to do, but mustn't fall over.
+    }
+
+    #[test]
+    fn dry_run_delays() {
+        assert_eq!(parse_dry_run_delay("1000,80").unwrap(), (Duration::from_micros(1000), Duration::from_micros(80)));
+        assert_eq!(parse_dry_run_delay("0").unwrap(), (Duration::ZERO, Duration::ZERO));
+        assert_eq!(parse_dry_run_delay("5, 7").unwrap(), (Duration::from_micros(5), Duration::from_micros(7)));
+        assert!(parse_dry_run_delay("1ms").is_err());
+        assert!(parse_dry_run_delay("1,2,3").is_err());
+    }
+
+    #[test]
+    fn eta_steady() {
+        let start = Instant::now();
+        let mut eta = Eta::new(Duration::from_secs(10));
+        assert_eq!(eta.show(100), "--:--:--");
+        for s in 0..=20 {
+            eta.update(start + Duration::from_secs(s), s * 100);
+        }
+        assert_eq!(eta.show(360000), "01:00:00");
+    }
+
+    #[test]
+    fn eta_bursty() {
+        let start = Instant::now();
+        let mut eta = Eta::new(Duration::from_secs(10));
+        let mut done = 0;
+        // 100 files/s on average, but in one big burst every 5 seconds.
+        for s in 0..=60 {
+            if s % 5 == 0 { done += 500 }
+            eta.update(start + Duration::from_secs(s), done);
+            if s >= 30 {
+                let secs = 10000.0 / eta.rate.unwrap();
+                assert!((50.0..200.0).contains(&secs), "{s}: {secs}");
+            }
+        }
+        // With no smoothing it's all over the place.
+        let mut raw = Eta::new(Duration::ZERO);
+        raw.update(start, 0);
+        raw.update(start + Duration::from_secs(1), 0);
+        assert_eq!(raw.show(10000), "--:--:--");
+    }
+
+    #[test]
+    fn previews() {
+        assert_eq!(preview(b""), "(empty)\n");
+        assert_eq!(preview(b"one\ntwo\n"), "  one\n  two\n");
+        assert_eq!(preview((0..20).map(|n| format!("{n}\n")).collect::<String>().as_bytes()).lines().count(), 10);
+        assert_eq!(preview("caf\u{e9}".as_bytes().split_last().unwrap().1), "  caf\n"); // cut off mid-character
+        assert_eq!(preview(b"\x7fELF\x02\x01\x01\0"), "  00000000  7f 45 4c 46 02 01 01 00                          .ELF....\n");
+        assert_eq!(preview(&[0xff; 100]).lines().count(), 4);
+    }
+
+    #[test]
+    fn same_dir() {
+        assert!(is_same_dir(Path::new("a/b/x"), Path::new("a/b/y")));
+        assert!(is_same_dir(Path::new("a/b/x"), Path::new("a/b/c/d/e")));
+        assert!(is_same_dir(Path::new("a/b/x"), Path::new("a/b")));
+        assert!(!is_same_dir(Path::new("a/b/x"), Path::new("a/c")));
+        assert!(!is_same_dir(Path::new("a/b/x"), Path::new("a")));
+        assert!(!is_same_dir(Path::new("/"), Path::new("/a")));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn mountinfo() {
+        let mountinfo = b"22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+                          40 22 0:35 / /mnt/my\\040drive rw - tmpfs none rw\n\
+                          41 22 0:36 / /tmp/a\\134b rw - tmpfs none rw\n";
+        assert_eq!(mount_points(mountinfo), ["/", "/mnt/my drive", "/tmp/a\\b"].map(PathBuf::from));
+    }
+}
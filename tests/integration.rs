@@ -3,33 +3,22 @@
 use std::{fs::{create_dir_all, read_dir},
           io::ErrorKind,
           path::{Path, PathBuf},
-          process::{Command, Stdio}};
-
-use anyhow::{anyhow, Context, Result};
-use tempfile::{tempdir, TempDir};
-
-fn make_test_tree(count: usize) -> Result<TempDir> {
-    let dir = tempdir().context("tempdir()")?;
-    let mut path = dir.path().to_owned();
-    for c in "abcdefghijklmnopqrstuvwxyz".chars().take(count).map(|c| PathBuf::from(c.to_string())) {
-        path.push(&c);
-        for f in "abcdefghijklmnopqrstuvwxyz".chars().take(count).map(|c| PathBuf::from(format!("{c}{c}"))) {
-            create_dir_all(&path).context("create_dir_all")?;
-            let file = path.join(&f);
-            std::fs::write(&file, file.strip_prefix(dir.path()).context("strip_prefix")?.as_os_str().as_encoded_bytes()).with_context(|| format!("write {file:?}"))?;
-        }
-    }
-    Ok(dir)
-}
+          process::{Command, ExitStatus, Stdio}};
+
+use anyhow::{anyhow, Result};
+use tempfile::tempdir;
+
+mod support;
+use support::make_test_tree;
 
 fn find(path: &Path) -> Vec<PathBuf> {
     let mut paths = vec![];
     _find(path.to_owned(), &mut paths).expect("_find");
-    paths.into_iter().map(|p| p.strip_prefix(&path).unwrap().to_owned()).collect()
+    paths.into_iter().map(|p| p.strip_prefix(path).unwrap().to_owned()).collect()
 }
 
 fn _find(path: PathBuf, paths: &mut Vec<PathBuf>) -> Result<()> {
-    let meta = match (&path).symlink_metadata() {
+    let meta = match path.symlink_metadata() {
         Err(err) => {
             if err.kind() == ErrorKind::NotFound {
                 return Ok(());
@@ -45,7 +34,7 @@ fn _find(path: PathBuf, paths: &mut Vec<PathBuf>) -> Result<()> {
             entry.push(f.map_err(ctx)?.path())
         }
         entry.sort();
-        let empty = entry.len() == 0;
+        let empty = entry.is_empty();
         for f in entry.into_iter() {
             _find(f, paths)?;
         }
@@ -63,10 +52,15 @@ fn paths(paths: Vec<&str>) -> Vec<PathBuf> {
 }
 
 fn run(flags: Vec<&'static str>, args: Vec<&Path>, stdin: &str, cd: Option<&Path>) -> Result<(String, String)> {
+    run_status(flags, args, stdin, cd).map(|(_, out, err)| (out, err))
+}
+
+fn run_status(flags: Vec<&str>, args: Vec<&Path>, stdin: &str, cd: Option<&Path>) -> Result<(ExitStatus, String, String)> {
     let exe = PathBuf::from(env!("CARGO_BIN_EXE_rm-rfp"));
     let mut cmd = Command::new(exe);
     cmd.args(flags)
        .args(args)
+       .env_remove("CI") // Which would change the default --progress.
        .stdin(Stdio::piped())
        .stdout(Stdio::piped())
        .stderr(Stdio::piped());
@@ -77,7 +71,7 @@ fn run(flags: Vec<&'static str>, args: Vec<&Path>, stdin: &str, cd: Option<&Path
                        .expect("Couldn't run {exe:?}");
     std::thread::spawn({
         let stdin_buf = stdin.to_owned();
-        let mut stdin = child.stdin.take().ok_or(anyhow!("Failed to open stdin"))?;
+        let mut stdin = child.stdin.take().expect("Failed to open stdin"); // Piped, so always there.
         move || {
             use std::io::Write;
             stdin.write_all(stdin_buf.as_bytes())
@@ -85,26 +79,150 @@ fn run(flags: Vec<&'static str>, args: Vec<&Path>, stdin: &str, cd: Option<&Path
     });
 
     let output = child.wait_with_output()?;
-    Ok((String::from_utf8(output.stdout)?, String::from_utf8(output.stderr)?))
+    Ok((output.status, String::from_utf8(output.stdout)?, String::from_utf8(output.stderr)?))
+}
+
+// Permissions don't stop root, so in that case use the immutable flag instead of a read-only parent.
+#[cfg(target_os = "linux")]
+struct Undeletable(PathBuf);
+
+#[cfg(target_os = "linux")]
+impl Undeletable {
+    fn new(path: &Path) -> Undeletable {
+        if unsafe { libc::geteuid() } == 0 {
+            assert!(Command::new("chattr").arg("+i").arg(path).status().expect("chattr").success());
+        } else {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path.parent().unwrap(), std::fs::Permissions::from_mode(0o555)).expect("chmod");
+        }
+        Undeletable(path.to_owned())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Undeletable {
+    fn drop(&mut self) {
+        if unsafe { libc::geteuid() } == 0 {
+            _ = Command::new("chattr").arg("-i").arg(&self.0).status();
+        } else {
+            use std::os::unix::fs::PermissionsExt;
+            _ = std::fs::set_permissions(self.0.parent().unwrap(), std::fs::Permissions::from_mode(0o755));
+        }
+    }
 }
 
 #[test]
 fn test_dry_run() {
     let dir = make_test_tree(26).expect("make_test_tree");
-    let orig = find(&dir.path());
+    let orig = find(dir.path());
     let (out, err) = run(vec!["--dry-run"], vec![dir.path()], "", None).expect("rmp failed");
-    let after = find(&dir.path());
+    let after = find(dir.path());
     drop(dir);
     assert_eq!(after, orig);
     assert_eq!(out.as_str(), "");
     assert_eq!(err.as_str(), "");
 }
 
+#[test]
+fn test_count_only() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let orig = find(dir.path());
+    let (out, err) = run(vec!["--count-only"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    assert_eq!(after, orig);
+    assert_eq!(out.as_str(), "54 B in 9 files and 4 directories\n");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_humanize() {
+    let dir = tempdir().expect("tempdir");
+    std::fs::write(dir.path().join("f"), [0; 1500]).expect("write");
+    for (flag, size) in [("--humanize=si", "1.50 kB"), ("--humanize=iec", "1.46 KiB"), ("--humanize=bytes", "1500 B")] {
+        let (out, err) = run(vec!["--count-only", flag], vec![dir.path()], "", None).expect("rmp failed");
+        assert_eq!(out, format!("{size} in 1 files and 1 directories\n"), "{flag}");
+        assert_eq!(err.as_str(), "");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_apparent_size() {
+    use std::os::unix::fs::MetadataExt;
+    let dir = tempdir().expect("tempdir");
+    let sparse = std::fs::File::create(dir.path().join("sparse")).expect("create");
+    sparse.set_len(10 << 20).expect("set_len"); // All hole.
+    std::fs::write(dir.path().join("small"), "x").expect("write");
+    let blocks = std::fs::metadata(dir.path().join("sparse")).expect("stat").blocks() +
+                 std::fs::metadata(dir.path().join("small")).expect("stat").blocks();
+    for (flag, size) in [("--apparent-size=yes", (10 << 20) + 1), ("--apparent-size=no", blocks * 512)] {
+        let (out, err) = run(vec!["--count-only", "--humanize=bytes", flag], vec![dir.path()], "", None).expect("rmp failed");
+        assert_eq!(out, format!("{size} B in 2 files and 1 directories\n"), "{flag}");
+        assert_eq!(err.as_str(), "");
+    }
+}
+
+#[test]
+fn test_tree() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let before = find(dir.path());
+    let (out, err) = run(vec!["--dry-run", "--tree", "-C"], vec![dir.path(), Path::new("a")], "", None).expect("rmp failed");
+    assert_eq!(find(dir.path()), before);
+    assert_eq!(out.as_str(), "a/\n  aa\n  b/\n    aa\n    bb\n  bb\n");
+    assert!(err.starts_with("Would free "), "{err}");
+
+    let (status, _out, _err) = run_status(vec!["--tree"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert_eq!(find(dir.path()), before);
+}
+
+#[test]
+fn test_config() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let before = find(dir.path());
+    let config = tempfile::NamedTempFile::new().expect("tempfile");
+    std::fs::write(config.path(), "dry-run = true\nsort = \"bogus\"\n").expect("write config");
+    let rmp = |args: &[&str], env: &[(&str, &str)]| {
+        Command::new(env!("CARGO_BIN_EXE_rm-rfp")).args(args).arg(dir.path())
+                                                  .env("RM_RFP_CONFIG", config.path()).envs(env.iter().copied())
+                                                  .output().expect("run").status
+    };
+    assert!(!rmp(&[], &[]).success()); // bogus sort from the config
+    assert!(rmp(&["--sort=name"], &[]).success()); // command line beats config
+    assert!(rmp(&[], &[("RM_RFP_SORT", "size")]).success()); // so does the environment
+    assert_eq!(find(dir.path()), before); // config said dry run
+    assert!(rmp(&["--sort=name"], &[("RM_RFP_DRY_RUN", "false")]).success());
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_config_safety_overrides() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let before = find(dir.path());
+    let config = tempfile::NamedTempFile::new().expect("tempfile");
+    let rmp = |env: &[(&str, &str)]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_rm-rfp")).arg(dir.path())
+                                                            .env("RM_RFP_CONFIG", config.path()).envs(env.iter().copied())
+                                                            .output().expect("run");
+        (out.status, String::from_utf8_lossy(&out.stderr).into_owned())
+    };
+    std::fs::write(config.path(), "no-preserve-root = true\n").expect("write config");
+    let (status, err) = rmp(&[]);
+    assert!(!status.success());
+    assert!(err.contains("no-preserve-root turns off a safety check"), "{err}");
+    std::fs::write(config.path(), "").expect("write config");
+    let (status, err) = rmp(&[("RM_RFP_ALLOW_DELETE_CWD", "1")]);
+    assert!(!status.success());
+    assert!(err.contains("$RM_RFP_ALLOW_DELETE_CWD: --allow-delete-cwd turns off a safety check"), "{err}");
+    assert_eq!(find(dir.path()), before);
+}
+
 #[test]
 fn test_delete() {
     let dir = make_test_tree(26).expect("make_test_tree");
     let (out, err) = run(vec![], vec![dir.path()], "", None).expect("rmp failed");
-    let after = find(&dir.path());
+    let after = find(dir.path());
     drop(dir);
     assert_eq!(after, Vec::<PathBuf>::new());
     assert_eq!(out.as_str(), "");
@@ -115,8 +233,8 @@ fn test_delete() {
 fn test_interactive_ynq() {
     let dir = make_test_tree(3).expect("make_test_tree");
     let inp = "yynyynyyyyynyyq".chars().map(|c| format!("{c}\n")).collect::<String>();
-    let (out, err) = run(vec!["-i"], vec![dir.path()], &inp, None).expect("rmp failed");
-    let after = find(&dir.path());
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![dir.path()], &inp, None).expect("rmp failed");
+    let after = find(dir.path());
     drop(dir);
     print!("{}", out.lines().zip(inp.lines()).map(|(o, i)| format!("{o}{i}\n")).collect::<String>());
     assert_eq!(after, paths(vec!["a/aa", "a/b/bb", "a/b/cc"]));
@@ -124,17 +242,119 @@ fn test_interactive_ynq() {
     assert_eq!(err.as_str(), "");
 }
 
+#[test]
+fn test_interactive_eof_quits() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let orig = find(dir.path());
+    let inp = "y\ny\ny"; // truncated: the last answer has no newline and then it stops.
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![dir.path()], inp, None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    println!("{out}");
+    assert_eq!(after, orig.into_iter().filter(|p| p != Path::new("a/aa")).collect::<Vec<_>>());
+    assert_eq!(out.lines().count(), 4); // The one that hit EOF is still printed.
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_interactive_closed_stdin() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let orig = find(dir.path());
+    // Taking the default at EOF would skip a/aa and then go right on to asking about a/bb.
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![&dir.path().join("a/aa"), &dir.path().join("a/bb")], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    assert_eq!(after, orig);
+    assert_eq!(out.lines().count(), 1, "should have stopped asking at the first EOF: {out}");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_prompt_timeout() {
+    // Nobody answers, but stdin stays open (unlike run(), which closes it once the input is written).
+    let ask = |flags: &[&str], path: &Path| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rm-rfp")).args(["-i", "--prompt-timeout=200ms", "--progress-to=stdout"])
+                                                                  .args(flags).arg(path)
+                                                                  .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+                                                                  .spawn().expect("run");
+        let _stdin = child.stdin.take();
+        let output = child.wait_with_output().expect("wait");
+        (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+    };
+    let dir = make_test_tree(1).expect("make_test_tree");
+    let (out, err) = ask(&[], &dir.path().join("a/aa"));
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(out.contains("(timed out)"), "{out}");
+    assert!(dir.path().join("a/aa").exists()); // The default is no.
+    assert_eq!(err.as_str(), "");
+
+    let (out, _err) = ask(&["--assume-yes"], &dir.path().join("a/aa"));
+    assert!(out.contains("(timed out)"), "{out}");
+    assert!(!dir.path().join("a/aa").exists());
+
+    let (status, _out, err) = run_status(vec!["--prompt-timeout=1s"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("only does anything with --interactive"), "{err}");
+}
+
+#[test]
+fn test_interactive_assume_yes() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let inp = "\n\nn\n"; // keep a/aa, then run out of input
+    let (out, err) = run(vec!["-i", "--progress-to=stdout", "--assume-yes"], vec![dir.path()], inp, None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    println!("{out}");
+    assert_eq!(after, paths(vec!["a/aa"]));
+    assert!(out.contains("(Y/n/a/q/d/s/t/k/v/?)"));
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_interactive_view() {
+    let dir = make_test_tree(1).expect("make_test_tree");
+    let inp = "y\nv\ny\nv\nn\n"; // the tempdir, view a, descend, view a/aa, keep it
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![dir.path()], inp, None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    println!("{out}");
+    assert_eq!(after, paths(vec!["a/aa"]));
+    assert!(out.contains("\n  aa\n"), "{out}"); // directory listing
+    assert!(out.contains("\n  a/aa\n"), "{out}"); // contents
+    assert_eq!(out.matches("remove file").count(), 2, "{out}"); // asked again after viewing
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_progress_to() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let (out, err) = run(vec!["-i"], vec![&dir.path().join("a/aa")], "n\n", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert!(err.contains("remove file"), "{err}");
+
+    // Data stays on stdout, and with the UI there too it comes after the totals.
+    let (out, err) = run(vec!["--dry-run", "--tree", "--progress-to=stdout"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(err.as_str(), "");
+    assert!(out.contains("aa\n"), "{out}");
+    assert!(out.ends_with("Would free 20 B across 4 files and 3 directories\n"), "{out}");
+
+    let (out, err) = run(vec!["--progress=none", "--progress-to=stdout"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(err.as_str(), "");
+    assert!(out.starts_with("Total: freed: "), "{out}");
+}
+
 #[test]
 fn test_interactive_multiarg_q() {
     let dir1 = make_test_tree(3).expect("make_test_tree1");
     let dir2 = make_test_tree(2).expect("make_test_tree2");
-    let orig2 = find(&dir2.path());
+    let orig2 = find(dir2.path());
     let inp = "yynyynyyyyynyyq".chars().map(|c| format!("{c}\n")).collect::<String>();
-    let (out, err) = run(vec!["-i"], vec![dir1.path(),
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![dir1.path(),
                                           dir2.path()], &inp, None)
         .expect("rmp failed");
-    let after1 = find(&dir1.path());
-    let after2 = find(&dir2.path());
+    let after1 = find(dir1.path());
+    let after2 = find(dir2.path());
     drop(dir1);
     drop(dir2);
     print!("{}", out.lines().zip(inp.lines()).map(|(o, i)| format!("{o}{i}\n")).collect::<String>());
@@ -148,8 +368,8 @@ fn test_interactive_multiarg_q() {
 fn test_interactive_a() {
     let dir = make_test_tree(26).expect("make_test_tree");
     let inp = "yynynnyyna".chars().map(|c| format!("{c}\n")).collect::<String>();
-    let (out, err) = run(vec!["-i"], vec![dir.path()], &inp, None).expect("rmp failed");
-    let after = find(&dir.path());
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![dir.path()], &inp, None).expect("rmp failed");
+    let after = find(dir.path());
     drop(dir);
     if after != Vec::<PathBuf>::new() {
         print!("{}", out.lines().zip(inp.lines()).map(|(o, i)| format!("{o}{i}\n")).collect::<String>());
@@ -164,7 +384,7 @@ fn test_interactive_multiarg_a() {
     let dir1 = make_test_tree(3).expect("make_test_tree1");
     let dir2 = make_test_tree(26).expect("make_test_tree");
     let inp = "nnnna".chars().map(|c| format!("{c}\n")).collect::<String>();
-    let (out, err) = run(vec!["-i"], vec![&dir1.path().join("a/aa"),
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![&dir1.path().join("a/aa"),
                                           &dir1.path().join("a/b/aa"),
                                           &dir1.path().join("a/b/bb"),
                                           &dir1.path().join("a/b/c/bb"),
@@ -175,8 +395,8 @@ fn test_interactive_multiarg_a() {
                                           &dir1.path().join("a/cc"),
                                           &dir2.path()], &inp, None)
         .expect("rmp failed");
-    let after1 = find(&dir1.path());
-    let after2 = find(&dir2.path());
+    let after1 = find(dir1.path());
+    let after2 = find(dir2.path());
     drop(dir1);
     drop(dir2);
     print!("{}", out.lines().map(|l| format!("{l}\n")).collect::<String>());
@@ -191,8 +411,8 @@ fn test_interactive_multiarg_a() {
 fn test_interactive_d() {
     let dir = make_test_tree(7).expect("make_test_tree");
     let inp = "yynynnyndddq".chars().map(|c| format!("{c}\n")).collect::<String>();
-    let (out, err) = run(vec!["-i"], vec![dir.path()], &inp, None).expect("rmp failed");
-    let after = find(&dir.path());
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![dir.path()], &inp, None).expect("rmp failed");
+    let after = find(dir.path());
     drop(dir);
     if after != Vec::<PathBuf>::new() {
         print!("{}", out.lines().zip(inp.lines()).map(|(o, i)| format!("{o}{i}\n")).collect::<String>());
@@ -206,8 +426,8 @@ fn test_interactive_d() {
 fn test_interactive_s() {
     let dir = make_test_tree(5).expect("make_test_tree");
     let inp = "yyyyyyyysysna".chars().map(|c| format!("{c}\n")).collect::<String>();
-    let (out, err) = run(vec!["-i"], vec![dir.path()], &inp, None).expect("rmp failed");
-    let after = find(&dir.path());
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![dir.path()], &inp, None).expect("rmp failed");
+    let after = find(dir.path());
     drop(dir);
     if after != Vec::<PathBuf>::new() {
         print!("{}", out.lines().zip(inp.lines()).map(|(o, i)| format!("{o}{i}\n")).collect::<String>());
@@ -221,55 +441,1594 @@ fn test_interactive_s() {
 }
 
 #[test]
-fn test_dot() {
+fn test_per_arg_summary() {
+    let dir1 = make_test_tree(3).expect("make_test_tree1");
+    let dir2 = make_test_tree(2).expect("make_test_tree2");
+    let (out, err) = run(vec!["--per-arg-summary"], vec![dir1.path(), dir2.path()], "", None).expect("rmp failed");
+    println!("out:\n{out}\nerr:\n{err}");
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("directories removed: 4, files removed: 9"));
+    assert!(lines[1].ends_with("directories removed: 3, files removed: 4"));
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_by_extension() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    std::fs::write(dir.path().join("a/x.o"), "12345678").expect("write");
+    std::fs::write(dir.path().join("a/b/y.o"), "1234").expect("write");
+    std::fs::write(dir.path().join("a/z.log"), "1234567890").expect("write");
+    let (out, err) = run(vec!["--dry-run", "--by-extension"], vec![dir.path()], "", None).expect("rmp failed");
+    println!("out:\n{out}\nerr:\n{err}");
+    assert_eq!(out, "(none): would free: 20 B, files to remove: 4\n\
+                     .o: would free: 12 B, files to remove: 2\n\
+                     .log: would free: 10 B, files to remove: 1\n");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_stop_on_error() {
     let dir = make_test_tree(3).expect("make_test_tree");
-    let before = find(&dir.path());
-    let (out, err) = run(vec!["--dry-run"], vec![Path::new(".")], "", Some(&dir.path())).expect("rm-rfp exec failed");
-    let after = find(&dir.path());
-    assert_eq!(before, after);
-    println!("err={err}\nout={out}");
-    assert_ne!(err.as_str(), "");
+    let stuck = Undeletable::new(&dir.path().join("a/aa"));
+    let (status, out, err) = run_status(vec!["--stop-on-error"], vec![dir.path()], "", None).expect("rmp failed");
+    drop(stuck);
+    let after = find(dir.path());
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(!status.success());
+    assert!(err.contains("a/aa"));
+    assert!(after.contains(&PathBuf::from("a/aa")));
+    assert!(after.contains(&PathBuf::from("a/b/c/cc"))); // Should have stopped before getting here.
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_stop_on_error_finder_still_going() {
+    // A queue of 1 keeps the finder waiting to send when the deleting stops, so it sees the channel close.
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let stuck = Undeletable::new(&dir.path().join("a/aa"));
+    let (status, out, err) = run_status(vec!["--stop-on-error", "--queue-size=1", "--progress=none", "--summary-format={errors}"],
+                                        vec![dir.path()], "", None).expect("rmp failed");
+    drop(stuck);
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(!status.success());
+    assert_eq!(out.as_str(), "1\n");
+    assert!(err.contains("a/aa\": rm: "), "{err}");
+    assert!(!err.contains("channel"), "{err}");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_max_errors() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let stuck: Vec<_> = ["a/aa", "a/bb", "a/b/aa"].iter().map(|p| Undeletable::new(&dir.path().join(p))).collect();
+    let (status, out, err) = run_status(vec!["--max-errors=2", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    drop(stuck);
+    let after = find(dir.path());
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(!status.success());
+    assert_eq!(err.lines().filter(|l| l.contains("rm: ")).count(), 2);
+    assert!(err.contains("Too many errors (2), aborting"));
+    assert!(after.contains(&PathBuf::from("a/cc"))); // Should have stopped before getting here.
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_verify() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let stuck = Undeletable::new(&dir.path().join("a/aa"));
+    let (status, _out, err) = run_status(vec!["--verify", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    drop(stuck);
+    println!("err:\n{err}");
+    assert!(!status.success());
+    assert!(err.contains("a/aa\": Still there after deleting"), "{err}");
+
+    // Only what the filters would have deleted counts.
+    let (status, out, err) = run_status(vec!["--verify", "--files-only", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
     assert_eq!(out.as_str(), "");
+    assert!(!err.contains("Still there"), "{err}");
+
+    let (status, _out, err) = run_status(vec!["--verify", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert!(!dir.path().exists());
 
+    let (status, _out, err) = run_status(vec!["--verify", "--dry-run"], vec![Path::new("nope")], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("--dry-run"), "{err}");
 }
 
 #[test]
-fn test_dot_dot() {
+#[cfg(target_os = "linux")]
+fn test_report_errors_json() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let stuck = Undeletable::new(&dir.path().join("a/aa"));
+    let (status, out, err) = run_status(vec!["--report-errors-json=stdout", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    drop(stuck);
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(!status.success());
+    assert!(!err.contains("rm: "), "{err}"); // Not reported twice.
+    let lines: Vec<_> = out.lines().filter(|l| l.starts_with('{')).collect();
+    assert_eq!(lines.len(), 3); // It, and the two directories it's in.
+    assert!(lines[1].ends_with(&format!("\"errno\":{}}}", libc::ENOTEMPTY)), "{}", lines[1]);
+    let path = dir.path().join("a/aa");
+    let errno = if unsafe { libc::geteuid() } == 0 { libc::EPERM } else { libc::EACCES };
+    assert_eq!(lines[0], format!("{{\"path\":\"{}\",\"error\":\"rm: {}\",\"errno\":{errno}}}", path.display(),
+                                 std::io::Error::from_raw_os_error(errno)));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_list_errors() {
     let dir = make_test_tree(3).expect("make_test_tree");
-    let before = find(&dir.path());
-    let (out, err) = run(vec!["--dry-run"], vec![Path::new("..")], "", Some(&dir.path().join("a"))).expect("rm-rfp exec failed");
-    let after = find(&dir.path());
-    assert_eq!(before, after);
-    println!("err={err}\nout={out}");
-    assert_ne!(err.as_str(), "");
+    let stuck: Vec<_> = ["a/aa", "a/b/c/aa"].iter().map(|p| Undeletable::new(&dir.path().join(p))).collect();
+    let (status, _out, err) = run_status(vec!["--list-errors", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    println!("err:\n{err}");
+    assert!(!status.success());
+    let (inline, list) = err.split_once("Errors (").expect("no error list");
+    let (count, list) = list.split_once("):\n").unwrap();
+    let inline: Vec<_> = inline.lines().filter(|l| l.starts_with('"')).collect();
+    assert!(inline.len() >= 2);
+    assert_eq!(count, inline.len().to_string());
+    assert_eq!(list.lines().take_while(|l| l.starts_with('"')).collect::<Vec<_>>(), inline);
+
+    let errors = tempfile::NamedTempFile::new().expect("tempfile");
+    let flag = format!("--errors-to={}", errors.path().display());
+    let (status, _out, err) = run_status(vec![&flag, "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    drop(stuck);
+    assert!(!status.success());
+    assert!(!err.contains("Errors ("), "{err}");
+    let written = std::fs::read_to_string(errors.path()).expect("read");
+    assert_eq!(written.lines().collect::<Vec<_>>(), err.lines().filter(|l| l.starts_with('"')).collect::<Vec<_>>());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_delete_error_continues() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let stuck = Undeletable::new(&dir.path().join("a/b/c/aa"));
+    let (status, out, err) = run_status(vec![], vec![dir.path()], "", None).expect("rmp failed");
+    drop(stuck);
+    let after = find(dir.path());
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(!status.success());
+    assert!(err.contains("a/b/c/aa"));
+    assert!(after.contains(&PathBuf::from("a/b/c/aa")));
+    assert!(!after.iter().any(|p| p.parent() != Some(Path::new("a/b/c")))); // One bad file shouldn't stop the rest.
     assert_eq!(out.as_str(), "");
 }
 
 #[test]
-fn test_root_preserve() {
-    test_dry_run(); // This test is quite dangerous if --dry-run doesn't work. So it's got to be a prerequisite!
-    test_interactive_ynq(); // As a backup we also set --interactive and send a "q".
-    // FIXME: is there some way to chroot or something so this isn't such a dire test to fail?
-    let (out, err) = run(vec!["--dry-run", "-i"], vec![Path::new("/")], "q", None).expect("rm-rfp exec failed");
+#[cfg(target_os = "linux")]
+fn test_trash_on_fail() {
+    if unsafe { libc::geteuid() } != 0 {
+        println!("A read-only directory can't be moved to the trash either, skipping");
+        return;
+    }
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let trash = tempdir().expect("tempdir");
+    // a/b/c can't be rmdir'ed with this still in it, but the whole directory can be moved.
+    let stuck = Undeletable::new(&dir.path().join("a/b/c/aa"));
+    let output = Command::new(env!("CARGO_BIN_EXE_rm-rfp")).args(["--trash-on-fail", "--progress=none"]).arg(dir.path())
+                                                           .env("XDG_DATA_HOME", trash.path())
+                                                           .output().expect("run");
+    let err = String::from_utf8(output.stderr).unwrap();
+    println!("err:\n{err}");
+    drop(stuck);
+    let _stuck = Undeletable(trash.path().join("Trash/files/c/aa"));
+    assert!(!output.status.success()); // The file itself still couldn't be deleted
+    assert!(err.contains("a/b/c/aa\": rm: "), "{err}");
+    assert!(err.contains("(1 of them moved to the trash instead)"), "{err}");
+    assert!(!dir.path().exists());
+    // The failed attempt at trashing aa itself can leave an empty placeholder behind, so don't look for exactly this.
+    assert!(find(&trash.path().join("Trash/files")).contains(&PathBuf::from("c/aa")));
+}
+
+#[test]
+fn test_bury_and_restore() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    create_dir_all(dir.path().join("empty")).expect("create_dir_all");
+    let before = find(dir.path());
+    let graveyard = tempdir().expect("tempdir");
+    let flag = format!("--graveyard={}", graveyard.path().join("g").display());
+    let graveyard = graveyard.path().join("g");
+
+    let (status, _out, err) = run_status(vec![&flag], vec![graveyard.parent().unwrap()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("The graveyard"), "{err}");
+
+    let (status, _out, err) = run_status(vec![&flag, "--progress=none"], vec![&dir.path().join("a"), &dir.path().join("empty")], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert!(err.contains("files buried: 9/9"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec![""]));
+    assert_eq!(read_dir(&graveyard).unwrap().count(), 1);
+
+    // Nothing gets overwritten, and the run stays in the graveyard until everything is back.
+    std::fs::write(dir.path().join("a"), "in the way").expect("write");
+    let (status, _out, err) = run_status(vec!["--restore", &flag], vec![], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("not overwriting"), "{err}");
+    assert_eq!(read_dir(&graveyard).unwrap().count(), 1);
+
+    std::fs::remove_file(dir.path().join("a")).expect("remove_file");
+    let (status, _out, err) = run_status(vec!["--restore", &flag], vec![], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert_eq!(find(dir.path()), before);
+    assert_eq!(read_dir(&graveyard).unwrap().count(), 0);
+    let (status, _out, err) = run_status(vec!["--restore", &flag], vec![], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("Nothing has been buried"), "{err}");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_on_error() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let log = tempfile::NamedTempFile::new().expect("tempfile");
+    let stuck = Undeletable::new(&dir.path().join("a/b/c/aa"));
+    let hook = format!(r#"--on-error=echo "$1|$RM_RFP_PATH|$2" >> {:?}"#, log.path());
+    let (status, _out, err) = run_status(vec![&hook], vec![dir.path()], "", None).expect("rmp failed");
+    drop(stuck);
+    println!("err:\n{err}");
+    assert!(!status.success());
+    let path = dir.path().join("a/b/c/aa");
+    let logged = std::fs::read_to_string(log.path()).expect("read log");
+    assert!(logged.starts_with(&format!("{}|{}|rm: ", path.display(), path.display())), "{logged}");
+    assert_eq!(logged.lines().count(), err.lines().filter(|l| l.starts_with('"')).count(), "{logged}"); // one per error
+}
+
+#[test]
+#[cfg(unix)]
+fn test_special_files() {
+    fn make_specials(dir: &Path) {
+        let fifo = std::ffi::CString::new(dir.join("fifo").into_os_string().into_encoded_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0, "mkfifo");
+        std::os::unix::net::UnixListener::bind(dir.join("socket")).expect("bind socket"); // dropping doesn't remove it
+    }
+
+    let dir = tempdir().expect("tempdir");
+    make_specials(dir.path());
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![dir.path()], "y\ny\ny\ny\n", None).expect("rmp failed");
     println!("out:\n{out}\nerr:\n{err}");
-    assert_ne!(err.as_str(), "");
+    assert!(out.contains(&format!("remove fifo {:?}?", dir.path().join("fifo"))));
+    assert!(out.contains(&format!("remove socket {:?}?", dir.path().join("socket"))));
+    assert!(!dir.path().exists());
+
+    let dir = tempdir().expect("tempdir");
+    make_specials(dir.path());
+    let (out, err) = run(vec!["--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(err.contains("files removed: 2"), "{err}");
     assert_eq!(out.as_str(), "");
-    assert!(err.contains("--no-preserve-root"));
-    assert!(err.to_lowercase().contains("error"));
-    assert!(err.to_lowercase().contains("refusing"));
+    assert!(!dir.path().exists());
 }
 
 #[test]
-fn test_no_root_preserve() {
-    test_dry_run(); // This test is quite dangerous if --dry-run doesn't work. So it's got to be a prerequisite!
-    test_interactive_ynq(); // As a backup we also set --interactive and send a "q".
-    // FIXME: is there some way to chroot or something so this isn't such a dire test to fail?
-    let (out, err) = run(vec!["--dry-run", "-i", "--no-preserve-root"], vec![Path::new("/")], "q", None).expect("rm-rfp exec failed");
+#[cfg(target_os = "linux")]
+fn test_whiteout() {
+    let dir = tempdir().expect("tempdir");
+    let path = std::ffi::CString::new(dir.path().join("gone").into_os_string().into_encoded_bytes()).unwrap();
+    // This is what overlayfs leaves in the upper layer when a lower layer's file is deleted.
+    if unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR, 0) } != 0 {
+        println!("Can't make a 0/0 character device without CAP_MKNOD, skipping");
+        return;
+    }
+    let (out, err) = run(vec!["-i", "--progress-to=stdout"], vec![dir.path()], "y\ny\ny\n", None).expect("rmp failed");
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(out.contains(&format!("remove whiteout entry {:?}?", dir.path().join("gone"))), "{out}");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_sort_size() {
+    let dir = tempdir().expect("tempdir");
+    for (name, size) in [("a", 10), ("b", 1000), ("c", 1), ("d", 100)] {
+        std::fs::write(dir.path().join(name), vec![b'x'; size]).expect("write");
+    }
+    let inp = "ynnnnn".chars().map(|c| format!("{c}\n")).collect::<String>();
+    let (out, err) = run(vec!["-i", "--progress-to=stdout", "--sort=size"], vec![dir.path()], &inp, None).expect("rmp failed");
     println!("out:\n{out}\nerr:\n{err}");
+    let order: Vec<String> = out.lines().skip(1).take(4)
+                                .map(|l| Path::new(l.split('"').nth(1).unwrap()).file_name().unwrap().to_string_lossy().into_owned())
+                                .collect();
+    assert_eq!(order, vec!["b", "d", "a", "c"]);
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_sort_random() {
+    let dir = tempdir().expect("tempdir");
+    let names: Vec<String> = (0..20).map(|n| format!("f{n:02}")).collect();
+    create_dir_all(dir.path().join("d")).expect("create_dir_all");
+    for name in &names {
+        std::fs::write(dir.path().join("d").join(name), "x").expect("write");
+    }
+    let order = |flags: Vec<&'static str>| -> Vec<String> {
+        let (out, err) = run(flags, vec![Path::new("d")], "", Some(dir.path())).expect("rmp failed");
+        assert!(err.starts_with("Would free 20 B"), "{err}");
+        out.lines().skip(1).map(|l| l.trim().to_owned()).collect()
+    };
+    let first = order(vec!["--dry-run", "--tree", "--sort=random", "--seed=42"]);
+    assert_ne!(first, names);
+    assert_eq!(first.iter().cloned().collect::<std::collections::BTreeSet<_>>(), names.iter().cloned().collect());
+    assert_eq!(order(vec!["--dry-run", "--tree", "--sort=random", "--seed=42"]), first);
+    assert_ne!(order(vec!["--dry-run", "--tree", "--sort=random", "--seed=43"]), first);
+
+    let (status, _out, err) = run_status(vec!["--seed=42"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("--sort=random"), "{err}");
+}
+
+#[test]
+fn test_sort_threshold_forced() {
+    let dir = tempdir().expect("tempdir");
+    for n in 0..5100 {
+        let sub = dir.path().join(format!("d{n:04}"));
+        create_dir_all(&sub).expect("create_dir_all");
+        std::fs::write(sub.join("f"), "x").expect("write");
+    }
+    let (out, err) = run(vec!["--sort-threshold=1000000"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    assert_eq!(after, Vec::<PathBuf>::new());
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_only_broken_symlinks() {
+    use std::os::unix::fs::symlink;
+    let dir = make_test_tree(2).expect("make_test_tree");
+    symlink("aa", dir.path().join("a/good")).expect("symlink");
+    symlink("nope", dir.path().join("a/bad")).expect("symlink");
+    symlink("../../nope", dir.path().join("a/b/bad")).expect("symlink");
+    create_dir_all(dir.path().join("empty")).expect("create_dir_all");
+    let (out, err) = run(vec!["--only-broken-symlinks"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    assert_eq!(after, paths(vec!["a/aa", "a/b/aa", "a/b/bb", "a/bb", "a/good", "empty"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_symlink_stats() {
+    use std::os::unix::fs::symlink;
+    let dir = make_test_tree(3).expect("make_test_tree");
+    symlink("aa", dir.path().join("a/link")).expect("symlink");
+    symlink("a long way to nowhere", dir.path().join("a/b/link")).expect("symlink");
+    let (out, _err) = run(vec!["--count-only"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "54 B in 9 files, 2 symlinks and 4 directories\n"); // The links' own sizes don't count.
+    let (out, err) = run(vec!["--progress=none", "--summary-format={files},{symlinks},{bytes}"], vec![dir.path()], "", None)
+        .expect("rmp failed");
+    assert!(err.contains("freed: 54 B/54 B, directories removed: 4/4, files removed: 9/9, symlinks removed: 2/2"), "{err}");
+    assert_eq!(out.as_str(), "9,2,54\n");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_timing_log() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let log = tempfile::NamedTempFile::new().expect("tempfile");
+    let flag = format!("--timing-log={}", log.path().display());
+    let (status, _out, err) = run_status(vec![&flag, "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    let logged = std::fs::read_to_string(log.path()).expect("read log");
+    println!("{logged}");
+    let events: Vec<&str> = logged.lines().map(|line| line.splitn(3, ' ').nth(2).unwrap_or("")).collect();
+    assert_eq!(events[0], "counting-started");
+    assert!(events.contains(&"counting-finished bytes=54 files=9 dirs=4 symlinks=0"), "{logged}");
+    assert!(events.contains(&"first-delete"), "{logged}");
+    assert_eq!(&events[events.len() - 2..], ["last-delete", "run-finished errors=0"]);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_longer_than_path_max() {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    let dir = tempdir().expect("tempdir");
+    let top = dir.path().join("deep");
+    // 30 levels of 200 character names is well past PATH_MAX (4096), so it can only be built a directory at a time.
+    let name = std::ffi::CString::new("d".repeat(200)).unwrap();
+    let open = |at: i32, name: &std::ffi::CStr| {
+        let fd = unsafe { libc::openat(at, name.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+        assert!(fd >= 0, "openat: {}", std::io::Error::last_os_error());
+        unsafe { OwnedFd::from_raw_fd(fd) }
+    };
+    let mut backends = vec![vec!["--progress=none"]];
+    if cfg!(feature = "iouring") { backends.push(vec!["--progress=none", "--backend=iouring"]) }
+    for flags in backends {
+        create_dir_all(&top).expect("create_dir_all");
+        let mut fd = open(libc::AT_FDCWD, &std::ffi::CString::new(top.to_str().unwrap()).unwrap());
+        for _ in 0..30 {
+            assert_eq!(unsafe { libc::mkdirat(fd.as_raw_fd(), name.as_ptr(), 0o755) }, 0, "mkdirat");
+            let file = unsafe { libc::openat(fd.as_raw_fd(), c"f".as_ptr(), libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC, 0o644) };
+            assert!(file >= 0, "create");
+            unsafe { libc::close(file) };
+            fd = open(fd.as_raw_fd(), &name);
+        }
+        drop(fd);
+        let (status, out, err) = run_status(flags.clone(), vec![&top], "", None).expect("rmp failed");
+        assert!(status.success(), "{flags:?}: {err}");
+        assert!(err.contains("directories removed: 31/31, files removed: 30/30"), "{flags:?}: {err}");
+        assert_eq!(out.as_str(), "");
+        assert!(!top.exists());
+    }
+}
+
+#[test]
+fn test_ci() {
+    let dir = make_test_tree(1).expect("make_test_tree");
+    let dry_run = |flags: &[&str], ci: Option<&str>| {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_rm-rfp"));
+        cmd.arg("-n").args(flags).arg(dir.path()).env_remove("CI");
+        if let Some(ci) = ci { cmd.env("CI", ci); }
+        String::from_utf8(cmd.output().expect("run").stderr).unwrap()
+    };
+    // Only --progress=plain says how long it took.
+    let plain = |err: String| err.trim_end().ends_with("elapsed: 00:00:00");
+    assert!(!plain(dry_run(&[], None)));
+    assert!(plain(dry_run(&["--ci"], None)));
+    assert!(plain(dry_run(&[], Some("true"))));
+    assert!(!plain(dry_run(&[], Some("false"))));
+    assert!(!plain(dry_run(&["--progress=none"], Some("true"))));
+}
+
+#[test]
+fn test_provisional_eta() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    // A second a file and no room in the queue keeps it counting past the first status line, 5 seconds in.
+    let (_status, _out, err) = run_status(vec!["-n", "--progress=plain", "--provisional-eta", "--queue-size=1",
+                                               "--dry-run-delay=1000000,0", "--time-limit=6s"],
+                                          vec![dir.path()], "", None).expect("rmp failed");
+    let status_line = err.lines().find(|line| line.contains("still counting")).unwrap_or_else(|| panic!("{err}"));
+    assert!(status_line.contains(", ETA at least 00:00:0"), "{err}");
+}
+
+#[test]
+fn test_no_clear() {
+    // The bars are hidden when it's not a terminal, and keeping them mustn't change that.
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (out, err) = run(vec!["--no-clear"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!dir.path().exists());
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_refresh_rate() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    for bad in ["--refresh-rate=0", "--refresh-rate=61"] {
+        let (status, _out, err) = run_status(vec![bad], vec![dir.path()], "", None).expect("rmp failed");
+        assert!(!status.success());
+        assert!(err.contains("--refresh-rate should be from 1 to 60"), "{err}");
+    }
+    assert!(dir.path().exists());
+    let (out, err) = run(vec!["--refresh-rate=2"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!dir.path().exists());
+    assert_eq!(out.as_str(), "");
     assert_eq!(err.as_str(), "");
-    assert_ne!(out.as_str(), ""); // should ask about / and get "q"
 }
 
+#[test]
+fn test_tiny_queue() {
+    let dir = make_test_tree(26).expect("make_test_tree");
+    let (out, err) = run(vec!["--queue-size=1"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    assert_eq!(after, Vec::<PathBuf>::new());
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_files_only() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (out, err) = run(vec!["--files-only"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    assert_eq!(after, paths(vec!["a/b/c"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_trim_to_depth() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (out, err) = run(vec!["--trim-to-depth=2"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b", "a/bb", "a/cc"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+
+    // 0 empties the <path> but keeps it, and a relative <path> counts the same way.
+    let (_out, err) = run(vec!["--trim-to-depth=0", "-C"], vec![dir.path(), Path::new("a")], "", None).expect("rmp failed");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a"]));
+}
+
+#[test]
+fn test_empty_dirs_only() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    create_dir_all(dir.path().join("x/y/z")).expect("create_dir_all");
+    create_dir_all(dir.path().join("a/b/c")).expect("create_dir_all");
+    let (out, err) = run(vec!["--empty-dirs-only"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    assert!(dir.path().exists());
+    drop(dir);
+    assert_eq!(after, paths(vec!["a/aa", "a/b/aa", "a/b/bb", "a/bb"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_syslog_bad_facility() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let orig = find(dir.path());
+    let (status, out, err) = run_status(vec!["--syslog", "--syslog-facility=nope"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(!status.success());
+    assert!(err.contains("facility"));
+    assert_eq!(after, orig);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_respect_xattr() {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = make_test_tree(3).expect("make_test_tree");
+    for p in ["a/bb", "a/b/c"] {
+        let path = std::ffi::CString::new(dir.path().join(p).as_os_str().as_bytes()).unwrap();
+        if unsafe { libc::setxattr(path.as_ptr(), c"user.norm".as_ptr(), b"1".as_ptr() as *const _, 1, 0) } != 0 {
+            println!("Couldn't set xattr ({}), skipping", std::io::Error::last_os_error());
+            return;
+        }
+    }
+    let (out, err) = run(vec!["--respect-xattr=user.norm"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    assert_eq!(after, paths(vec!["a/b/c/aa", "a/b/c/bb", "a/b/c/cc", "a/bb"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+fn test_skip_hidden() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    create_dir_all(dir.path().join(".git/objects")).expect("create_dir_all");
+    std::fs::write(dir.path().join("a/.env"), "x").expect("write");
+    std::fs::write(dir.path().join(".git/objects/aa"), "x").expect("write");
+    let (out, err) = run(vec!["--skip-hidden"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    assert_eq!(after, paths(vec![".git/objects/aa", "a/.env"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+
+    // A hidden <path> was asked for, so it goes.
+    let (out, err) = run(vec!["--skip-hidden"], vec![&dir.path().join(".git")], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    assert_eq!(after, paths(vec!["a/.env"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_owned_by() {
+    if unsafe { libc::geteuid() } != 0 {
+        println!("Can't give files away without being root, skipping");
+        return;
+    }
+    let dir = make_test_tree(2).expect("make_test_tree");
+    for p in ["a/bb", "a/b"] {
+        let path = std::ffi::CString::new(dir.path().join(p).to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::chown(path.as_ptr(), 65534, 65534) }, 0);
+    }
+    let (out, err) = run(vec!["--owned-by=root"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    assert_eq!(after, paths(vec!["a/b", "a/bb"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+
+    let (out, err) = run(vec!["--owned-by=65534"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    assert_eq!(after, paths(vec!["a"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+
+    let (status, _out, err) = run_status(vec!["--owned-by=no-such-user"], vec![Path::new("nope")], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("Unknown user"), "{err}");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_cross_mounts() {
+    if unsafe { libc::geteuid() } != 0 {
+        println!("Can't mount things without being root, skipping");
+        return;
+    }
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let mountpoint = dir.path().join("a/m");
+    create_dir_all(&mountpoint).expect("mkdir");
+    let mount = |mountpoint: &Path| Command::new("mount").args(["-t", "tmpfs", "none"]).arg(mountpoint).status();
+    if !mount(&mountpoint).is_ok_and(|s| s.success()) {
+        println!("Couldn't mount a tmpfs, skipping");
+        return;
+    }
+    std::fs::write(mountpoint.join("precious"), "keep me").expect("write");
+
+    let stopped = run_status(vec!["--progress=none"], vec![dir.path()], "", None);
+    let left = find(dir.path());
+    let crossed = run_status(vec!["--progress=none", "--cross-mounts"], vec![dir.path()], "", None);
+    let after = find(dir.path());
+
+    // before any panics, unmount it!
+    assert!(Command::new("umount").arg(&mountpoint).status().expect("umount").success());
+
+    let (status, out, err) = stopped.expect("rmp failed");
+    assert!(!status.success());
+    assert_eq!(out.as_str(), "");
+    assert!(err.contains("--cross-mounts"), "{err}");
+    assert_eq!(left, paths(vec!["a/m/precious"]));
+
+    // The mount point itself is busy (so it and everything above it fail), but everything in it is gone.
+    let (status, out, err) = crossed.expect("rmp failed");
+    assert!(!status.success());
+    assert_eq!(out.as_str(), "");
+    assert!(err.contains("resource busy"), "{err}");
+    assert!(!err.contains("--cross-mounts"), "{err}");
+    assert_eq!(after, paths(vec!["a/m"]));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_ignore_readonly() {
+    if unsafe { libc::geteuid() } != 0 {
+        println!("Can't mount things without being root, skipping");
+        return;
+    }
+    let dir = tempdir().expect("tempdir");
+    let mountpoint = dir.path().join("m");
+    create_dir_all(&mountpoint).expect("mkdir");
+    if !Command::new("mount").args(["-t", "tmpfs", "none"]).arg(&mountpoint).status().is_ok_and(|s| s.success()) {
+        println!("Couldn't mount a tmpfs, skipping");
+        return;
+    }
+    create_dir_all(mountpoint.join("a/b")).expect("mkdir");
+    std::fs::write(mountpoint.join("a/b/c"), "stuck").expect("write");
+    let remounted = Command::new("mount").args(["-o", "remount,ro"]).arg(&mountpoint).status();
+
+    let refused = run_status(vec!["--progress=none"], vec![&mountpoint.join("a")], "", None);
+    let tried = run_status(vec!["--progress=none", "--ignore-readonly"], vec![&mountpoint.join("a/b/c")], "", None);
+    let left = find(&mountpoint);
+
+    // before any panics, unmount it!
+    assert!(Command::new("umount").arg(&mountpoint).status().expect("umount").success());
+    assert!(remounted.expect("remount").success());
+
+    let (status, out, err) = refused.expect("rmp failed");
+    assert!(!status.success());
+    assert_eq!(out.as_str(), "");
+    assert!(err.contains("read-only filesystem") && err.contains("--ignore-readonly"), "{err}");
+
+    let (status, _out, err) = tried.expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("Read-only file system"), "{err}");
+    assert!(!err.contains("--ignore-readonly"), "{err}");
+    assert_eq!(left, paths(vec!["a/b/c"]));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_preserve_parent_mtime() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    let mtime = |p: &str| dir.path().join(p).metadata().unwrap().modified().unwrap();
+    for p in ["", "a", "a/b", "a/b/c"] {
+        std::fs::File::open(dir.path().join(p)).unwrap().set_modified(old).unwrap();
+    }
+    let (out, err) = run(vec!["--preserve-parent-mtime", "--files-only"], vec![&dir.path().join("a/b")], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/c", "a/bb", "a/cc"]));
+    assert_eq!([mtime("a/b"), mtime("a/b/c")], [old, old]);
+
+    let (out, err) = run(vec!["--preserve-parent-mtime"], vec![&dir.path().join("a/b")], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/bb", "a/cc"]));
+    assert_eq!([mtime(""), mtime("a")], [old, old]);
+
+    // And without it, for comparison.
+    let (_out, _err) = run(vec![], vec![&dir.path().join("a/aa")], "", None).expect("rmp failed");
+    assert_ne!(mtime("a"), old);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_sync() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (out, err) = run(vec!["--sync", "--files-only"], vec![&dir.path().join("a/b")], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/c", "a/bb", "a/cc"]));
+
+    let (out, err) = run(vec!["--sync"], vec![Path::new("a")], "", Some(dir.path())).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec![""]));
+}
+
+#[test]
+fn test_manifest() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let manifest = dir.path().join("manifest");
+    std::fs::write(&manifest, "a/b/c/aa\t8\na/b/c/bb\t99\na/nope\n\na/b/aa\n").expect("write manifest");
+    let (status, out, err) = run_status(vec!["--manifest=manifest"], vec![], "", Some(dir.path())).expect("rmp failed");
+    assert!(!status.success());
+    assert_eq!(out.as_str(), "");
+    assert!(err.contains("\"a/b/c/bb\": Is 8 bytes but the manifest says 99"), "{err}");
+    assert!(err.contains("\"a/nope\": Listed in the manifest"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/bb", "a/b/c/bb", "a/b/c/cc", "a/b/cc", "a/bb", "a/cc", "manifest"]));
+
+    // a/b/c is emptied, so it's pruned. a/b isn't, so it (and a) stay.
+    std::fs::write(&manifest, "a/b/c/bb\na/b/c/cc\na/b/bb\n").expect("write manifest");
+    let (out, err) = run(vec!["--manifest=manifest", "--prune"], vec![], "", Some(dir.path())).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/cc", "a/bb", "a/cc", "manifest"]));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_by_inode() {
+    use std::os::unix::fs::MetadataExt;
+    let dir = make_test_tree(2).expect("make_test_tree");
+    std::fs::write(dir.path().join("a/\x1b[2J\x07"), "junk").expect("write");
+    let ino = std::fs::symlink_metadata(dir.path().join("a/\x1b[2J\x07")).expect("stat").ino();
+    let (status, _out, err) = run_status(vec![&format!("--by-inode=a:{}", ino + 1_000_000)], vec![], "", Some(dir.path())).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains(&format!("--by-inode: Nothing in \"a\" has inode {}", ino + 1_000_000)), "{err}");
+
+    let (status, out, err) = run_status(vec![&format!("--by-inode=a:{ino}")], vec![], "", Some(dir.path())).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/aa", "a/b/bb", "a/bb"]));
+}
+
+#[test]
+fn test_newer_older_than_file() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let (status, _out, err) = run_status(vec!["--newer-than-file=nope"], vec![dir.path()], "", Some(dir.path())).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("--newer-than-file: \"nope\""), "{err}");
+
+    let now = std::time::SystemTime::now();
+    let set_mtime = |path: &str, ago: u64| std::fs::File::options().write(true).open(dir.path().join(path)).expect("open")
+                                                 .set_modified(now - std::time::Duration::from_secs(ago)).expect("set_modified");
+    set_mtime("a/aa", 300);
+    set_mtime("a/bb", 200); // The reference.
+    set_mtime("a/b/aa", 100);
+    set_mtime("a/b/bb", 200);
+    let (out, err) = run(vec!["--older-than-file=a/bb"], vec![Path::new("a")], "", Some(dir.path())).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/b/aa", "a/b/bb", "a/bb"]));
+
+    // Just as old isn't newer, so the reference and a/b/bb stay.
+    let (out, err) = run(vec!["--newer-than-file=a/bb"], vec![Path::new("a")], "", Some(dir.path())).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/b/bb", "a/bb"]));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_skip_open() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    // This process counts as another one, as far as rm-rfp is concerned.
+    let open = std::fs::File::open(dir.path().join("a/b/bb")).expect("open");
+    let (out, err) = run(vec!["--skip-open"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/b/bb"]));
+
+    drop(open);
+    let (_out, err) = run(vec!["--skip-open"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(err.as_str(), "");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_regex() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let (status, _out, err) = run_status(vec!["--regex=("], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("--regex"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/aa", "a/b/bb", "a/bb"]));
+
+    // a/b doesn't match (no trailing /), so it stays.
+    let (out, err) = run(vec!["--regex=^a/b/"], vec![&dir.path().join("a")], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b", "a/bb"]));
+
+    let (_out, err) = run(vec!["--regex-scope=name", "--regex=^aa$"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/b", "a/bb"]));
+
+    let (_out, err) = run(vec!["--regex-scope=absolute", "--regex=^/.*/a/bb$"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/b"]));
+}
+
+#[test]
+fn test_include_exclude() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (status, _out, err) = run_status(vec!["--include=[a"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("--include: Bad pattern"), "{err}");
+
+    // Exclude wins, so a/b/c stays for the sake of a/b/c/aa.
+    let (out, err) = run(vec!["--include=aa:c", "--exclude=a/b/c/aa"], vec![&dir.path().join("a")], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/b/bb", "a/b/c/aa", "a/b/cc", "a/bb", "a/cc"]));
+
+    let (_out, err) = run(vec!["--exclude=b"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/b/bb", "a/b/c/aa", "a/b/cc"]));
+}
+
+#[test]
+fn test_dir() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    create_dir_all(dir.path().join("empty")).expect("create_dir_all");
+    let (status, out, err) = run_status(vec!["--dir"], vec![&dir.path().join("empty"), &dir.path().join("a"), &dir.path().join("a/bb")],
+                                        "", None).expect("rmp failed");
+    let after = find(dir.path());
+    drop(dir);
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(!status.success());
+    assert!(err.contains("not empty"));
+    assert_eq!(after, paths(vec!["a/aa", "a/b/aa", "a/b/bb"]));
+    assert_eq!(out.as_str(), "");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_unreadable_dir() {
+    use std::os::unix::fs::PermissionsExt;
+    if unsafe { libc::geteuid() } == 0 { println!("root can read anything, skipping"); return }
+    let dir1 = make_test_tree(3).expect("make_test_tree1");
+    let dir2 = make_test_tree(2).expect("make_test_tree2");
+    let unreadable = [dir1.path().join("a/b/c"), dir2.path().join("a")];
+    for d in unreadable.iter() { std::fs::set_permissions(d, std::fs::Permissions::from_mode(0o300)).expect("chmod") }
+    let (status, out, err) = run_status(vec![], vec![&dir1.path().join("a"), &dir2.path().join("a"), dir2.path()], "", None).expect("rmp failed");
+    for d in unreadable.iter() { std::fs::set_permissions(d, std::fs::Permissions::from_mode(0o755)).expect("chmod") }
+    let after1 = find(dir1.path());
+    let after2 = find(dir2.path());
+    drop(dir1);
+    drop(dir2);
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(!status.success());
+    assert_eq!(after1, paths(vec!["a/b/c/aa", "a/b/c/bb", "a/b/c/cc"]));
+    assert_eq!(after2, paths(vec!["a/aa", "a/b/aa", "a/b/bb", "a/bb"]));
+    assert!(!err.contains("not empty"));
+    assert_eq!(out.as_str(), "");
+}
+
+#[test]
+fn test_time_limit() {
+    let dir = make_test_tree(5).expect("make_test_tree");
+    let (status, out, err) = run_status(vec!["--dry-run", "--dry-run-delay=20000", "--time-limit=100ms"], vec![dir.path()], "", None).expect("rmp failed");
+    println!("out:\n{out}\nerr:\n{err}");
+    assert_eq!(status.code(), Some(3)); // 5*5 files at 20ms each is a lot more than 100ms
+    assert!(err.contains("time limit"));
+    assert_eq!(out.as_str(), "");
+
+    let (status, _out, err) = run_status(vec!["--dry-run", "--dry-run-delay=0", "--time-limit=10s"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_dereference() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let link = dir.path().join("link");
+    std::os::unix::fs::symlink(dir.path().join("a"), &link).expect("symlink");
+
+    let (out, err) = run(vec!["--progress=none"], vec![&link], "", None).expect("rmp failed");
+    assert!(err.contains("only the link is being deleted"), "{err}");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/aa", "a/b/bb", "a/bb"]));
+
+    std::os::unix::fs::symlink(dir.path().join("a"), &link).expect("symlink");
+    let (_out, err) = run(vec!["--progress=none", "--no-dereference"], vec![&link], "", None).expect("rmp failed");
+    assert!(!err.contains("only the link"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/aa", "a/b/bb", "a/bb"]));
+
+    std::os::unix::fs::symlink(dir.path().join("a"), &link).expect("symlink");
+    let (_out, err) = run(vec!["--progress=none", "--dereference"], vec![&link], "", None).expect("rmp failed");
+    assert!(!err.contains("only the link"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["a"])); // The directory itself stays, empty.
+    assert!(link.symlink_metadata().is_err());
+
+    let (status, _out, err) = run_status(vec!["--dereference", "--no-dereference"], vec![&link], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("can't both be given"), "{err}");
+}
+
+#[test]
+fn test_deterministic() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let (a, b) = (dir.path().join("a"), dir.path().join("a/b"));
+    let (status, out, err) = run_status(vec!["--deterministic", "--dry-run", "--tree", "--dir"], vec![&b, &a.join("aa"), &a],
+                                        "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert_eq!(out, format!("{}\n", a.join("aa").display()));
+    // The errors are in path order, not the order the <path>s were given in.
+    assert!(err.starts_with(&format!("{a:?}: Directory not empty (and --dir doesn't recurse)\n\
+                                      {b:?}: Directory not empty (and --dir doesn't recurse)\n\
+                                      Would free 4 B across 1 files and 0 directories\n\
+                                      Error: Finished with 2 errors\n")), "{err}");
+}
+
+#[test]
+fn test_deterministic_delete() {
+    // Really deleting, with errors, and everything that reports on each thing as it goes.
+    let dir = make_test_tree(2).expect("make_test_tree");
+    create_dir_all(dir.path().join("c")).expect("mkdir c");
+    let (status, out, err) = run_status(vec!["--deterministic", "--dir", "--list-errors", "--per-arg-summary", "--by-extension",
+                                             "--progress-to=stdout"],
+                                        vec![Path::new("a/b"), Path::new("c"), Path::new("a/bb"), Path::new("a/aa"), Path::new("a")],
+                                        "", Some(dir.path())).expect("rmp failed");
+    assert!(!status.success());
+    assert_eq!(out, "Total: freed: 8 B/8 B, directories removed: 1/1, files removed: 2/2\n\
+                     \"a/b\": freed: 0 B, directories removed: 0, files removed: 0\n\
+                     \"c\": freed: 0 B, directories removed: 1, files removed: 0\n\
+                     \"a/bb\": freed: 4 B, directories removed: 0, files removed: 1\n\
+                     \"a/aa\": freed: 4 B, directories removed: 0, files removed: 1\n\
+                     \"a\": freed: 0 B, directories removed: 0, files removed: 0\n\
+                     (none): freed: 8 B, files removed: 2\n");
+    // Sorted when they happen, in the order they happened in the list at the end.
+    assert!(err.starts_with("\"a\": Directory not empty (and --dir doesn't recurse)\n\
+                             \"a/b\": Directory not empty (and --dir doesn't recurse)\n\
+                             Errors (2):\n\
+                             \"a/b\": Directory not empty (and --dir doesn't recurse)\n\
+                             \"a\": Directory not empty (and --dir doesn't recurse)\n\
+                             Error: Finished with 2 errors\n"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["a/b/aa", "a/b/bb"]));
+}
+
+#[test]
+fn test_all_or_nothing() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let (a, b) = (dir.path().join("a"), dir.path().join("a/b"));
+    let (status, _out, err) = run_status(vec!["--all-or-nothing", "--dir"], vec![&a.join("aa"), &b], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains(&format!("{b:?}: Directory not empty")), "{err}");
+    assert!(err.contains("nothing was deleted"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/aa", "a/b/bb", "a/bb"]));
+
+    let (out, err) = run(vec!["--all-or-nothing"], vec![&a.join("aa"), &b], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/bb"]));
+}
+
+#[test]
+fn test_overwrite() {
+    use std::io::Read;
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let a = dir.path().join("a");
+    // Still open, so what it ended up as can be read after it's gone.
+    let mut aa = std::fs::File::open(a.join("aa")).expect("open a/aa");
+    #[cfg(unix)] std::os::unix::fs::symlink(a.join("bb"), dir.path().join("link")).expect("symlink");
+    #[cfg(unix)] std::fs::hard_link(a.join("b/bb"), dir.path().join("hard")).expect("hard_link");
+    let (status, _out, err) = run_status(vec!["-P", "--bury"], vec![&a], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("don't go together"), "{err}");
+
+    let (out, err) = run(vec!["-P"], vec![&a.join("aa"), &dir.path().join("link"), &a.join("b")], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "");
+    let mut contents = vec![];
+    aa.read_to_end(&mut contents).expect("read a/aa");
+    if err.contains("copy-on-write") {
+        assert_eq!(contents, b"a/aa");
+    } else {
+        assert_eq!(contents, b"\xff\xff\xff\xff");
+    }
+    // Not what the symlink points to, and not the file that's still there under another name.
+    #[cfg(unix)] assert!(err.contains("Has other hard links"), "{err}");
+    #[cfg(unix)] assert_eq!(std::fs::read(dir.path().join("hard")).expect("read hard"), b"a/b/bb");
+    assert_eq!(std::fs::read(a.join("bb")).expect("read a/bb"), b"a/bb");
+}
+
+#[test]
+fn test_progress_template() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let orig = find(dir.path());
+    let (status, _out, err) = run_status(vec!["--progress-template={msg:<x}"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("Bad --progress-template"), "{err}");
+    let (status, _out, err) = run_status(vec!["--spinner-template={}"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("Bad --spinner-template"), "{err}");
+    assert_eq!(find(dir.path()), orig);
+
+    let (status, _out, err) = run_status(vec!["--progress-template={wide_bar} {pos}/{len} {msg}", "--spinner-template={msg}"],
+                                         vec![dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_limit() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (status, out, err) = run_status(vec!["--limit=6", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    let after = find(dir.path());
+    println!("out:\n{out}\nerr:\n{err}");
+    assert_eq!(status.code(), Some(4));
+    assert!(err.contains("files removed: 6/9"), "{err}");
+    assert!(err.contains("reached the limit of 6 files"), "{err}");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(after, paths(vec!["a/b/cc", "a/bb", "a/cc"])); // a/b/c went with its last file.
+
+    let (status, _out, err) = run_status(vec!["--limit=6", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_free() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    std::fs::create_dir(dir.path().join("sub")).expect("mkdir");
+    let now = std::time::SystemTime::now();
+    for (name, size, ago) in [("a", 100, 10), ("b", 300, 20), ("sub/c", 200, 30), ("sub/d", 50, 40)] {
+        let file = std::fs::File::create(dir.path().join(name)).expect("create");
+        file.set_len(size).expect("set_len");
+        file.set_modified(now - std::time::Duration::from_secs(ago)).expect("set_modified");
+    }
+    let (status, out, err) = run_status(vec!["--free=350", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(status.success(), "{err}");
+    assert!(err.contains("files removed: 2/2"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["a", "sub/d"]));
+    assert!(dir.path().join("sub").is_dir());
+
+    let (status, _out, err) = run_status(vec!["--free=120", "--free-order=oldest", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["sub"])); // d (50) wasn't enough on its own.
+
+    std::fs::write(dir.path().join("sub/e"), "12345").expect("write");
+    let (status, _out, err) = run_status(vec!["--free=1k", "--progress=none"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(status.code(), Some(6), "{err}");
+    assert!(err.contains("Could only free 5 B of the 1000 B asked for with --free"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["sub"]));
+
+    std::fs::write(dir.path().join("sub/f"), "12345").expect("write");
+    let (status, _out, err) = run_status(vec!["--free=1", "--verify"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("--verify would call every one of them a failure"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["sub/f"]));
+}
+
+#[test]
+fn test_backend_iouring() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (status, _out, err) = run_status(vec!["--backend=iouring", "--bury"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("doesn't work with --bury"), "{err}");
+
+    let (status, _out, err) = run_status(vec!["--backend=iouring", "--progress=plain"], vec![dir.path()], "", None)
+        .expect("rmp failed");
+    if cfg!(all(target_os = "linux", feature = "iouring")) {
+        assert!(status.success(), "{err}");
+        assert!(err.contains("directories removed: 4/4, files removed: 9/9"), "{err}");
+        assert!(!dir.path().exists());
+    } else {
+        assert!(!status.success());
+        assert!(err.contains("`iouring` feature"), "{err}");
+    }
+}
+
+#[test]
+fn test_nothing_status() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let (status, _out, err) = run_status(vec!["--nothing-status", "--force", "--progress=none"], vec![&dir.path().join("nope")],
+                                         "", None).expect("rmp failed");
+    assert_eq!(status.code(), Some(5), "{err}");
+    assert!(err.contains("Nothing to delete"), "{err}");
+    let (status, _out, err) = run_status(vec!["--nothing-status", "--include=*.o", "--progress=none"], vec![dir.path()],
+                                         "", None).expect("rmp failed");
+    assert_eq!(status.code(), Some(5), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/b/aa", "a/b/bb", "a/bb"]));
+
+    let (status, _out, err) = run_status(vec!["--nothing-status", "--progress=none"], vec![&dir.path().join("a/b")], "", None)
+        .expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert!(!err.contains("Nothing to delete"), "{err}");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_non_utf8_names() {
+    use std::os::unix::ffi::OsStringExt;
+    let dir = tempfile::tempdir().expect("tempdir");
+    let bad = dir.path().join(std::ffi::OsString::from_vec(b"caf\xe9".to_vec()));
+    std::fs::write(&bad, "x").expect("write");
+    std::fs::write(bad.with_extension("txt"), "x").expect("write");
+
+    // Reported clearly when it can't be deleted...
+    let stuck = Undeletable::new(&bad.with_extension("txt"));
+    let (status, _out, err) = run_status(vec![], vec![dir.path()], "", None).expect("rmp failed");
+    println!("err:\n{err}");
+    assert!(!status.success());
+    assert!(err.contains(r#"caf\xE9.txt": rm: "#));
+
+    // ...and round-trippable with --raw-paths.
+    let output = Command::new(env!("CARGO_BIN_EXE_rm-rfp")).arg("--raw-paths").arg(dir.path()).output().expect("run");
+    drop(stuck);
+    assert!(!output.status.success());
+    let mut expected = bad.with_extension("txt").into_os_string().into_vec();
+    expected.extend_from_slice(b": rm: ");
+    assert!(output.stderr.windows(expected.len()).any(|w| w == expected), "{:?}", String::from_utf8_lossy(&output.stderr));
+
+    // ...and deleted just like everything else when it can be.
+    let (_out, _err) = run(vec![], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_summary_on_signal() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let child = Command::new(env!("CARGO_BIN_EXE_rm-rfp"))
+        .args(["--summary-on-signal", "--progress=none", "--dry-run", "--dry-run-delay=300000"]).arg(dir.path())
+        .stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().expect("spawn");
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let killed = unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGUSR1) };
+    let out = child.wait_with_output().expect("wait");
+    assert_eq!(killed, 0);
+    let err = String::from_utf8_lossy(&out.stderr);
+    assert!(out.status.success(), "{err}"); // It kept going instead of dying of the signal.
+    assert_eq!(err.lines().filter(|l| l.contains("items/s")).count(), 1, "{err}");
+    assert!(err.contains("Total: "), "{err}");
+    assert_eq!(find(dir.path()).len(), 4);
+
+    // Still counting, and stuck there waiting on an answer, with nothing coming through to be deleted.
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rm-rfp"))
+        .args(["--summary-on-signal", "--progress=none", "--deterministic", "-i"]).arg(dir.path())
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().expect("spawn");
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let killed = unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGUSR1) };
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    drop(child.stdin.take()); // EOF, so it quits.
+    let out = child.wait_with_output().expect("wait");
+    assert_eq!(killed, 0);
+    let err = String::from_utf8_lossy(&out.stderr);
+    assert!(out.status.code().is_some(), "{err}");
+    assert_eq!(err.lines().filter(|l| l.contains("items/s, still counting")).count(), 1, "{err}");
+    assert_eq!(find(dir.path()).len(), 4);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_status_socket() {
+    use std::io::BufRead;
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let socket = dir.path().with_extension("sock");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rm-rfp"))
+        .args(["--progress=none", "--dry-run", "--dry-run-delay=300000"]).arg(format!("--status-socket={}", socket.display()))
+        .arg(dir.path()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().expect("spawn");
+    let stream = (0..100).find_map(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::os::unix::net::UnixStream::connect(&socket).ok()
+    });
+    let Some(stream) = stream else {
+        _ = child.kill();
+        _ = child.wait();
+        panic!("Couldn't connect to {socket:?}");
+    };
+    let lines: Vec<String> = std::io::BufReader::new(stream).lines().map(|line| line.expect("read")).collect();
+    let out = child.wait_with_output().expect("wait");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(lines.len() >= 2, "{lines:?}"); // At least one while it's going, and the one at the end.
+    assert!(lines[..lines.len() - 1].iter().all(|line| line.ends_with(r#""finished":false}"#)), "{lines:?}");
+    assert!(lines.last().unwrap().contains(r#""found_all":true,"done":{"bytes":20,"files":4,"dirs":3,"symlinks":0},"errors":0,"finished":true}"#),
+            "{lines:?}");
+    assert!(!socket.exists());
+
+    // Someone's listening, so it's not ours to take over. Nobody is, so it's left over and fair game.
+    let listener = std::os::unix::net::UnixListener::bind(&socket).expect("bind");
+    let (status, _out, err) = run_status(vec![&format!("--status-socket={}", socket.display()), "-n"], vec![dir.path()], "", None)
+        .expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("Already in use"), "{err}");
+    drop(listener);
+    let (status, _out, err) = run_status(vec![&format!("--status-socket={}", socket.display()), "-n"], vec![dir.path()], "", None)
+        .expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert!(!socket.exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_lock() {
+    use std::os::{fd::AsRawFd, unix::fs::{DirBuilderExt, MetadataExt}};
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let m = dir.path().symlink_metadata().expect("stat");
+    let lock_dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) if !runtime.is_empty() => PathBuf::from(runtime).join("rm-rfp"),
+        _ => std::env::temp_dir().join(format!("rm-rfp-{}", unsafe { libc::geteuid() })),
+    };
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&lock_dir).expect("lock dir");
+    let lock_path = lock_dir.join(format!("rm-rfp-{:x}-{:x}.lock", m.dev(), m.ino()));
+    let lock = std::fs::File::create(&lock_path).expect("create lock");
+    assert_eq!(unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }, 0);
+
+    let (status, out, err) = run_status(vec!["--lock"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("Another rm-rfp is already deleting this"), "{err}");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(find(dir.path()).len(), 4);
+
+    drop(lock);
+    let (_out, _err) = run(vec!["--lock"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!dir.path().exists());
+    _ = std::fs::remove_file(lock_path);
+
+    // Lock files aren't followed anywhere.
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let m = dir.path().symlink_metadata().expect("stat");
+    let lock_path = lock_dir.join(format!("rm-rfp-{:x}-{:x}.lock", m.dev(), m.ino()));
+    std::os::unix::fs::symlink(dir.path().join("a/aa"), &lock_path).expect("symlink");
+    let (status, _out, err) = run_status(vec!["--lock"], vec![dir.path()], "", None).expect("rmp failed");
+    _ = std::fs::remove_file(lock_path);
+    assert!(!status.success());
+    assert!(err.contains("Couldn't open lock file"), "{err}");
+    assert_eq!(find(dir.path()).len(), 4);
+
+    // One inside the other collides too, whichever way around. Another run deleting the whole tree has it locked...
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let m = dir.path().symlink_metadata().expect("stat");
+    let lock_path = lock_dir.join(format!("rm-rfp-{:x}-{:x}.lock", m.dev(), m.ino()));
+    let lock = std::fs::File::create(&lock_path).expect("create lock");
+    assert_eq!(unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }, 0);
+    let (status, _out, err) = run_status(vec!["--lock"], vec![&dir.path().join("a/b")], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains(&format!("Another rm-rfp is already deleting {:?}, which this is in", dir.path())), "{err}");
+    assert_eq!(find(dir.path()).len(), 4);
+
+    // ...and another run deleting something inside it has it share locked.
+    assert_eq!(unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) }, 0);
+    let (status, _out, err) = run_status(vec!["--lock"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("Another rm-rfp is already deleting this, or something in it"), "{err}");
+    assert_eq!(find(dir.path()).len(), 4);
+
+    // But a run doesn't collide with itself. (Really deleting both would trip over each other, lock or no lock.)
+    drop(lock);
+    let (status, _out, err) = run_status(vec!["--lock", "--dry-run"], vec![&dir.path().join("a/b"), dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    _ = std::fs::remove_file(lock_path);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_protect_from() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let list = tempfile::NamedTempFile::new().expect("tempfile");
+    std::os::unix::fs::symlink(dir.path().join("a/b/c"), dir.path().join("a/link")).expect("symlink");
+    // Through the symlink, so it has to go by identity rather than name.
+    std::fs::write(list.path(), format!("# Keep these\n{}\n\n{}\n/nonexistent\n", dir.path().join("a/bb").display(),
+                                        dir.path().join("a/link/aa").display())).expect("write list");
+    let protect = format!("--protect-from={}", list.path().display());
+    let (status, out, err) = run_status(vec![&protect], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+    assert_eq!(find(dir.path()), paths(vec!["a/b/c/aa", "a/bb"]));
+
+    let (_, _out, err) = run_status(vec![&protect], vec![&dir.path().join("a/bb")], "", None).expect("rmp failed");
+    assert!(err.contains("Is in the --protect-from list"), "{err}");
+    assert_eq!(find(dir.path()), paths(vec!["a/b/c/aa", "a/bb"]));
+
+    std::fs::write(list.path(), "relative/path\n").expect("write list");
+    let (status, _out, err) = run_status(vec![&protect], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("isn't an absolute path"), "{err}");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_precious() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let precious = format!("--precious=/usr:{}", dir.path().display());
+    let (status, out, err) = run_status(vec!["--dry-run", &precious], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("--yes-really"), "{err}");
+    assert_eq!(out.as_str(), "");
+
+    let (status, out, err) = run_status(vec!["-i", "--progress-to=stdout", &precious], vec![dir.path()], "n\n", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(out.contains("Really delete it?"), "{out}");
+    assert!(err.contains("Not deleting"), "{err}");
+
+    let (status, _out, err) = run_status(vec!["--yes-really", &precious], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_progress_plain_and_none() {
+    for progress in ["--progress=plain", "--progress=none"] {
+        let dir = make_test_tree(3).expect("make_test_tree");
+        let (out, err) = run(vec![progress], vec![dir.path()], "", None).expect("rmp failed");
+        assert!(!dir.path().exists());
+        assert_eq!(out.as_str(), "");
+        assert!(err.starts_with("Total: freed: "), "{progress}: {err}");
+        assert!(!err.contains('\x1b'), "{progress}: {err}");
+        assert_eq!(err.lines().count(), 1, "{progress}: {err}");
+    }
+}
+
+#[test]
+fn test_glob() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    for name in ["a.log", "b.log", "c.tmp", "d.txt", ".e.log"] {
+        std::fs::write(dir.path().join(name), "x").expect("write");
+    }
+    let pattern = dir.path().join("*.{log,tmp}");
+    let (out, err) = run(vec!["--glob"], vec![&pattern], "", None).expect("rmp failed");
+    assert_eq!(find(dir.path()), paths(vec![".e.log", "d.txt"]));
+    assert_eq!(out.as_str(), "");
+    assert_eq!(err.as_str(), "");
+
+    // Now it doesn't match anything.
+    let (status, _out, err) = run_status(vec!["--glob"], vec![&pattern], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(err.contains("No matches"), "{err}");
+    let (_out, _err) = run(vec!["--glob", "-f"], vec![&pattern], "", None).expect("rmp failed");
+
+    // Without --glob it's literal.
+    let (status, _out, _err) = run_status(vec![], vec![&dir.path().join("*.txt")], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert_eq!(find(dir.path()), paths(vec![".e.log", "d.txt"]));
+}
+
+#[test]
+fn test_force() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let (status, _out, _err) = run_status(vec![], vec![&dir.path().join("nope"), dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert!(dir.path().exists());
+    let (_out, _err) = run(vec!["-f"], vec![&dir.path().join("nope"), dir.path()], "", None).expect("rmp failed");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_dedupe_args() {
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let (status, out, err) = run_status(vec!["--dedupe-args", "--per-arg-summary"],
+                                        vec![&dir.path().join("a/b"), dir.path(), &dir.path().join("a/b/aa")], "", None)
+        .expect("rmp failed");
+    assert!(status.success(), "{err}");
+    assert!(!dir.path().exists());
+    assert_eq!(err.matches("(which was also given), so it's left to that").count(), 2, "{err}");
+    assert!(!err.contains("No such file"), "{err}");
+    // Only the one that's left gets a summary, and it has everything.
+    assert_eq!(out.lines().count(), 1, "{out}");
+    assert!(out.starts_with(&format!("{:?}: ", dir.path())), "{out}");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_as_rm() {
+    use std::io::Write;
+    let bin = tempdir().expect("tempdir");
+    std::os::unix::fs::symlink(env!("CARGO_BIN_EXE_rm-rfp"), bin.path().join("rm")).expect("symlink");
+    let dir = make_test_tree(2).expect("make_test_tree");
+    let rm = |args: &[&str], stdin: &str| {
+        let mut child = Command::new(bin.path().join("rm")).args(args).arg(dir.path()).env_remove("CI")
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().expect("spawn");
+        child.stdin.take().unwrap().write_all(stdin.as_bytes()).expect("stdin");
+        let out = child.wait_with_output().expect("wait");
+        (out.status, String::from_utf8_lossy(&out.stderr).into_owned())
+    };
+    let (status, err) = rm(&["-rIv", "--progress-to=stderr"], "n\n");
+    assert!(status.success(), "{err}");
+    assert!(err.contains("rm: remove 1 argument recursively? [y/N]"), "{err}");
+    assert_eq!(find(dir.path()).len(), 4);
+    let (status, err) = rm(&["-rfn", "--progress=none"], ""); // -n is ours.
+    assert!(status.success(), "{err}");
+    assert_eq!(find(dir.path()).len(), 4);
+    let (status, err) = rm(&["-I", "-R"], "y\n");
+    assert!(status.success(), "{err}");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_progress_by() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (_out, err) = run(vec!["--progress-by=bytes"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(err.as_str(), "");
+    assert!(!dir.path().exists());
+    let (status, _out, _err) = run_status(vec!["--progress-by=inodes"], vec![Path::new("nope")], "", None).expect("rmp failed");
+    assert!(!status.success());
+}
+
+#[test]
+fn test_throttle() {
+    let dir = make_test_tree(2).expect("make_test_tree"); // 7 things to delete
+    let start = std::time::Instant::now();
+    let (_out, err) = run(vec!["--progress=none", "--throttle=20/s"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(start.elapsed() >= std::time::Duration::from_millis(300), "{:?}", start.elapsed());
+    assert!(err.contains(" files/s"), "{err}");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_summary_format() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (status, out, err) = run_status(vec!["--summary-format={files},{dir}"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(!status.success());
+    assert_eq!(out.as_str(), "");
+    assert!(err.contains("Unknown placeholder {dir}"), "{err}");
+    assert!(dir.path().exists());
+
+    let (out, _err) = run(vec!["--summary-format={files},{dirs},{bytes},{errors}"], vec![dir.path()], "", None).expect("rmp failed");
+    assert_eq!(out.as_str(), "9,4,54,0\n");
+    assert!(!dir.path().exists());
+}
+
+#[test]
+fn test_show_inodes() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let (_out, err) = run(vec!["--progress=none", "--show-inodes"], vec![dir.path()], "", None).expect("rmp failed");
+    assert!(err.contains(", inodes removed: 13 ("), "{err}");
+    assert!(err.trim_end().ends_with("/s)"), "{err}");
+}
+
+#[test]
+fn test_dot() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let before = find(dir.path());
+    let (out, err) = run(vec!["--dry-run"], vec![Path::new(".")], "", Some(dir.path())).expect("rm-rfp exec failed");
+    let after = find(dir.path());
+    assert_eq!(before, after);
+    println!("err={err}\nout={out}");
+    assert_ne!(err.as_str(), "");
+    assert_eq!(out.as_str(), "");
+
+}
+
+#[test]
+fn test_dot_dot() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let before = find(dir.path());
+    let (out, err) = run(vec!["--dry-run"], vec![Path::new("..")], "", Some(&dir.path().join("a"))).expect("rm-rfp exec failed");
+    let after = find(dir.path());
+    assert_eq!(before, after);
+    println!("err={err}\nout={out}");
+    assert_ne!(err.as_str(), "");
+    assert_eq!(out.as_str(), "");
+}
+
+#[test]
+fn test_directory() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let before = find(dir.path());
+    for dot in [".", "..", "b/.."] {
+        let (status, out, err) = run_status(vec!["--dry-run", "-C"], vec![&dir.path().join("a"), Path::new(dot)], "", None).expect("rm-rfp exec failed");
+        println!("err={err}\nout={out}");
+        assert!(!status.success());
+        assert!(err.contains("Refusing"), "{dot}: {err}");
+    }
+    assert_eq!(find(dir.path()), before);
+
+    let (_out, _err) = run(vec!["-C"], vec![dir.path(), Path::new("a/b")], "", None).expect("rm-rfp exec failed");
+    assert_eq!(find(dir.path()), paths(vec!["a/aa", "a/bb", "a/cc"]));
+}
+
+#[test]
+fn test_cwd() {
+    let dir = make_test_tree(3).expect("make_test_tree");
+    let before = find(dir.path());
+    let cwd = dir.path().join("a/b");
+    for arg in [dir.path().join("a/b"), dir.path().join("a"), PathBuf::from("../../a")] {
+        let (status, _out, err) = run_status(vec![], vec![&arg], "", Some(&cwd)).expect("rm-rfp exec failed");
+        assert!(!status.success());
+        assert!(err.contains("--allow-delete-cwd"), "{arg:?}: {err}");
+    }
+    assert_eq!(find(dir.path()), before);
+
+    // a is also "..", which --precious protects by default.
+    let (_out, _err) = run(vec!["--allow-delete-cwd", "--yes-really"], vec![&dir.path().join("a")], "", Some(&cwd)).expect("rm-rfp exec failed");
+    assert!(!dir.path().join("a").exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_delete_self() {
+    let dir = tempdir().expect("tempdir");
+    let exe = dir.path().join("bin/rm-rfp");
+    create_dir_all(exe.parent().unwrap()).expect("create_dir_all");
+    std::fs::copy(env!("CARGO_BIN_EXE_rm-rfp"), &exe).expect("copy");
+    std::os::unix::fs::symlink(&exe, dir.path().join("link")).expect("symlink");
+    let rm_rfp = |run_as: &Path, flags: &[&str], path: &Path| {
+        Command::new(run_as).args(flags).arg("--progress=none").arg(path).output().expect("run")
+    };
+    // However it was run, and whichever way the argument leads there.
+    for (run_as, path) in [(&exe, dir.path().join("bin")), (&exe, dir.path().to_owned()), (&dir.path().join("link"), exe.clone())] {
+        let output = rm_rfp(run_as, &[], &path);
+        let err = String::from_utf8(output.stderr).unwrap();
+        assert!(!output.status.success());
+        assert!(err.contains("Refusing to delete rm-rfp itself"), "{run_as:?} {path:?}: {err}");
+    }
+    assert!(exe.exists());
+
+    let output = rm_rfp(&exe, &["--allow-delete-self"], &dir.path().join("bin"));
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!exe.exists());
+}
+
+#[test]
+fn test_root_preserve() {
+    test_dry_run(); // This test is quite dangerous if --dry-run doesn't work. So it's got to be a prerequisite!
+    test_interactive_ynq(); // As a backup we also set --interactive and send a "q".
+    // FIXME: is there some way to chroot or something so this isn't such a dire test to fail?
+    let (out, err) = run(vec!["--dry-run", "-i", "--progress-to=stdout"], vec![Path::new("/")], "q", None).expect("rm-rfp exec failed");
+    println!("out:\n{out}\nerr:\n{err}");
+    assert_ne!(err.as_str(), "");
+    assert_eq!(out.as_str(), "");
+    assert!(err.contains("--no-preserve-root"));
+    assert!(err.to_lowercase().contains("error"));
+    assert!(err.to_lowercase().contains("refusing"));
+}
+
+#[test]
+fn test_no_root_preserve() {
+    test_dry_run(); // This test is quite dangerous if --dry-run doesn't work. So it's got to be a prerequisite!
+    test_interactive_ynq(); // As a backup we also set --interactive and send a "q".
+    // FIXME: is there some way to chroot or something so this isn't such a dire test to fail?
+    let (out, err) = run(vec!["--dry-run", "-i", "--progress-to=stdout", "--no-preserve-root"], vec![Path::new("/")], "q", None).expect("rm-rfp exec failed");
+    println!("out:\n{out}\nerr:\n{err}");
+    assert_eq!(err.as_str(), "");
+    assert_ne!(out.as_str(), ""); // should ask about / and get "q"
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_root_preserve_all_file_in_mount_root() {
+    // /dev/shm is usually its own tmpfs. A file sitting in the root of a mount is fine to delete, the mount itself isn't.
+    use std::os::unix::fs::MetadataExt;
+    let shm = Path::new("/dev/shm");
+    let (Ok(shm_meta), Ok(dev_meta)) = (shm.metadata(), Path::new("/dev").metadata()) else { return };
+    if shm_meta.dev() == dev_meta.dev() { println!("/dev/shm isn't a mount point, skipping"); return }
+
+    let file = tempfile::NamedTempFile::new_in(shm).expect("tempfile");
+    let (status, out, err) = run_status(vec!["--dry-run"], vec![file.path()], "", None).expect("rm-rfp exec failed");
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(status.success());
+    assert_eq!(err.as_str(), "");
+
+    let (status, out, err) = run_status(vec!["--dry-run", "-i", "--progress-to=stdout"], vec![shm], "q", None).expect("rm-rfp exec failed");
+    println!("out:\n{out}\nerr:\n{err}");
+    assert!(!status.success());
+    assert_eq!(out.as_str(), "");
+    assert!(err.contains("root of a mounted filesystem"));
+}
+
+#[cfg(target_os = "macos")]
 fn print_utf8(what: &str, bytes: &[u8]) {
     match std::str::from_utf8(bytes) {
         Ok(s) => println!("{what}:\n{s}"),
@@ -0,0 +1,41 @@
+// Copyright © 2024 David Caldwell <david@porkrind.org>
+
+// Shared by the integration tests and the benchmarks, which each only use some of it.
+#![allow(dead_code)]
+
+use std::{fs::create_dir_all,
+          path::PathBuf};
+
+use anyhow::{Context, Result};
+use tempfile::{tempdir, TempDir};
+
+/// `count` directories nested inside each other (`a/b/c/…`), each with `count` files (`aa`, `bb`, …) whose contents
+/// are their own paths.
+pub fn make_test_tree(count: usize) -> Result<TempDir> {
+    let dir = tempdir().context("tempdir()")?;
+    let mut path = dir.path().to_owned();
+    for c in "abcdefghijklmnopqrstuvwxyz".chars().take(count).map(|c| PathBuf::from(c.to_string())) {
+        path.push(&c);
+        for f in "abcdefghijklmnopqrstuvwxyz".chars().take(count).map(|c| PathBuf::from(format!("{c}{c}"))) {
+            create_dir_all(&path).context("create_dir_all")?;
+            let file = path.join(&f);
+            std::fs::write(&file, file.strip_prefix(dir.path()).context("strip_prefix")?.as_os_str().as_encoded_bytes()).with_context(|| format!("write {file:?}"))?;
+        }
+    }
+    Ok(dir)
+}
+
+/// Something more like a real build directory, for the benchmarks: `dirs` directories side by side with `files`
+/// (small) files in each.
+pub fn make_wide_tree(dirs: usize, files: usize) -> Result<TempDir> {
+    let dir = tempdir().context("tempdir()")?;
+    for d in 0..dirs {
+        let path = dir.path().join(format!("d{d}"));
+        create_dir_all(&path).context("create_dir_all")?;
+        for f in 0..files {
+            let file = path.join(format!("f{f}"));
+            std::fs::write(&file, format!("{d}/{f}")).with_context(|| format!("write {file:?}"))?;
+        }
+    }
+    Ok(dir)
+}